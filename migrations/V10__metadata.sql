@@ -0,0 +1,4 @@
+CREATE TABLE metadata (
+    version_id INTEGER PRIMARY KEY REFERENCES versions (id) ON DELETE CASCADE,
+    content    TEXT    NOT NULL
+);
\ No newline at end of file
@@ -0,0 +1,6 @@
+CREATE TABLE upstream_crates (
+    name        TEXT    PRIMARY KEY,
+    content     BLOB    NOT NULL,
+    etag        TEXT,
+    modified_at INTEGER NOT NULL
+);
@@ -0,0 +1,7 @@
+CREATE TABLE mirrored_crates (
+    name        TEXT    PRIMARY KEY,
+    mirrored_at INTEGER NOT NULL
+);
+
+INSERT INTO mirrored_crates (name, mirrored_at)
+SELECT name, modified_at FROM upstream_crates;
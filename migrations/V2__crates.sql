@@ -0,0 +1,16 @@
+CREATE TABLE crates (
+    id          INTEGER PRIMARY KEY,
+    name        TEXT    NOT NULL UNIQUE,
+    max_version TEXT    NOT NULL,
+    description TEXT,
+    created_at  INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+    updated_at  INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+);
+
+CREATE TABLE crate_keywords (
+    crate_id INTEGER NOT NULL REFERENCES crates (id) ON DELETE CASCADE,
+    keyword  TEXT    NOT NULL,
+    PRIMARY KEY (crate_id, keyword)
+);
+
+CREATE INDEX idx_crate_keywords_keyword ON crate_keywords (keyword);
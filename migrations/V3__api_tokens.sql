@@ -0,0 +1,10 @@
+CREATE UNIQUE INDEX idx_users_name ON users (name);
+
+CREATE TABLE api_tokens (
+    id           INTEGER PRIMARY KEY,
+    user_id      INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name         TEXT    NOT NULL,
+    token_hash   TEXT    NOT NULL UNIQUE,
+    created_at   INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+    last_used_at INTEGER
+);
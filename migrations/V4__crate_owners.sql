@@ -0,0 +1,6 @@
+CREATE TABLE crate_owners (
+    crate_id   INTEGER NOT NULL REFERENCES crates (id) ON DELETE CASCADE,
+    user_id    INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+    PRIMARY KEY (crate_id, user_id)
+);
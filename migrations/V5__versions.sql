@@ -0,0 +1,39 @@
+ALTER TABLE crates ADD COLUMN homepage TEXT;
+ALTER TABLE crates ADD COLUMN documentation TEXT;
+ALTER TABLE crates ADD COLUMN repository TEXT;
+
+CREATE TABLE crate_categories (
+    crate_id INTEGER NOT NULL REFERENCES crates (id) ON DELETE CASCADE,
+    category TEXT    NOT NULL,
+    PRIMARY KEY (crate_id, category)
+);
+
+CREATE TABLE versions (
+    id           INTEGER PRIMARY KEY,
+    crate_id     INTEGER NOT NULL REFERENCES crates (id) ON DELETE CASCADE,
+    num          TEXT    NOT NULL,
+    checksum     TEXT    NOT NULL,
+    crate_size   INTEGER NOT NULL,
+    features     TEXT    NOT NULL,
+    links        TEXT,
+    license      TEXT,
+    yanked       INTEGER NOT NULL DEFAULT 0,
+    published_by INTEGER REFERENCES users (id) ON DELETE SET NULL,
+    created_at   INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+    UNIQUE (crate_id, num)
+);
+
+CREATE TABLE dependencies (
+    id               INTEGER PRIMARY KEY,
+    version_id       INTEGER NOT NULL REFERENCES versions (id) ON DELETE CASCADE,
+    crate_name       TEXT    NOT NULL,
+    req              TEXT    NOT NULL,
+    features         TEXT    NOT NULL,
+    optional         INTEGER NOT NULL,
+    default_features INTEGER NOT NULL,
+    target           TEXT,
+    kind             TEXT    NOT NULL,
+    registry         TEXT
+);
+
+CREATE INDEX idx_dependencies_crate_name ON dependencies (crate_name);
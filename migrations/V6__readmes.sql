@@ -0,0 +1,4 @@
+CREATE TABLE readmes (
+    version_id INTEGER PRIMARY KEY REFERENCES versions (id) ON DELETE CASCADE,
+    content    TEXT    NOT NULL
+);
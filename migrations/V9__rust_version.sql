@@ -0,0 +1 @@
+ALTER TABLE versions ADD COLUMN rust_version TEXT;
\ No newline at end of file
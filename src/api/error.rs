@@ -1,6 +1,13 @@
-use warp::{http::StatusCode, reject::Reject, Rejection, Reply};
+use warp::{
+    body::BodyDeserializeError,
+    http::{header::WWW_AUTHENTICATE, HeaderValue, StatusCode},
+    reject::{InvalidQuery, LengthRequired, PayloadTooLarge, Reject},
+    reply::Response,
+    Rejection, Reply,
+};
 
 use super::models::{ErrorDetail, ErrorResponse};
+use crate::models::AlreadyExists;
 
 pub type Result<T, E = Rejection> = std::result::Result<T, E>;
 
@@ -15,7 +22,61 @@ impl<T: Into<anyhow::Error>> From<T> for ServerError {
 
 impl Reject for ServerError {}
 
-pub async fn recover(err: Rejection) -> Result<impl Reply, Rejection> {
+/// Errors caused by the client's request, which are reported back with a matching status code.
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    /// The requested crate, version or other resource doesn't exist.
+    #[error("{0}")]
+    NotFound(String),
+    /// The request conflicts with the current state of the registry, like publishing a version
+    /// that already exists.
+    #[error("{0}")]
+    Conflict(String),
+    /// The request lacks valid credentials. Answered with a `Cargo` authentication challenge, so
+    /// cargo asks the user to log in.
+    #[error("{0}")]
+    Unauthorized(String),
+    /// The authenticated user isn't allowed to perform the requested action.
+    #[error("{0}")]
+    Forbidden(String),
+    /// The request is malformed or contains invalid data.
+    #[error("{0}")]
+    BadRequest(String),
+    /// The request body exceeds the allowed size.
+    #[error("{0}")]
+    PayloadTooLarge(String),
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        }
+    }
+}
+
+impl Reject for ApiError {}
+
+/// Turn an error into a rejection. [`ApiError`]s and errors with a known meaning keep their
+/// status code, anything else is reported as [`ServerError`].
+pub fn reject(err: anyhow::Error) -> Rejection {
+    let err = match err.downcast::<ApiError>() {
+        Ok(err) => return err.into(),
+        Err(err) => err,
+    };
+
+    match err.downcast::<AlreadyExists>() {
+        Ok(err) => ApiError::Conflict(err.to_string()).into(),
+        Err(err) => ServerError(err).into(),
+    }
+}
+
+pub async fn recover(err: Rejection) -> Result<Response, Rejection> {
     if let Some(ServerError(err)) = err.find() {
         let mut errors = Vec::new();
         let mut current: Option<&dyn std::error::Error> = Some(err.as_ref());
@@ -27,11 +88,84 @@ pub async fn recover(err: Rejection) -> Result<impl Reply, Rejection> {
             current = err.source();
         }
 
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&ErrorResponse { errors }),
-            StatusCode::INTERNAL_SERVER_ERROR,
-        ));
+        return Ok(reply(errors, StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    if let Some(err) = err.find::<ApiError>() {
+        let mut resp = reply(
+            vec![ErrorDetail {
+                detail: err.to_string(),
+            }],
+            err.status(),
+        );
+        if matches!(err, ApiError::Unauthorized(_)) {
+            resp.headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Cargo"));
+        }
+
+        return Ok(resp);
+    }
+
+    // Translate warp's own rejections for malformed requests, so cargo can show the reason.
+    let err = if let Some(err) = err.find::<PayloadTooLarge>() {
+        ApiError::PayloadTooLarge(err.to_string())
+    } else if let Some(err) = err.find::<LengthRequired>() {
+        ApiError::BadRequest(err.to_string())
+    } else if let Some(err) = err.find::<BodyDeserializeError>() {
+        ApiError::BadRequest(err.to_string())
+    } else if let Some(err) = err.find::<InvalidQuery>() {
+        ApiError::BadRequest(err.to_string())
+    } else {
+        return Err(err);
+    };
+
+    Ok(reply(
+        vec![ErrorDetail {
+            detail: err.to_string(),
+        }],
+        err.status(),
+    ))
+}
+
+/// Create a response in the error format that cargo expects.
+fn reply(errors: Vec<ErrorDetail>, status: StatusCode) -> Response {
+    warp::reply::with_status(warp::reply::json(&ErrorResponse { errors }), status).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_known_errors() {
+        let rejection = reject(ApiError::NotFound("missing".to_owned()).into());
+        assert!(matches!(rejection.find(), Some(ApiError::NotFound(_))));
+
+        let rejection = reject(
+            AlreadyExists {
+                name: "test".parse().unwrap(),
+                version: "1.0.0".parse().unwrap(),
+            }
+            .into(),
+        );
+        assert!(matches!(rejection.find(), Some(ApiError::Conflict(_))));
+
+        let rejection = reject(anyhow::anyhow!("broken"));
+        assert!(rejection.find::<ServerError>().is_some());
     }
 
-    Err(err)
+    #[tokio::test]
+    async fn challenge_unauthorized() {
+        let resp = recover(ApiError::Unauthorized("missing API token".to_owned()).into())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+        assert_eq!("Cargo", resp.headers()[WWW_AUTHENTICATE]);
+
+        let resp = recover(ApiError::Forbidden("not an owner".to_owned()).into())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, resp.status());
+        assert!(!resp.headers().contains_key(WWW_AUTHENTICATE));
+    }
 }
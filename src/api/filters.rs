@@ -1,45 +1,84 @@
 use std::{convert::Infallible, sync::Arc};
 
 use semver::Version;
-use tokio::sync::Mutex;
-use warp::{Filter, Rejection, Reply};
+use warp::{path::FullPath, Filter, Rejection, Reply};
 
 use super::{
     error, handlers,
     models::{AddOwnersRequest, RemoveOwnersRequest, SearchQuery},
 };
 use crate::{
-    index::Service as IndexService, models::CrateName, storage::Service as StorageService,
+    auth::Service as AuthService,
+    db::{users::User, DbConnPool},
+    index::{dl::Template, Service as IndexService},
+    models::CrateName,
+    settings,
+    storage::Service as StorageService,
+    upstream::Service as UpstreamService,
 };
 
-/// All API related routes prefixed with `/api/v1/crates/...`.
+/// All API related routes prefixed with `/api/v1/crates/...`, plus the download route at the
+/// location described by the `dl` template.
 pub fn api(
     index: Arc<impl IndexService>,
-    storage: Arc<Mutex<impl StorageService>>,
+    storage: Arc<impl StorageService>,
+    auth: Arc<impl AuthService>,
+    pool: DbConnPool,
+    dl: Template,
+    limits: settings::Publish,
+    upstream: Option<Arc<dyn UpstreamService>>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path!("api" / "v1" / "crates" / ..).and(
-        crates_new(Arc::clone(&index), Arc::clone(&storage))
-            .or(yank(Arc::clone(&index)))
-            .or(unyank(index))
-            .or(list_owners())
-            .or(add_owners())
-            .or(remove_owners())
-            .or(search())
-            .or(download(storage)),
-    )
+    let dl = Arc::new(dl);
+
+    warp::path!("api" / "v1" / "crates" / ..)
+        .and(
+            crates_new(
+                Arc::clone(&index),
+                Arc::clone(&storage),
+                Arc::clone(&auth),
+                upstream.clone(),
+                pool.clone(),
+                limits,
+            )
+            .or(yank(Arc::clone(&index), Arc::clone(&auth), pool.clone()))
+            .or(unyank(Arc::clone(&index), Arc::clone(&auth), pool.clone()))
+            .or(list_owners(pool.clone()))
+            .or(add_owners(Arc::clone(&auth), pool.clone()))
+            .or(remove_owners(auth, pool.clone()))
+            .or(crate_info(pool.clone(), Arc::clone(&dl)))
+            .or(crate_versions(pool.clone(), Arc::clone(&dl)))
+            .or(version_info(pool.clone(), Arc::clone(&dl)))
+            .or(dependencies(pool.clone()))
+            .or(readme(pool.clone()))
+            .or(metadata(pool.clone()))
+            .or(reverse_dependencies(pool.clone(), Arc::clone(&dl)))
+            .or(search(pool)),
+        )
+        .or(download(index, storage, upstream, dl))
 }
 
 /// `PUT /api/v1/crates/<crate_name>/new`
 fn crates_new(
     index: Arc<impl IndexService>,
-    storage: Arc<Mutex<impl StorageService>>,
+    storage: Arc<impl StorageService>,
+    auth: Arc<impl AuthService>,
+    upstream: Option<Arc<dyn UpstreamService>>,
+    pool: DbConnPool,
+    limits: settings::Publish,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    // Two length fields, the metadata and the crate file.
+    let max_size = 8 + handlers::MAX_METADATA_SIZE + limits.max_crate_size;
+
     warp::path("new")
         .and(warp::put())
-        .and(warp::body::content_length_limit(10_000_000))
-        .and(warp::body::bytes())
+        .and(authenticated(auth))
+        .and(warp::body::content_length_limit(max_size))
+        .and(warp::body::stream())
+        .and(warp::any().map(move || limits))
         .and(with_storage(storage))
         .and(with_index(index))
+        .and(with_upstream(upstream))
+        .and(with_pool(pool))
         .and_then(handlers::crates_new)
         .recover(error::recover)
 }
@@ -47,10 +86,14 @@ fn crates_new(
 /// `DELETE /api/v1/crates/<crate_name>/<version>/yank`
 fn yank(
     index: Arc<impl IndexService>,
+    auth: Arc<impl AuthService>,
+    pool: DbConnPool,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!(CrateName / Version / "yank")
         .and(warp::delete())
+        .and(authenticated(auth))
         .and(with_index(index))
+        .and(with_pool(pool))
         .and_then(handlers::yank)
         .recover(error::recover)
 }
@@ -58,60 +101,176 @@ fn yank(
 /// `PUT /api/v1/crates/<crate_name>/<version>/unyank`
 fn unyank(
     index: Arc<impl IndexService>,
+    auth: Arc<impl AuthService>,
+    pool: DbConnPool,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!(CrateName / Version / "unyank")
         .and(warp::put())
+        .and(authenticated(auth))
         .and(with_index(index))
+        .and(with_pool(pool))
         .and_then(handlers::unyank)
         .recover(error::recover)
 }
 
 /// `GET /api/v1/crates/<crate_name>/owners`
-fn list_owners() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+fn list_owners(pool: DbConnPool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!(CrateName / "owners")
         .and(warp::get())
+        .and(with_pool(pool))
         .and_then(handlers::list_owners)
         .recover(error::recover)
 }
 
 /// `PUT /api/v1/crates/<crate_name>/owners`
-fn add_owners() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+fn add_owners(
+    auth: Arc<impl AuthService>,
+    pool: DbConnPool,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!(CrateName / "owners")
         .and(warp::put())
+        .and(authenticated(auth))
         .and(warp::body::json::<AddOwnersRequest>())
+        .and(with_pool(pool))
         .and_then(handlers::add_owners)
         .recover(error::recover)
 }
 
 /// `DELETE /api/v1/crates/<crate_name>/owners`
-fn remove_owners() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+fn remove_owners(
+    auth: Arc<impl AuthService>,
+    pool: DbConnPool,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!(CrateName / "owners")
         .and(warp::delete())
+        .and(authenticated(auth))
         .and(warp::body::json::<RemoveOwnersRequest>())
+        .and(with_pool(pool))
         .and_then(handlers::remove_owners)
         .recover(error::recover)
 }
 
-/// `GET /api/v1/crates/?q=<query>&per_page=<per_page>`
-fn search() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+/// `GET /api/v1/crates/<crate_name>`
+fn crate_info(
+    pool: DbConnPool,
+    dl: Arc<Template>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!(CrateName)
+        .and(warp::get())
+        .and(with_pool(pool))
+        .and(with_template(dl))
+        .and_then(handlers::crate_info)
+        .recover(error::recover)
+}
+
+/// `GET /api/v1/crates/<crate_name>/versions`
+fn crate_versions(
+    pool: DbConnPool,
+    dl: Arc<Template>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!(CrateName / "versions")
+        .and(warp::get())
+        .and(with_pool(pool))
+        .and(with_template(dl))
+        .and_then(handlers::crate_versions)
+        .recover(error::recover)
+}
+
+/// `GET /api/v1/crates/<crate_name>/<version>`
+fn version_info(
+    pool: DbConnPool,
+    dl: Arc<Template>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!(CrateName / Version)
+        .and(warp::get())
+        .and(with_pool(pool))
+        .and(with_template(dl))
+        .and_then(handlers::version_info)
+        .recover(error::recover)
+}
+
+/// `GET /api/v1/crates/<crate_name>/<version>/dependencies`
+fn dependencies(pool: DbConnPool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!(CrateName / Version / "dependencies")
+        .and(warp::get())
+        .and(with_pool(pool))
+        .and_then(handlers::dependencies)
+        .recover(error::recover)
+}
+
+/// `GET /api/v1/crates/<crate_name>/<version>/metadata`
+fn metadata(pool: DbConnPool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!(CrateName / Version / "metadata")
+        .and(warp::get())
+        .and(with_pool(pool))
+        .and_then(handlers::metadata)
+        .recover(error::recover)
+}
+
+/// `GET /api/v1/crates/<crate_name>/<version>/readme`
+fn readme(pool: DbConnPool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!(CrateName / Version / "readme")
+        .and(warp::get())
+        .and(with_pool(pool))
+        .and_then(handlers::readme)
+        .recover(error::recover)
+}
+
+/// `GET /api/v1/crates/<crate_name>/reverse_dependencies`
+fn reverse_dependencies(
+    pool: DbConnPool,
+    dl: Arc<Template>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!(CrateName / "reverse_dependencies")
+        .and(warp::get())
+        .and(with_pool(pool))
+        .and(with_template(dl))
+        .and_then(handlers::reverse_dependencies)
+        .recover(error::recover)
+}
+
+/// `GET /api/v1/crates/?q=<query>&per_page=<per_page>&page=<page>`
+fn search(pool: DbConnPool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path::end()
         .and(warp::get())
         .and(warp::query::<SearchQuery>())
+        .and(with_pool(pool))
         .and_then(handlers::search)
         .recover(error::recover)
 }
 
-/// `GET /api/v1/crates/<crate_name>/<version>/download`
+/// `GET <dl>`, by default `/api/v1/crates/<crate_name>/<version>/download`
 fn download(
-    storage: Arc<Mutex<impl StorageService>>,
+    index: Arc<impl IndexService>,
+    storage: Arc<impl StorageService>,
+    upstream: Option<Arc<dyn UpstreamService>>,
+    dl: Arc<Template>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path!(CrateName / Version / "download")
-        .and(warp::get())
+    warp::get()
+        .and(warp::path::full())
+        .and_then(move |path: FullPath| {
+            let dl = Arc::clone(&dl);
+            async move {
+                dl.matches(path.as_str())
+                    .ok_or_else(warp::reject::not_found)
+            }
+        })
+        .and(with_index(index))
         .and(with_storage(storage))
+        .and(with_upstream(upstream))
         .and_then(handlers::download)
         .recover(error::recover)
 }
 
+/// Authenticate the request with the API token that cargo sends in the `Authorization` header.
+fn authenticated(
+    auth: Arc<impl AuthService>,
+) -> impl Filter<Extract = (User,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(with_auth(auth))
+        .and_then(handlers::authenticate)
+}
+
 fn with_index(
     service: Arc<impl IndexService>,
 ) -> impl Filter<Extract = (Arc<impl IndexService>,), Error = Infallible> + Clone {
@@ -119,7 +278,29 @@ fn with_index(
 }
 
 fn with_storage(
-    service: Arc<Mutex<impl StorageService>>,
-) -> impl Filter<Extract = (Arc<Mutex<impl StorageService>>,), Error = Infallible> + Clone {
+    service: Arc<impl StorageService>,
+) -> impl Filter<Extract = (Arc<impl StorageService>,), Error = Infallible> + Clone {
+    warp::any().map(move || Arc::clone(&service))
+}
+
+fn with_upstream(
+    upstream: Option<Arc<dyn UpstreamService>>,
+) -> impl Filter<Extract = (Option<Arc<dyn UpstreamService>>,), Error = Infallible> + Clone {
+    warp::any().map(move || upstream.clone())
+}
+
+fn with_auth(
+    service: Arc<impl AuthService>,
+) -> impl Filter<Extract = (Arc<impl AuthService>,), Error = Infallible> + Clone {
     warp::any().map(move || Arc::clone(&service))
 }
+
+fn with_template(
+    dl: Arc<Template>,
+) -> impl Filter<Extract = (Arc<Template>,), Error = Infallible> + Clone {
+    warp::any().map(move || Arc::clone(&dl))
+}
+
+fn with_pool(pool: DbConnPool) -> impl Filter<Extract = (DbConnPool,), Error = Infallible> + Clone {
+    warp::any().map(move || pool.clone())
+}
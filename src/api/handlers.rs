@@ -1,73 +1,177 @@
-use std::{collections::BTreeSet, convert::Infallible, sync::Arc};
+use std::{collections::BTreeSet, io, sync::Arc};
 
-use anyhow::ensure;
+use anyhow::{anyhow, ensure, Context};
+use futures_util::{stream, Stream, TryStreamExt};
 use hyper::{
     body::{Buf, Bytes},
-    Body,
+    header::CONTENT_TYPE,
+    Body, Uri,
 };
 use semver::Version;
-use tokio::{sync::Mutex, task};
-use tokio_util::codec::{BytesCodec, FramedRead};
-use tracing::instrument;
+use sha2::{Digest, Sha256};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::mpsc,
+    task,
+};
+use tokio_util::{
+    codec::{BytesCodec, FramedRead},
+    io::{StreamReader, SyncIoBridge},
+};
+use tracing::{error, instrument};
 use warp::{reply::Response, Rejection, Reply};
 
 use super::{
-    error::{Result, ServerError},
+    error::{self, ApiError, Result, ServerError},
     models::{
-        AddOwnersRequest, AddOwnersResponse, Crate, ListOwnersResponse, Meta, PublishRequest,
-        PublishResponse, RemoveOwnersRequest, RemoveOwnersResponse, SearchQuery, SearchResponse,
-        UnyankResponse, User, Warnings, YankResponse,
+        AddOwnersRequest, AddOwnersResponse, Crate, CrateDetails, CrateResponse,
+        DependenciesResponse, DependencyDetails, ListOwnersResponse, Meta, PublishRequest,
+        PublishResponse, RemoveOwnersRequest, RemoveOwnersResponse, ReverseDependenciesResponse,
+        SearchQuery, SearchResponse, UnyankResponse, User, VersionDetails, VersionResponse,
+        VersionsResponse, Warnings, YankResponse,
     },
 };
-use crate::{index, models::CrateName, storage};
-
-pub struct PublishRequestWithData(PublishRequest, Vec<u8>);
-
-impl PublishRequestWithData {
-    fn from_bytes(mut data: Bytes) -> anyhow::Result<Self> {
-        let len = data.get_u32_le() as usize;
-        ensure!(
-            data.remaining() >= len,
-            "expected at least {} bytes but only {} remaining",
-            len,
-            data.remaining()
-        );
+use crate::{
+    auth,
+    db::{self, owners::Claim, users::User as DbUser, DbConnPool},
+    index::{
+        self,
+        dl::{Download, Template},
+    },
+    models::CrateName,
+    package, settings, storage, upstream,
+};
 
-        let request = serde_json::from_slice(&data.slice(0..len))?;
-        data.advance(len);
+/// Maximum size of the metadata JSON in a publish request.
+pub const MAX_METADATA_SIZE: u64 = 1024 * 1024;
+/// Size of the chunks that uploaded crate files are passed on to the storage in.
+const CHUNK_SIZE: usize = 64 * 1024;
 
-        let len = data.get_u32_le() as usize;
-        ensure!(
-            data.remaining() == len,
-            "expected {} bytes but only {} remaining",
-            len,
-            data.remaining()
-        );
+/// Read the metadata part of a publish request, returning the parsed request, the raw metadata
+/// JSON and the length of the crate file that follows.
+async fn read_metadata(
+    body: &mut (impl AsyncRead + Unpin),
+) -> anyhow::Result<(PublishRequest, String, u64)> {
+    let len = body
+        .read_u32_le()
+        .await
+        .context("missing length of the metadata")?;
+    ensure!(
+        u64::from(len) <= MAX_METADATA_SIZE,
+        "metadata is too large ({len} bytes, max {MAX_METADATA_SIZE} bytes)"
+    );
 
-        let buf = data.to_vec();
+    let mut metadata = vec![0; len as usize];
+    body.read_exact(&mut metadata)
+        .await
+        .with_context(|| format!("expected {len} bytes of metadata"))?;
 
-        Ok(Self(request, buf))
-    }
+    let metadata = String::from_utf8(metadata)?;
+    let request = serde_json::from_str(&metadata)?;
+
+    let len = body
+        .read_u32_le()
+        .await
+        .context("missing length of the crate file")?;
+
+    Ok((request, metadata, len.into()))
+}
+
+pub async fn authenticate(header: Option<String>, auth: Arc<impl auth::Service>) -> Result<DbUser> {
+    let token = header
+        .map(|h| h.trim_start_matches("Bearer ").trim().to_owned())
+        .filter(|t| !t.is_empty())
+        .ok_or_else(|| ApiError::Unauthorized("missing API token".to_owned()))?;
+
+    auth.authenticate(&token)
+        .await
+        .map_err(ServerError)?
+        .ok_or_else(|| ApiError::Unauthorized("invalid API token".to_owned()).into())
 }
 
-#[instrument(skip(data, storage, index))]
+#[instrument(skip_all, fields(user = %user.name))]
 pub async fn crates_new(
-    data: Bytes,
-    storage: Arc<Mutex<impl storage::Service>>,
+    user: DbUser,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>> + Send + 'static,
+    limits: settings::Publish,
+    storage: Arc<impl storage::Service>,
     index: Arc<impl index::Service>,
+    upstream: Option<Arc<dyn upstream::Service>>,
+    pool: DbConnPool,
 ) -> Result<impl Reply> {
-    let data = PublishRequestWithData::from_bytes(data).map_err(ServerError)?;
+    let mut body = StreamReader::new(Box::pin(
+        body.map_ok(|mut buf| buf.copy_to_bytes(buf.remaining()))
+            .map_err(io::Error::other),
+    ));
 
-    storage
-        .lock()
-        .await
-        .store(&data.0.name, &data.0.vers, &data.1)
+    let (req, metadata, crate_size) = read_metadata(&mut body)
         .await
-        .map_err(ServerError)?;
+        .map_err(|e| ApiError::BadRequest(format!("invalid publish request: {e:#}")))?;
+    if crate_size > limits.max_crate_size {
+        return Err(ApiError::PayloadTooLarge(format!(
+            "crate file is too large ({crate_size} bytes, max {} bytes)",
+            limits.max_crate_size
+        ))
+        .into());
+    }
+
+    let name = req.name.clone();
+    let version = req.vers.clone();
+
+    let in_index = {
+        let (index, name) = (Arc::clone(&index), name.clone());
+        task::spawn_blocking(move || index.read_crate(&name).map(|file| file.is_some()))
+            .await
+            .map_err(ServerError::from)?
+            .map_err(ServerError)?
+    };
+
+    // New crates must not shadow the ones from the upstream registry, or builds would silently
+    // pick up the local crate instead (dependency confusion). If the upstream can't be reached,
+    // the publish fails rather than risking that.
+    if let (false, Some(upstream)) = (in_index, upstream) {
+        if upstream
+            .read_crate(&name)
+            .await
+            .map_err(ServerError)?
+            .is_some()
+        {
+            return Err(ApiError::Forbidden(format!(
+                "crate {name} exists in the upstream registry and can't be published here"
+            ))
+            .into());
+        }
+    }
+
+    let claim = {
+        let (name, version, user) = (name.clone(), version.clone(), user.clone());
+        pool.run(move |conn| db::owners::claim(conn, &name, &version, &user, in_index))
+            .await
+            .map_err(ServerError)?
+    };
+    if claim == Claim::Denied {
+        return Err(not_an_owner(&user, &name).into());
+    }
 
-    task::spawn_blocking(move || index.add_crate(data.0, &data.1).map_err(ServerError))
+    let published = publish(req.clone(), &mut body, crate_size, limits, &*storage, index).await;
+
+    let checksum = match published {
+        Ok(checksum) => checksum,
+        Err(e) => {
+            if claim == Claim::New {
+                let name = name.clone();
+                if let Err(e) = pool.run(move |conn| db::owners::unclaim(conn, &name)).await {
+                    error!(error = ?e, "failed releasing the claim of a new crate");
+                }
+            }
+            return Err(e);
+        }
+    };
+
+    pool.run(move |conn| db::crates::upsert(conn, &req, &metadata, &checksum, crate_size, &user))
         .await
-        .map_err(|e| ServerError(e.into()))??;
+        .map_err(ServerError)?;
 
     Ok(warp::reply::json(&PublishResponse {
         warnings: Warnings {
@@ -78,98 +182,783 @@ pub async fn crates_new(
     }))
 }
 
-#[instrument(skip(index))]
+/// Check, upload and add a new release to the index. Returns the checksum of the crate file.
+async fn publish(
+    req: PublishRequest,
+    body: &mut (impl AsyncRead + Send + Unpin),
+    crate_size: u64,
+    limits: settings::Publish,
+    storage: &impl storage::Service,
+    index: Arc<impl index::Service>,
+) -> Result<String> {
+    // Reject existing versions before receiving the whole crate file. The check is repeated when
+    // the release is added to the index, as another publish may finish in the meantime.
+    let req = {
+        let index = Arc::clone(&index);
+        task::spawn_blocking(move || index.check_crate(&req).map(|()| req))
+            .await
+            .map_err(ServerError::from)?
+            .map_err(error::reject)?
+    };
+
+    let checksum = upload(storage, &req, body, crate_size, limits).await?;
+
+    let (name, version) = (req.name.clone(), req.vers.clone());
+    let added = {
+        let checksum = checksum.clone();
+        task::spawn_blocking(move || index.add_crate(req, &checksum))
+            .await
+            .map_err(ServerError::from)?
+    };
+
+    if let Err(e) = added {
+        if let Err(e) = storage.delete(&name, &version).await {
+            error!(error = ?e, "failed removing stored crate after index update failed");
+        }
+        return Err(error::reject(e));
+    }
+
+    Ok(checksum)
+}
+
+/// Stream the crate file from the request body into the storage, validating the package and
+/// calculating its checksum on the way, so it never has to be held in memory as a whole. Returns
+/// the hex encoded SHA-256 checksum of the file.
+async fn upload(
+    storage: &impl storage::Service,
+    req: &PublishRequest,
+    body: &mut (impl AsyncRead + Send + Unpin),
+    len: u64,
+    limits: settings::Publish,
+) -> Result<String> {
+    let (store_tx, store_rx) = mpsc::channel(4);
+    let (check_tx, check_rx) = mpsc::channel(4);
+
+    let check = {
+        let req = req.clone();
+        let reader = SyncIoBridge::new(channel_reader(check_rx));
+        task::spawn_blocking(move || package::validate(&req, reader, limits))
+    };
+
+    let store = storage.store(
+        &req.name,
+        &req.vers,
+        len,
+        Box::pin(channel_reader(store_rx)),
+    );
+    let feed = async move {
+        let result = feed(body, len, &store_tx, check_tx).await;
+        if let Err(e) = &result {
+            // Let the storage fail as well, instead of keeping a truncated file.
+            store_tx
+                .send(Err(io::Error::other(format!("{e:#}"))))
+                .await
+                .ok();
+        }
+        result
+    };
+
+    let delete = || async {
+        if let Err(e) = storage.delete(&req.name, &req.vers).await {
+            error!(error = ?e, "failed removing stored crate after upload failed");
+        }
+    };
+
+    let checksum = match tokio::join!(store, feed) {
+        (Ok(()), Ok(Some(checksum))) => checksum,
+        (Ok(()), Ok(None)) => {
+            delete().await;
+            return Err(ServerError(anyhow!("storage stopped reading the crate file")).into());
+        }
+        (stored, Err(e)) => {
+            if stored.is_ok() {
+                delete().await;
+            }
+            return Err(ApiError::BadRequest(format!("invalid publish request: {e:#}")).into());
+        }
+        (Err(e), Ok(_)) => return Err(error::reject(e)),
+    };
+
+    if let Err(e) = check.await.map_err(ServerError::from)? {
+        delete().await;
+        return Err(ApiError::BadRequest(format!("{e:#}")).into());
+    }
+
+    Ok(checksum)
+}
+
+/// Read the crate file from the body and pass it on in chunks to the storage and the validation,
+/// followed by the end of the body. Returns the checksum of the file, or `None` if the storage
+/// stopped reading early, which means that it failed.
+async fn feed(
+    body: &mut (impl AsyncRead + Unpin),
+    len: u64,
+    store: &mpsc::Sender<io::Result<Bytes>>,
+    check: mpsc::Sender<io::Result<Bytes>>,
+) -> anyhow::Result<Option<String>> {
+    let mut reader = body.take(len);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+
+        let chunk = Bytes::copy_from_slice(&buf[..n]);
+        hasher.update(&chunk);
+
+        // The validation stops reading at the end of the archive or the first issue it finds.
+        check.send(Ok(chunk.clone())).await.ok();
+        if store.send(Ok(chunk)).await.is_err() {
+            return Ok(None);
+        }
+    }
+
+    ensure!(
+        reader.limit() == 0,
+        "expected {len} bytes of crate file but only got {}",
+        len - reader.limit()
+    );
+    ensure!(
+        reader.into_inner().read(&mut [0]).await? == 0,
+        "unexpected data after the crate file"
+    );
+
+    Ok(Some(hex::encode(hasher.finalize())))
+}
+
+/// Turn the receiving side of a channel into a reader, that ends once all senders are gone.
+fn channel_reader(
+    rx: mpsc::Receiver<io::Result<Bytes>>,
+) -> impl AsyncRead + Send + Unpin + 'static {
+    StreamReader::new(Box::pin(stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    })))
+}
+
+#[instrument(skip(user, index, pool), fields(user = %user.name))]
 pub async fn yank(
     name: CrateName,
     version: Version,
+    user: DbUser,
     index: Arc<impl index::Service>,
-) -> Result<impl Reply, Infallible> {
-    task::spawn_blocking(move || {
-        index.yank(name, version, true).unwrap();
-    });
+    pool: DbConnPool,
+) -> Result<impl Reply> {
+    set_yanked(name, version, true, &user, index, pool).await?;
 
     Ok(warp::reply::json(&YankResponse { ok: true }))
 }
 
-#[instrument(skip(index))]
+#[instrument(skip(user, index, pool), fields(user = %user.name))]
 pub async fn unyank(
     name: CrateName,
     version: Version,
+    user: DbUser,
     index: Arc<impl index::Service>,
-) -> Result<impl Reply, Infallible> {
-    task::spawn_blocking(move || {
-        index.yank(name, version, false).unwrap();
-    });
+    pool: DbConnPool,
+) -> Result<impl Reply> {
+    set_yanked(name, version, false, &user, index, pool).await?;
 
     Ok(warp::reply::json(&UnyankResponse { ok: true }))
 }
 
-#[instrument]
-pub async fn list_owners(_name: CrateName) -> Result<impl Reply, Infallible> {
+/// Update the yanked state of a single crate version in the index and the database, if the user
+/// is one of the crate's owners. Unknown versions are reported before checking the ownership.
+async fn set_yanked(
+    name: CrateName,
+    version: Version,
+    yank: bool,
+    user: &DbUser,
+    index: Arc<impl index::Service>,
+    pool: DbConnPool,
+) -> Result<()> {
+    let exists = {
+        let (index, name, version) = (Arc::clone(&index), name.clone(), version.clone());
+        task::spawn_blocking(move || index.read_release(&name, &version))
+            .await
+            .map_err(ServerError::from)?
+            .map_err(ServerError)?
+            .is_some()
+    };
+    if !exists {
+        return Err(version_not_found(&name, &version).into());
+    }
+
+    ensure_owner(&pool, &name, user).await?;
+
+    {
+        let (name, version) = (name.clone(), version.clone());
+        task::spawn_blocking(move || index.yank(name, version, yank))
+            .await
+            .map_err(ServerError::from)?
+            .map_err(error::reject)?;
+    }
+
+    pool.run(move |conn| db::versions::set_yanked(conn, &name, &version, yank))
+        .await
+        .map_err(ServerError)
+        .map_err(Into::into)
+}
+
+#[instrument(skip(pool))]
+pub async fn list_owners(name: CrateName, pool: DbConnPool) -> Result<impl Reply> {
+    let users = {
+        let name = name.clone();
+        pool.run(move |conn| db::owners::list(conn, &name))
+            .await
+            .map_err(ServerError)?
+    }
+    .ok_or_else(|| crate_not_found(&name))?;
+
     Ok(warp::reply::json(&ListOwnersResponse {
-        users: vec![User {
-            id: 70,
-            login: "github:rust-lang:core".to_owned(),
-            name: Some("Core".to_owned()),
-        }],
+        users: users
+            .into_iter()
+            .map(to_user)
+            .collect::<anyhow::Result<_>>()
+            .map_err(ServerError)?,
     }))
 }
 
-#[instrument(skip(_req))]
+#[instrument(skip(user, req, pool), fields(user = %user.name))]
 pub async fn add_owners(
-    _name: CrateName,
-    _req: AddOwnersRequest,
-) -> Result<impl Reply, Infallible> {
-    Ok(warp::reply::json(&AddOwnersResponse {
-        ok: true,
-        msg: "user ehuss has been invited to be an owner of crate cargo".to_owned(),
-    }))
+    name: CrateName,
+    user: DbUser,
+    req: AddOwnersRequest,
+    pool: DbConnPool,
+) -> Result<impl Reply> {
+    ensure_owner(&pool, &name, &user).await?;
+
+    let logins = req.users.iter().cloned().collect::<Vec<_>>().join(", ");
+    let msg = format!("user {logins} has been added as an owner of crate {name}");
+
+    pool.run(move |conn| {
+        let users = find_users(conn, &req.users)?;
+        db::owners::add(conn, &name, &users)
+    })
+    .await
+    .map_err(error::reject)?;
+
+    Ok(warp::reply::json(&AddOwnersResponse { ok: true, msg }))
 }
 
-#[instrument(skip(_req))]
+#[instrument(skip(user, req, pool), fields(user = %user.name))]
 pub async fn remove_owners(
-    _name: CrateName,
-    _req: RemoveOwnersRequest,
-) -> Result<impl Reply, Infallible> {
+    name: CrateName,
+    user: DbUser,
+    req: RemoveOwnersRequest,
+    pool: DbConnPool,
+) -> Result<impl Reply> {
+    ensure_owner(&pool, &name, &user).await?;
+
+    pool.run(move |conn| {
+        let users = find_users(conn, &req.users)?;
+        db::owners::remove(conn, &name, &users)
+    })
+    .await
+    .map_err(error::reject)?;
+
     Ok(warp::reply::json(&RemoveOwnersResponse {
         ok: true,
-        msg: "".to_owned(),
+        msg: "owners successfully removed".to_owned(),
     }))
 }
 
-#[instrument]
-pub async fn search(_query: SearchQuery) -> Result<impl Reply, Infallible> {
+/// Reject the request if the user isn't an owner of the crate.
+async fn ensure_owner(pool: &DbConnPool, name: &CrateName, user: &DbUser) -> Result<()> {
+    let owner = {
+        let (name, user) = (name.clone(), user.clone());
+        pool.run(move |conn| db::owners::is_owner(conn, &name, &user))
+            .await
+            .map_err(ServerError)?
+    };
+
+    if owner {
+        Ok(())
+    } else {
+        Err(not_an_owner(user, name).into())
+    }
+}
+
+fn not_an_owner(user: &DbUser, name: &CrateName) -> ApiError {
+    ApiError::Forbidden(format!(
+        "user {} is not an owner of crate {name}",
+        user.name
+    ))
+}
+
+/// Resolve all login names to their users, failing if any of them is unknown.
+fn find_users(
+    conn: &rusqlite::Connection,
+    logins: &BTreeSet<String>,
+) -> anyhow::Result<Vec<DbUser>> {
+    logins
+        .iter()
+        .map(|login| {
+            db::users::find_by_name(conn, login)?.ok_or_else(|| {
+                ApiError::BadRequest(format!("could not find user with login `{login}`")).into()
+            })
+        })
+        .collect()
+}
+
+#[instrument(skip(pool))]
+pub async fn search(query: SearchQuery, pool: DbConnPool) -> Result<impl Reply> {
+    let limit = u32::from(query.per_page.clamp(1, 100));
+    let offset = query.page.saturating_sub(1).saturating_mul(limit);
+    let (crates, total) = pool
+        .run(move |conn| db::crates::search(conn, &query.q, offset, limit))
+        .await
+        .map_err(ServerError)?;
+
     Ok(warp::reply::json(&SearchResponse {
-        crates: vec![Crate {
-            name: "rand".parse().unwrap(),
-            max_version: "0.6.1".parse().unwrap(),
-            description: "Random number generators and other randomness functionality.\n"
-                .to_owned(),
-        }],
-        meta: Meta { total: 119 },
+        crates: crates
+            .into_iter()
+            .map(|c| Crate {
+                name: c.name,
+                max_version: c.max_version,
+                description: c.description,
+            })
+            .collect(),
+        meta: Meta { total },
     }))
 }
 
-#[instrument(skip(storage))]
-pub async fn download(
+#[instrument(skip(pool, dl))]
+pub async fn crate_info(
+    name: CrateName,
+    pool: DbConnPool,
+    dl: Arc<Template>,
+) -> Result<impl Reply> {
+    let (details, versions) = {
+        let n = name.clone();
+        pool.run(move |conn| {
+            let details = db::crates::find(conn, &n)?;
+            let versions = db::versions::list(conn, &n)?;
+            anyhow::Ok(details.map(|d| (d, versions)))
+        })
+        .await
+        .map_err(ServerError)?
+        .ok_or_else(|| crate_not_found(&name))?
+    };
+
+    Ok(warp::reply::json(&CrateResponse {
+        krate: CrateDetails {
+            name: details.name,
+            max_version: details.max_version,
+            description: details.description,
+            homepage: details.homepage,
+            documentation: details.documentation,
+            repository: details.repository,
+            keywords: details.keywords,
+            categories: details.categories,
+            created_at: timestamp(details.created_at).map_err(ServerError)?,
+            updated_at: timestamp(details.updated_at).map_err(ServerError)?,
+        },
+        versions: versions
+            .into_iter()
+            .map(|v| to_version_details(&name, v, &dl))
+            .collect::<anyhow::Result<_>>()
+            .map_err(ServerError)?,
+    }))
+}
+
+#[instrument(skip(pool, dl))]
+pub async fn crate_versions(
+    name: CrateName,
+    pool: DbConnPool,
+    dl: Arc<Template>,
+) -> Result<impl Reply> {
+    let versions = {
+        let n = name.clone();
+        pool.run(move |conn| {
+            if db::crates::find(conn, &n)?.is_none() {
+                return Ok(None);
+            }
+            db::versions::list(conn, &n).map(Some)
+        })
+        .await
+        .map_err(ServerError)?
+        .ok_or_else(|| crate_not_found(&name))?
+    };
+
+    Ok(warp::reply::json(&VersionsResponse {
+        meta: Meta {
+            total: versions.len() as u64,
+        },
+        versions: versions
+            .into_iter()
+            .map(|v| to_version_details(&name, v, &dl))
+            .collect::<anyhow::Result<_>>()
+            .map_err(ServerError)?,
+    }))
+}
+
+#[instrument(skip(pool, dl))]
+pub async fn version_info(
     name: CrateName,
     version: Version,
-    storage: Arc<Mutex<impl storage::Service>>,
+    pool: DbConnPool,
+    dl: Arc<Template>,
+) -> Result<impl Reply> {
+    let info = find_version(&pool, &name, &version).await?;
+
+    Ok(warp::reply::json(&VersionResponse {
+        version: to_version_details(&name, info, &dl).map_err(ServerError)?,
+    }))
+}
+
+#[instrument(skip(pool))]
+pub async fn dependencies(
+    name: CrateName,
+    version: Version,
+    pool: DbConnPool,
+) -> Result<impl Reply> {
+    let id = find_version(&pool, &name, &version).await?.id;
+    let deps = pool
+        .run(move |conn| db::versions::dependencies(conn, id))
+        .await
+        .map_err(ServerError)?;
+
+    Ok(warp::reply::json(&DependenciesResponse {
+        dependencies: deps.into_iter().map(to_dependency_details).collect(),
+    }))
+}
+
+/// Serve the metadata of a crate version as JSON, exactly as cargo sent it when publishing. It can
+/// contain fields that the registry doesn't know about.
+#[instrument(skip(pool))]
+pub async fn metadata(name: CrateName, version: Version, pool: DbConnPool) -> Result<impl Reply> {
+    let id = find_version(&pool, &name, &version).await?.id;
+    let metadata = pool
+        .run(move |conn| db::versions::metadata(conn, id))
+        .await
+        .map_err(ServerError)?
+        .ok_or_else(|| {
+            ApiError::NotFound(format!(
+                "crate `{name}` version `{version}` has no recorded metadata"
+            ))
+        })?;
+
+    Ok(warp::reply::with_header(
+        metadata,
+        CONTENT_TYPE,
+        "application/json",
+    ))
+}
+
+/// Serve the README of a crate version as it was published, in its original Markdown format.
+#[instrument(skip(pool))]
+pub async fn readme(name: CrateName, version: Version, pool: DbConnPool) -> Result<impl Reply> {
+    let id = find_version(&pool, &name, &version).await?.id;
+    let readme = pool
+        .run(move |conn| db::versions::readme(conn, id))
+        .await
+        .map_err(ServerError)?
+        .ok_or_else(|| {
+            ApiError::NotFound(format!("crate `{name}` version `{version}` has no README"))
+        })?;
+
+    Ok(warp::reply::with_header(
+        readme,
+        CONTENT_TYPE,
+        "text/markdown; charset=utf-8",
+    ))
+}
+
+#[instrument(skip(pool, dl))]
+pub async fn reverse_dependencies(
+    name: CrateName,
+    pool: DbConnPool,
+    dl: Arc<Template>,
+) -> Result<impl Reply> {
+    let deps = {
+        let n = name.clone();
+        pool.run(move |conn| {
+            if db::crates::find(conn, &n)?.is_none() {
+                return Ok(None);
+            }
+            db::versions::reverse_dependencies(conn, &n).map(Some)
+        })
+        .await
+        .map_err(ServerError)?
+        .ok_or_else(|| crate_not_found(&name))?
+    };
+
+    let mut response = ReverseDependenciesResponse {
+        dependencies: Vec::with_capacity(deps.len()),
+        versions: Vec::with_capacity(deps.len()),
+        meta: Meta {
+            total: deps.len() as u64,
+        },
+    };
+
+    for dep in deps {
+        response
+            .dependencies
+            .push(to_dependency_details(dep.dependency));
+        response
+            .versions
+            .push(to_version_details(&dep.name, dep.version, &dl).map_err(ServerError)?);
+    }
+
+    Ok(warp::reply::json(&response))
+}
+
+/// Load a single crate version, rejecting the request if it doesn't exist.
+async fn find_version(
+    pool: &DbConnPool,
+    name: &CrateName,
+    version: &Version,
+) -> Result<db::versions::VersionInfo> {
+    let (n, v) = (name.clone(), version.clone());
+
+    pool.run(move |conn| db::versions::find(conn, &n, &v))
+        .await
+        .map_err(ServerError)?
+        .ok_or_else(|| version_not_found(name, version).into())
+}
+
+fn to_user(user: DbUser) -> anyhow::Result<User> {
+    Ok(User {
+        id: user.id.try_into()?,
+        login: user.name,
+        name: None,
+    })
+}
+
+fn to_version_details(
+    name: &CrateName,
+    info: db::versions::VersionInfo,
+    dl: &Template,
+) -> anyhow::Result<VersionDetails> {
+    Ok(VersionDetails {
+        id: info.id,
+        krate: name.clone(),
+        dl_path: dl.render(name, &info.num, &info.checksum),
+        num: info.num,
+        checksum: info.checksum,
+        crate_size: info.crate_size,
+        features: info.features,
+        links: info.links,
+        license: info.license,
+        rust_version: info.rust_version,
+        yanked: info.yanked,
+        published_by: info.published_by.map(to_user).transpose()?,
+        created_at: timestamp(info.created_at)?,
+    })
+}
+
+fn to_dependency_details(dep: db::versions::DependencyInfo) -> DependencyDetails {
+    DependencyDetails {
+        id: dep.id,
+        version_id: dep.version_id,
+        crate_id: dep.crate_name,
+        req: dep.req,
+        optional: dep.optional,
+        default_features: dep.default_features,
+        features: dep.features,
+        target: dep.target,
+        kind: dep.kind,
+        registry: dep.registry,
+    }
+}
+
+/// Format seconds since the Unix epoch as RFC 3339 timestamp.
+fn timestamp(secs: i64) -> anyhow::Result<String> {
+    OffsetDateTime::from_unix_timestamp(secs)?
+        .format(&Rfc3339)
+        .map_err(Into::into)
+}
+
+#[instrument(skip(index, storage, upstream))]
+pub async fn download(
+    Download {
+        name,
+        version,
+        checksum,
+    }: Download,
+    index: Arc<impl index::Service>,
+    storage: Arc<impl storage::Service>,
+    upstream: Option<Arc<dyn upstream::Service>>,
 ) -> Result<impl Reply, Rejection> {
-    let file = storage.lock().await.get(&name, &version).await.unwrap();
+    let release = {
+        let (index, name, version) = (Arc::clone(&index), name.clone(), version.clone());
+        task::spawn_blocking(move || index.read_release(&name, &version))
+            .await
+            .map_err(ServerError::from)?
+            .map_err(ServerError)?
+    };
+
+    let found = match (release, upstream) {
+        (Some(release), _) => checksum.as_ref().is_none_or(|c| *c == release.cksum),
+        // Crates that don't exist locally may come from the upstream registry, in which case
+        // the file is fetched into the storage first.
+        (None, Some(upstream)) => {
+            let local = {
+                let name = name.clone();
+                task::spawn_blocking(move || index.read_crate(&name))
+                    .await
+                    .map_err(ServerError::from)?
+                    .map_err(ServerError)?
+            };
+
+            local.is_none()
+                && upstream
+                    .fetch_crate(&name, &version, checksum.as_deref())
+                    .await
+                    .map_err(ServerError)?
+        }
+        (None, None) => false,
+    };
+
+    if !found {
+        return Err(version_not_found(&name, &version).into());
+    }
+
+    // Let clients fetch the file straight from the storage backend if possible, so large
+    // tarballs don't have to pass through the registry.
+    if let Some(url) = storage
+        .download_url(&name, &version)
+        .await
+        .map_err(ServerError)?
+    {
+        let uri = url.parse::<Uri>().map_err(ServerError::from)?;
+        return Ok(warp::redirect::found(uri).into_response());
+    }
+
+    let file = storage
+        .get(&name, &version)
+        .await
+        .map_err(ServerError)?
+        .ok_or_else(|| version_not_found(&name, &version))?;
+
+    let stream = FramedRead::new(file, BytesCodec::new());
+    let body = Body::wrap_stream(stream);
+
+    Ok(Response::new(body))
+}
 
-    match file {
-        Some(file) => {
-            let stream = FramedRead::new(file, BytesCodec::new());
-            let body = Body::wrap_stream(stream);
+fn crate_not_found(name: &CrateName) -> ApiError {
+    ApiError::NotFound(format!("crate `{name}` does not exist"))
+}
+
+fn version_not_found(name: &CrateName, version: &Version) -> ApiError {
+    ApiError::NotFound(format!(
+        "crate `{name}` does not have a version `{version}`"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn list_owners_of_unknown_crate() {
+        let pool = db::memory_pool();
+
+        let rejection = list_owners("test".parse().unwrap(), pool)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(rejection.find(), Some(ApiError::NotFound(_))));
+    }
+
+    fn test_index(location: &std::path::Path) -> Arc<impl index::Service> {
+        Arc::new(
+            index::new(&settings::Index {
+                location: location.to_owned(),
+                config: index::models::Config {
+                    dl: "http://localhost:8080/api/v1/crates".parse().unwrap(),
+                    api: "http://localhost:8080".parse().unwrap(),
+                },
+            })
+            .unwrap(),
+        )
+    }
+
+    /// Upstream registry that has every crate, but never any `.crate` files.
+    struct FakeUpstream;
+
+    #[async_trait::async_trait]
+    impl upstream::Service for FakeUpstream {
+        async fn read_crate(&self, _name: &CrateName) -> anyhow::Result<Option<index::IndexFile>> {
+            Ok(Some(index::IndexFile {
+                content: Vec::new(),
+                modified: std::time::SystemTime::now(),
+            }))
+        }
 
-            Ok(Response::new(body))
+        async fn fetch_crate(
+            &self,
+            _name: &CrateName,
+            _version: &Version,
+            _checksum: Option<&str>,
+        ) -> anyhow::Result<bool> {
+            Ok(false)
         }
-        None => Err(ServerError(
-            anyhow::anyhow!("crate {} with version {} not found", name, version)
-                .context("what")
-                .context("happened?"),
+    }
+
+    #[tokio::test]
+    async fn publish_upstream_crate() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = test_index(&dir.path().join("index"));
+        let storage = Arc::new(
+            storage::new(&settings::Storage::Filesystem {
+                location: dir.path().join("crates"),
+            })
+            .unwrap(),
+        );
+        let pool = db::memory_pool();
+        let user = db::users::get_or_create(&pool.get().unwrap(), "alice").unwrap();
+
+        let metadata = serde_json::json!({ "name": "serde", "vers": "1.0.0" }).to_string();
+        let body = [
+            &(metadata.len() as u32).to_le_bytes()[..],
+            metadata.as_bytes(),
+            &0_u32.to_le_bytes(),
+        ]
+        .concat();
+
+        let rejection = crates_new(
+            user,
+            stream::iter([Ok::<_, warp::Error>(Bytes::from(body))]),
+            settings::Publish::default(),
+            storage,
+            index,
+            Some(Arc::new(FakeUpstream)),
+            pool.clone(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(matches!(rejection.find(), Some(ApiError::Forbidden(_))));
+
+        let name = "serde".parse().unwrap();
+        assert!(db::owners::list(&pool.get().unwrap(), &name)
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn yank_unknown_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = test_index(dir.path());
+        let pool = db::memory_pool();
+        let user = db::users::get_or_create(&pool.get().unwrap(), "alice").unwrap();
+
+        // Not being an owner of a crate that doesn't exist isn't the problem.
+        let rejection = yank(
+            "test".parse().unwrap(),
+            "1.0.0".parse().unwrap(),
+            user,
+            index,
+            pool,
         )
-        .into()),
+        .await
+        .err()
+        .unwrap();
+        assert!(matches!(rejection.find(), Some(ApiError::NotFound(_))));
     }
 }
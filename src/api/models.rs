@@ -1,4 +1,9 @@
-use std::collections::{BTreeMap, BTreeSet};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+};
+
+use anyhow::bail;
 
 use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
@@ -17,25 +22,37 @@ pub struct ErrorDetail {
     pub detail: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Metadata of a new crate release as sent by cargo. Unknown fields are ignored and missing lists
+/// are treated as empty, so changes to cargo's format don't break publishing. The raw JSON is kept
+/// as well, to preserve all fields.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PublishRequest {
     pub name: CrateName,
     pub vers: Version,
+    #[serde(default)]
     pub deps: Vec<Dependency>,
+    #[serde(default)]
     pub features: BTreeMap<String, BTreeSet<String>>,
+    #[serde(default)]
     pub authors: BTreeSet<String>,
     pub description: Option<String>,
     pub documentation: Option<Url>,
     pub homepage: Option<Url>,
     pub readme: Option<String>,
     pub readme_file: Option<String>,
+    #[serde(default)]
     pub keywords: BTreeSet<String>,
+    #[serde(default)]
     pub categories: BTreeSet<String>,
     pub license: Option<String>,
     pub license_file: Option<String>,
     pub repository: Option<Url>,
+    #[serde(default)]
     pub badges: BTreeMap<String, BTreeMap<String, String>>,
     pub links: Option<String>,
+    /// Minimum supported Rust version, only sent by newer cargo versions.
+    #[serde(default)]
+    pub rust_version: Option<String>,
 }
 
 impl PublishRequest {
@@ -59,11 +76,12 @@ impl PublishRequest {
             repository: None,
             badges: BTreeMap::new(),
             links: None,
+            rust_version: None,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Dependency {
     pub name: String,
     pub version_req: VersionReq,
@@ -76,7 +94,7 @@ pub struct Dependency {
     pub explicit_name_in_toml: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum Kind {
     Dev,
@@ -84,6 +102,29 @@ pub enum Kind {
     Normal,
 }
 
+impl Kind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Dev => "dev",
+            Self::Build => "build",
+            Self::Normal => "normal",
+        }
+    }
+}
+
+impl FromStr for Kind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "dev" => Self::Dev,
+            "build" => Self::Build,
+            "normal" => Self::Normal,
+            _ => bail!("unknown dependency kind `{s}`"),
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct PublishResponse {
     pub warnings: Warnings,
@@ -150,7 +191,89 @@ pub struct SearchResponse {
 pub struct Crate {
     pub name: CrateName,
     pub max_version: Version,
-    pub description: String,
+    pub description: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CrateResponse {
+    #[serde(rename = "crate")]
+    pub krate: CrateDetails,
+    pub versions: Vec<VersionDetails>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CrateDetails {
+    pub name: CrateName,
+    pub max_version: Version,
+    pub description: Option<String>,
+    pub homepage: Option<String>,
+    pub documentation: Option<String>,
+    pub repository: Option<String>,
+    pub keywords: BTreeSet<String>,
+    pub categories: BTreeSet<String>,
+    /// Creation time in RFC 3339 format.
+    pub created_at: String,
+    /// Last update in RFC 3339 format.
+    pub updated_at: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VersionsResponse {
+    pub versions: Vec<VersionDetails>,
+    pub meta: Meta,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VersionResponse {
+    pub version: VersionDetails,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VersionDetails {
+    pub id: i64,
+    #[serde(rename = "crate")]
+    pub krate: CrateName,
+    pub num: Version,
+    pub dl_path: String,
+    pub checksum: String,
+    pub crate_size: u64,
+    pub features: BTreeMap<String, BTreeSet<String>>,
+    pub links: Option<String>,
+    pub license: Option<String>,
+    pub rust_version: Option<String>,
+    pub yanked: bool,
+    pub published_by: Option<User>,
+    /// Publishing time in RFC 3339 format.
+    pub created_at: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DependenciesResponse {
+    pub dependencies: Vec<DependencyDetails>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReverseDependenciesResponse {
+    pub dependencies: Vec<DependencyDetails>,
+    /// The dependent versions that the entries in `dependencies` refer to by `version_id`.
+    pub versions: Vec<VersionDetails>,
+    pub meta: Meta,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DependencyDetails {
+    pub id: i64,
+    pub version_id: i64,
+    /// Name of the crate that is depended on.
+    pub crate_id: String,
+    pub req: VersionReq,
+    pub optional: bool,
+    pub default_features: bool,
+    pub features: BTreeSet<String>,
+    pub target: Option<String>,
+    pub kind: Kind,
+    /// Index URL of the registry the dependency comes from, if not this one.
+    pub registry: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -160,8 +283,20 @@ pub struct Meta {
 
 #[derive(Debug, Deserialize)]
 pub struct SearchQuery {
+    #[serde(default)]
     pub q: String,
+    #[serde(default = "default_per_page")]
     pub per_page: u8,
+    #[serde(default = "default_page")]
+    pub page: u32,
+}
+
+const fn default_per_page() -> u8 {
+    10
+}
+
+const fn default_page() -> u32 {
+    1
 }
 
 #[cfg(test)]
@@ -222,6 +357,7 @@ mod tests {
                     },
                 },
                 links: None,
+                rust_version: Some("1.60".to_owned()),
             })
             .unwrap()
         );
@@ -327,12 +463,78 @@ mod tests {
                 crates: vec![Crate {
                     name: "rand".parse().unwrap(),
                     max_version: "0.6.1".parse().unwrap(),
-                    description: "Random number generators and other randomness functionality.\n"
-                        .to_owned(),
+                    description: Some(
+                        "Random number generators and other randomness functionality.\n".to_owned()
+                    ),
                 }],
                 meta: Meta { total: 119 }
             })
             .unwrap()
         );
     }
+
+    #[test]
+    fn serialize_version_response() {
+        assert_eq!(
+            serde_json::json!({
+                "version": {
+                    "id": 1,
+                    "crate": "rand",
+                    "num": "0.6.1",
+                    "dl_path": "/api/v1/crates/rand/0.6.1/download",
+                    "checksum": "a".repeat(64),
+                    "crate_size": 1024,
+                    "features": { "std": [] },
+                    "links": null,
+                    "license": "MIT OR Apache-2.0",
+                    "rust_version": null,
+                    "yanked": false,
+                    "published_by": { "id": 1, "login": "alice", "name": null },
+                    "created_at": "2019-01-01T12:00:00Z",
+                }
+            }),
+            serde_json::to_value(&VersionResponse {
+                version: VersionDetails {
+                    id: 1,
+                    krate: "rand".parse().unwrap(),
+                    num: "0.6.1".parse().unwrap(),
+                    dl_path: "/api/v1/crates/rand/0.6.1/download".to_owned(),
+                    checksum: "a".repeat(64),
+                    crate_size: 1024,
+                    features: btreemap! {
+                        "std".to_owned() => btreeset![],
+                    },
+                    links: None,
+                    license: Some("MIT OR Apache-2.0".to_owned()),
+                    rust_version: None,
+                    yanked: false,
+                    published_by: Some(User {
+                        id: 1,
+                        login: "alice".to_owned(),
+                        name: None,
+                    }),
+                    created_at: "2019-01-01T12:00:00Z".to_owned(),
+                }
+            })
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn deserialize_publish_request_leniently() {
+        let req = serde_json::from_str::<PublishRequest>(
+            r#"{
+                "name": "foo",
+                "vers": "1.0.0",
+                "description": "test",
+                "some_future_field": { "enabled": true }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!("foo", req.name.as_ref());
+        assert!(req.deps.is_empty());
+        assert!(req.badges.is_empty());
+        assert_eq!(None, req.rust_version);
+    }
 }
@@ -0,0 +1,660 @@
+//! Authentication [`Service`] that checks API tokens against an LDAP server. Tokens have the form
+//! `<user>:<password>` and are verified with a simple bind as the user's distinguished name. Only the
+//! few parts of the protocol that are needed for binding are implemented.
+//!
+//! Connections are secured with TLS for `ldaps` URLs, or upgraded with StartTLS when enabled in the
+//! settings. Successful binds are remembered for a few minutes, so cargo's requests don't each
+//! cause a round trip to the server.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use ring::constant_time;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{ClientConfig, ServerName},
+    TlsConnector,
+};
+use tracing::instrument;
+
+use super::Service;
+use crate::{
+    crypto,
+    db::{self, users::User, DbConnPool},
+    http, settings,
+};
+
+/// How long to wait for the LDAP server before giving up.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a successful bind is remembered.
+const CACHE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+/// Upper limit for the size of responses, which are tiny for bind operations.
+const MAX_RESPONSE: usize = 64 * 1024;
+/// Result code of a successful operation.
+const SUCCESS: u32 = 0;
+/// Result code for a wrong DN or password.
+const INVALID_CREDENTIALS: u32 = 49;
+/// Object identifier of the StartTLS extended operation, from RFC 4511.
+const START_TLS_OID: &[u8] = b"1.3.6.1.4.1.1466.20037";
+
+/// BER tags of the used LDAP message parts.
+mod tag {
+    pub const INTEGER: u8 = 0x02;
+    pub const OCTET_STRING: u8 = 0x04;
+    pub const ENUMERATED: u8 = 0x0a;
+    pub const SEQUENCE: u8 = 0x30;
+    pub const BIND_REQUEST: u8 = 0x60;
+    pub const BIND_RESPONSE: u8 = 0x61;
+    pub const UNBIND_REQUEST: u8 = 0x42;
+    pub const EXTENDED_REQUEST: u8 = 0x77;
+    pub const EXTENDED_RESPONSE: u8 = 0x78;
+    pub const SIMPLE_AUTH: u8 = 0x80;
+    pub const REQUEST_NAME: u8 = 0x80;
+}
+
+/// How the connection to the server is secured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Security {
+    /// Plain connection, for `ldap` URLs.
+    None,
+    /// TLS from the start, for `ldaps` URLs.
+    Tls,
+    /// Plain connection that is upgraded to TLS before binding.
+    StartTls,
+}
+
+/// Authentication [`Service`] implementation for LDAP servers. Users are created on first use.
+pub struct ServiceImpl {
+    /// Host and port of the server.
+    address: String,
+    /// Name of the server, that its certificate is checked against.
+    server_name: ServerName,
+    security: Security,
+    tls: TlsConnector,
+    bind_dn: String,
+    /// Recent successful binds, with a keyed hash of the password and the time they expire.
+    cache: Mutex<HashMap<String, ([u8; 32], Instant)>>,
+    /// Random key for hashing the cached passwords.
+    cache_key: [u8; 32],
+    pool: DbConnPool,
+}
+
+#[async_trait]
+impl Service for ServiceImpl {
+    #[instrument(skip_all)]
+    async fn authenticate(&self, token: &str) -> Result<Option<User>> {
+        let Some((user, password)) = token.split_once(':') else {
+            return Ok(None);
+        };
+
+        // A bind without password is an anonymous bind, which would always succeed.
+        if user.is_empty() || password.is_empty() {
+            return Ok(None);
+        }
+
+        // LDAP servers compare user names case-insensitively, so `Alice` and `alice` are the same
+        // account and must map to the same registry user.
+        let user = user.to_lowercase();
+        let hash = crypto::hmac_sha256(&self.cache_key, password.as_bytes());
+
+        if !self.is_cached(&user, &hash) {
+            let dn = self.bind_dn.replace("{user}", &escape_dn_value(&user));
+            let valid = tokio::time::timeout(TIMEOUT, self.bind(&dn, password))
+                .await
+                .context("LDAP server didn't respond in time")??;
+
+            if !valid {
+                return Ok(None);
+            }
+
+            self.remember(&user, hash);
+        }
+
+        self.pool
+            .run(move |conn| db::users::get_or_create(conn, &user).map(Some))
+            .await
+    }
+}
+
+impl ServiceImpl {
+    /// Check whether the user recently bound successfully with the same password.
+    fn is_cached(&self, user: &str, hash: &[u8; 32]) -> bool {
+        self.cache
+            .lock()
+            .get(user)
+            .is_some_and(|(expected, expires)| {
+                *expires > Instant::now()
+                    && constant_time::verify_slices_are_equal(expected, hash).is_ok()
+            })
+    }
+
+    /// Remember a successful bind, and forget any that expired in the meantime.
+    fn remember(&self, user: &str, hash: [u8; 32]) {
+        let now = Instant::now();
+        let mut cache = self.cache.lock();
+
+        cache.retain(|_, (_, expires)| *expires > now);
+        cache.insert(user.to_owned(), (hash, now + CACHE_LIFETIME));
+    }
+
+    /// Try a simple bind with the credentials, returning whether they are valid.
+    async fn bind(&self, dn: &str, password: &str) -> Result<bool> {
+        let mut stream = TcpStream::connect(&self.address)
+            .await
+            .context("failed connecting to the LDAP server")?;
+
+        let (code, message) = match self.security {
+            Security::None => bind(&mut stream, 1, dn, password).await?,
+            Security::Tls => {
+                let mut stream = self.connect_tls(stream).await?;
+                bind(&mut stream, 1, dn, password).await?
+            }
+            Security::StartTls => {
+                start_tls(&mut stream, 1).await?;
+                let mut stream = self.connect_tls(stream).await?;
+                bind(&mut stream, 2, dn, password).await?
+            }
+        };
+
+        match code {
+            SUCCESS => Ok(true),
+            INVALID_CREDENTIALS => Ok(false),
+            code => bail!("LDAP bind failed with result code {code}: {message}"),
+        }
+    }
+
+    /// Secure the connection with TLS, verifying the server certificate.
+    async fn connect_tls<S>(&self, stream: S) -> Result<tokio_rustls::client::TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.tls
+            .connect(self.server_name.clone(), stream)
+            .await
+            .context("TLS handshake with the LDAP server failed")
+    }
+}
+
+/// Send a bind request with the given message ID and return the result code and message.
+async fn bind(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    id: u8,
+    dn: &str,
+    password: &str,
+) -> Result<(u32, String)> {
+    stream.write_all(&bind_request(id, dn, password)).await?;
+    let result = parse_response(&read_message(stream).await?, tag::BIND_RESPONSE)?;
+
+    // Closing the connection is only a courtesy, the result is known already.
+    stream.write_all(&unbind_request(id + 1)).await.ok();
+
+    Ok(result)
+}
+
+/// Ask the server to upgrade the connection to TLS, as described in RFC 4511 section 4.14.
+async fn start_tls(stream: &mut (impl AsyncRead + AsyncWrite + Unpin), id: u8) -> Result<()> {
+    stream.write_all(&start_tls_request(id)).await?;
+    let (code, message) = parse_response(&read_message(stream).await?, tag::EXTENDED_RESPONSE)?;
+
+    ensure!(
+        code == SUCCESS,
+        "LDAP server refused StartTLS with result code {code}: {message}"
+    );
+
+    Ok(())
+}
+
+/// Escape a value for use in a distinguished name, as described in RFC 4514.
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '=' | '>' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' if i == 0 => escaped.push_str("\\#"),
+            ' ' if i == 0 || i == last => escaped.push_str("\\ "),
+            '\0' => escaped.push_str("\\00"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Encode a single BER element.
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+
+    if content.len() < 0x80 {
+        out.push(content.len() as u8);
+    } else {
+        let len = content.len().to_be_bytes();
+        let len = &len[len.iter().take_while(|&&b| b == 0).count()..];
+        out.push(0x80 | len.len() as u8);
+        out.extend_from_slice(len);
+    }
+
+    out.extend_from_slice(content);
+    out
+}
+
+fn bind_request(id: u8, dn: &str, password: &str) -> Vec<u8> {
+    let bind = [
+        tlv(tag::INTEGER, &[3]),
+        tlv(tag::OCTET_STRING, dn.as_bytes()),
+        tlv(tag::SIMPLE_AUTH, password.as_bytes()),
+    ]
+    .concat();
+
+    tlv(
+        tag::SEQUENCE,
+        &[tlv(tag::INTEGER, &[id]), tlv(tag::BIND_REQUEST, &bind)].concat(),
+    )
+}
+
+fn start_tls_request(id: u8) -> Vec<u8> {
+    tlv(
+        tag::SEQUENCE,
+        &[
+            tlv(tag::INTEGER, &[id]),
+            tlv(
+                tag::EXTENDED_REQUEST,
+                &tlv(tag::REQUEST_NAME, START_TLS_OID),
+            ),
+        ]
+        .concat(),
+    )
+}
+
+fn unbind_request(id: u8) -> Vec<u8> {
+    tlv(
+        tag::SEQUENCE,
+        &[tlv(tag::INTEGER, &[id]), tlv(tag::UNBIND_REQUEST, &[])].concat(),
+    )
+}
+
+/// Read a whole LDAP message from the stream, returning the content of the outer sequence.
+async fn read_message(reader: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>> {
+    let mut header = [0; 2];
+    reader.read_exact(&mut header).await?;
+    ensure!(header[0] == tag::SEQUENCE, "invalid LDAP message");
+
+    let len = if header[1] & 0x80 == 0 {
+        usize::from(header[1])
+    } else {
+        let count = usize::from(header[1] & 0x7f);
+        ensure!(count <= 4, "invalid LDAP message length");
+
+        let mut len = [0; 4];
+        reader.read_exact(&mut len[4 - count..]).await?;
+        u32::from_be_bytes(len) as usize
+    };
+    ensure!(len <= MAX_RESPONSE, "LDAP message is too large");
+
+    let mut content = vec![0; len];
+    reader.read_exact(&mut content).await?;
+
+    Ok(content)
+}
+
+/// Split off the first BER element, returning its tag, content and the remaining data.
+fn read_tlv(data: &[u8]) -> Result<(u8, &[u8], &[u8])> {
+    let &[tag, first, ref rest @ ..] = data else {
+        bail!("truncated BER element");
+    };
+
+    let (len, rest) = if first & 0x80 == 0 {
+        (usize::from(first), rest)
+    } else {
+        let count = usize::from(first & 0x7f);
+        ensure!(count <= 4 && rest.len() >= count, "invalid BER length");
+
+        let len = rest[..count]
+            .iter()
+            .fold(0, |len, &b| (len << 8) | usize::from(b));
+        (len, &rest[count..])
+    };
+
+    ensure!(rest.len() >= len, "truncated BER element");
+
+    Ok((tag, &rest[..len], &rest[len..]))
+}
+
+/// Extract the result code and diagnostic message from a response with the expected tag, like a
+/// bind response.
+fn parse_response(message: &[u8], expected: u8) -> Result<(u32, String)> {
+    let (tag, _id, rest) = read_tlv(message)?;
+    ensure!(
+        tag == tag::INTEGER,
+        "LDAP message is missing the message ID"
+    );
+
+    let (tag, response, _) = read_tlv(rest)?;
+    ensure!(tag == expected, "unexpected LDAP response");
+
+    let (tag, code, rest) = read_tlv(response)?;
+    ensure!(
+        tag == tag::ENUMERATED && code.len() <= 4,
+        "invalid LDAP result code"
+    );
+    let code = code.iter().fold(0, |code, &b| (code << 8) | u32::from(b));
+
+    let (_, _matched_dn, rest) = read_tlv(rest)?;
+    let (_, message, _) = read_tlv(rest)?;
+
+    Ok((code, String::from_utf8_lossy(message).into_owned()))
+}
+
+/// Create the LDAP authentication service from the settings.
+pub fn new(settings: &settings::Ldap, pool: DbConnPool) -> Result<ServiceImpl> {
+    let (security, default_port) = match (settings.url.scheme(), settings.start_tls) {
+        ("ldap", false) => (Security::None, 389),
+        ("ldap", true) => (Security::StartTls, 389),
+        ("ldaps", false) => (Security::Tls, 636),
+        ("ldaps", true) => bail!("StartTLS can't be used with `ldaps` URLs"),
+        _ => bail!("only `ldap` and `ldaps` URLs are supported"),
+    };
+    ensure!(
+        settings.bind_dn.contains("{user}"),
+        "the bind DN must contain the `{{user}}` marker"
+    );
+
+    let host = settings
+        .url
+        .host_str()
+        .context("LDAP URL is missing the host")?;
+    let server_name = ServerName::try_from(host.trim_start_matches('[').trim_end_matches(']'))
+        .context("LDAP URL has an invalid host")?;
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(http::root_certificates())
+        .with_no_client_auth();
+
+    Ok(ServiceImpl {
+        address: format!("{host}:{}", settings.url.port().unwrap_or(default_port)),
+        server_name,
+        security,
+        tls: TlsConnector::from(Arc::new(config)),
+        bind_dn: settings.bind_dn.clone(),
+        cache: Mutex::default(),
+        cache_key: rand::random(),
+        pool,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    };
+
+    use tokio::net::TcpListener;
+    use tokio_rustls::{
+        rustls::{self, Certificate, PrivateKey, RootCertStore},
+        TlsAcceptor,
+    };
+
+    use super::*;
+
+    /// Minimal stand-in for an LDAP server, that only accepts the user `alice` with the password
+    /// `secret`. It returns its address and a counter of the received bind requests.
+    async fn start_fake_server(security: Security) -> (url::Url, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let binds = Arc::new(AtomicUsize::new(0));
+        let acceptor = TlsAcceptor::from(Arc::new(server_config()));
+
+        tokio::spawn({
+            let binds = binds.clone();
+            async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    binds.fetch_add(1, Ordering::SeqCst);
+
+                    match security {
+                        Security::None => serve_bind(&mut stream).await,
+                        Security::Tls => {
+                            let mut stream = acceptor.accept(stream).await.unwrap();
+                            serve_bind(&mut stream).await;
+                        }
+                        Security::StartTls => {
+                            let message = read_message(&mut stream).await.unwrap();
+                            let (_, id, rest) = read_tlv(&message).unwrap();
+                            let (tag, request, _) = read_tlv(rest).unwrap();
+                            assert_eq!(tag::EXTENDED_REQUEST, tag);
+                            assert_eq!(START_TLS_OID, read_tlv(request).unwrap().1);
+
+                            stream
+                                .write_all(&response(id, tag::EXTENDED_RESPONSE, SUCCESS as u8))
+                                .await
+                                .unwrap();
+
+                            let mut stream = acceptor.accept(stream).await.unwrap();
+                            serve_bind(&mut stream).await;
+                        }
+                    }
+                }
+            }
+        });
+
+        let scheme = if security == Security::Tls {
+            "ldaps"
+        } else {
+            "ldap"
+        };
+        let url = format!("{scheme}://localhost:{}", addr.port());
+
+        (url.parse().unwrap(), binds)
+    }
+
+    /// Answer a single bind request.
+    async fn serve_bind(stream: &mut (impl AsyncRead + AsyncWrite + Unpin)) {
+        let message = read_message(stream).await.unwrap();
+
+        let (_, id, rest) = read_tlv(&message).unwrap();
+        let (_, bind, _) = read_tlv(rest).unwrap();
+        let (_, _version, rest) = read_tlv(bind).unwrap();
+        let (_, dn, rest) = read_tlv(rest).unwrap();
+        let (_, password, _) = read_tlv(rest).unwrap();
+
+        let valid = dn == b"uid=alice,ou=people,dc=example,dc=org" && password == b"secret";
+        let code = if valid { SUCCESS } else { INVALID_CREDENTIALS };
+
+        stream
+            .write_all(&response(id, tag::BIND_RESPONSE, code as u8))
+            .await
+            .unwrap();
+        stream.flush().await.unwrap();
+    }
+
+    fn response(id: &[u8], kind: u8, code: u8) -> Vec<u8> {
+        let result = [
+            tlv(tag::ENUMERATED, &[code]),
+            tlv(tag::OCTET_STRING, b""),
+            tlv(tag::OCTET_STRING, b""),
+        ]
+        .concat();
+
+        tlv(
+            tag::SEQUENCE,
+            &[tlv(tag::INTEGER, id), tlv(kind, &result)].concat(),
+        )
+    }
+
+    /// Self-signed certificate for `localhost` with its private key, created once for all tests.
+    fn certificate() -> &'static (Certificate, PrivateKey) {
+        static CERT: OnceLock<(Certificate, PrivateKey)> = OnceLock::new();
+        CERT.get_or_init(|| {
+            let cert = rcgen::generate_simple_self_signed(["localhost".to_owned()]).unwrap();
+            (
+                Certificate(cert.serialize_der().unwrap()),
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+        })
+    }
+
+    fn server_config() -> rustls::ServerConfig {
+        let (cert, key) = certificate().clone();
+
+        rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)
+            .unwrap()
+    }
+
+    /// Create the service, trusting the certificate of the fake server.
+    fn service(url: url::Url, start_tls: bool) -> ServiceImpl {
+        let mut service = new(
+            &settings::Ldap {
+                url,
+                start_tls,
+                bind_dn: "uid={user},ou=people,dc=example,dc=org".to_owned(),
+            },
+            db::memory_pool(),
+        )
+        .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(&certificate().0).unwrap();
+        service.tls = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+
+        service
+    }
+
+    #[tokio::test]
+    async fn bind() {
+        for (security, start_tls) in [
+            (Security::None, false),
+            (Security::Tls, false),
+            (Security::StartTls, true),
+        ] {
+            let (url, _) = start_fake_server(security).await;
+            let service = service(url, start_tls);
+            assert_eq!(security, service.security);
+
+            let user = service.authenticate("alice:secret").await.unwrap().unwrap();
+            assert_eq!("alice", user.name);
+
+            assert!(service.authenticate("alice:wrong").await.unwrap().is_none());
+            assert!(service.authenticate("bob:secret").await.unwrap().is_none());
+            assert!(service.authenticate("alice:").await.unwrap().is_none());
+            assert!(service.authenticate("secret").await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificate() {
+        let (url, _) = start_fake_server(Security::Tls).await;
+        let service = new(
+            &settings::Ldap {
+                url,
+                start_tls: false,
+                bind_dn: "uid={user},ou=people,dc=example,dc=org".to_owned(),
+            },
+            db::memory_pool(),
+        )
+        .unwrap();
+
+        assert!(service.authenticate("alice:secret").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn user_name_case() {
+        let (url, _) = start_fake_server(Security::None).await;
+        let service = service(url, false);
+
+        let lower = service.authenticate("alice:secret").await.unwrap().unwrap();
+        let upper = service.authenticate("Alice:secret").await.unwrap().unwrap();
+        assert_eq!(lower.id, upper.id);
+        assert_eq!("alice", upper.name);
+    }
+
+    #[tokio::test]
+    async fn cache() {
+        let (url, binds) = start_fake_server(Security::None).await;
+        let service = service(url, false);
+
+        assert!(service
+            .authenticate("alice:secret")
+            .await
+            .unwrap()
+            .is_some());
+        assert!(service
+            .authenticate("alice:secret")
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(1, binds.load(Ordering::SeqCst));
+
+        // Other passwords are still checked with the server.
+        assert!(service.authenticate("alice:wrong").await.unwrap().is_none());
+        assert_eq!(2, binds.load(Ordering::SeqCst));
+
+        // Expired entries are not used anymore.
+        for (_, expires) in service.cache.lock().values_mut() {
+            *expires = Instant::now();
+        }
+        assert!(service
+            .authenticate("alice:secret")
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(3, binds.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn settings() {
+        let settings = |url: &str, start_tls| settings::Ldap {
+            url: url.parse().unwrap(),
+            start_tls,
+            bind_dn: "uid={user}".to_owned(),
+        };
+
+        let service = new(&settings("ldaps://localhost", false), db::memory_pool()).unwrap();
+        assert_eq!("localhost:636", service.address);
+        assert!(new(&settings("ldaps://localhost", true), db::memory_pool()).is_err());
+        assert!(new(&settings("http://localhost", false), db::memory_pool()).is_err());
+    }
+
+    #[test]
+    fn ber_length() {
+        let long = "a".repeat(300);
+        let encoded = tlv(tag::OCTET_STRING, long.as_bytes());
+        assert_eq!([0x04, 0x82, 0x01, 0x2c], encoded[..4]);
+
+        let (tag, content, rest) = read_tlv(&encoded).unwrap();
+        assert_eq!(tag::OCTET_STRING, tag);
+        assert_eq!(long.as_bytes(), content);
+        assert!(rest.is_empty());
+
+        assert!(read_tlv(&encoded[..100]).is_err());
+    }
+
+    #[test]
+    fn escape() {
+        assert_eq!("alice", escape_dn_value("alice"));
+        assert_eq!("a\\,b\\=c\\+d\\\\e", escape_dn_value("a,b=c+d\\e"));
+        assert_eq!("\\#a \\ ", escape_dn_value("#a  "));
+    }
+}
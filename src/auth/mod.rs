@@ -0,0 +1,143 @@
+//! Authentication of API requests. The token that cargo sends with each request is checked against
+//! the backend that is selected in the settings.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use ring::constant_time;
+use sha2::{Digest, Sha256};
+use tracing::instrument;
+
+use crate::{
+    db::{self, users::User, DbConnPool},
+    settings,
+};
+
+mod ldap;
+
+/// The authentication service that maps API tokens to the users they belong to.
+#[async_trait]
+pub trait Service: Send + Sync + 'static {
+    /// Check the token from the `Authorization` header and return its user, if the token is valid.
+    async fn authenticate(&self, token: &str) -> Result<Option<User>>;
+}
+
+#[async_trait]
+impl Service for Box<dyn Service> {
+    async fn authenticate(&self, token: &str) -> Result<Option<User>> {
+        (**self).authenticate(token).await
+    }
+}
+
+/// Main implementation of the authentication [`Service`], using the API tokens from the database.
+struct ServiceImpl {
+    pool: DbConnPool,
+}
+
+#[async_trait]
+impl Service for ServiceImpl {
+    #[instrument(skip_all)]
+    async fn authenticate(&self, token: &str) -> Result<Option<User>> {
+        let token = token.to_owned();
+        self.pool
+            .run(move |conn| db::tokens::authenticate(conn, &token))
+            .await
+    }
+}
+
+/// Authentication [`Service`] with a fixed list of tokens from the settings. Users are created on
+/// first use.
+struct StaticImpl {
+    /// SHA-256 hashes of the tokens with their user name, so comparisons take constant time.
+    tokens: Vec<([u8; 32], String)>,
+    pool: DbConnPool,
+}
+
+#[async_trait]
+impl Service for StaticImpl {
+    #[instrument(skip_all)]
+    async fn authenticate(&self, token: &str) -> Result<Option<User>> {
+        let hash = Sha256::digest(token);
+        let Some((_, user)) = self
+            .tokens
+            .iter()
+            .find(|(expected, _)| constant_time::verify_slices_are_equal(expected, &hash).is_ok())
+        else {
+            return Ok(None);
+        };
+
+        let user = user.clone();
+        self.pool
+            .run(move |conn| db::users::get_or_create(conn, &user).map(Some))
+            .await
+    }
+}
+
+/// Create a new authentication service, using the backend that is selected in the settings.
+pub fn new(settings: Option<&settings::Auth>, pool: DbConnPool) -> Result<Box<dyn Service>> {
+    Ok(match settings {
+        None => Box::new(ServiceImpl { pool }),
+        Some(settings::Auth::Static { tokens }) => Box::new(StaticImpl {
+            tokens: tokens
+                .iter()
+                .map(|t| (Sha256::digest(&t.token).into(), t.user.clone()))
+                .collect(),
+            pool,
+        }),
+        Some(settings::Auth::Ldap { ldap }) => Box::new(ldap::new(ldap, pool)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn database_tokens() {
+        let pool = db::memory_pool();
+        let token = pool
+            .run(|conn| {
+                let user = db::users::get_or_create(conn, "alice")?;
+                db::tokens::create(conn, &user, "test")
+            })
+            .await
+            .unwrap();
+
+        let service = new(None, pool).unwrap();
+
+        let user = service.authenticate(&token).await.unwrap().unwrap();
+        assert_eq!("alice", user.name);
+        assert!(service.authenticate("wrong").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn static_tokens() {
+        let pool = db::memory_pool();
+        let service = new(
+            Some(&settings::Auth::Static {
+                tokens: vec![
+                    settings::StaticToken {
+                        user: "alice".to_owned(),
+                        token: "secret-a".to_owned(),
+                    },
+                    settings::StaticToken {
+                        user: "bob".to_owned(),
+                        token: "secret-b".to_owned(),
+                    },
+                ],
+            }),
+            pool.clone(),
+        )
+        .unwrap();
+
+        let user = service.authenticate("secret-b").await.unwrap().unwrap();
+        assert_eq!("bob", user.name);
+        assert!(service.authenticate("secret").await.unwrap().is_none());
+
+        // Tokens from the database are not accepted.
+        let token = pool
+            .run(move |conn| db::tokens::create(conn, &user, "test"))
+            .await
+            .unwrap();
+        assert!(service.authenticate(&token).await.unwrap().is_none());
+    }
+}
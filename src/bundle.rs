@@ -0,0 +1,680 @@
+//! Offline mirror bundles, to move crates into registries without network access. A bundle is a tar
+//! archive with the index entries of the selected releases and all their dependencies, followed by
+//! the `.crate` files of those releases:
+//!
+//! - `index/<crate path>`: one file per crate, in the same format as the index.
+//! - `crates/<name>/<name>-<version>.crate`: the crate file of each release.
+//!
+//! When importing a bundle, each crate file is checked against the checksum of its index entry
+//! before it is stored and its release is added to the index and the database.
+
+use std::{
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap},
+    io::{Cursor, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use semver::{Version, VersionReq};
+use sha2::{Digest, Sha256};
+use tar::{Archive, Builder, EntryType, Header};
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    db::{self, DbConnPool},
+    index::{
+        self,
+        models::{Kind, Release},
+    },
+    models::{AlreadyExists, CrateName},
+    storage,
+};
+
+/// A crate that is selected for export, with the requirement for the version to pick.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Selection {
+    pub name: CrateName,
+    pub req: VersionReq,
+}
+
+impl FromStr for Selection {
+    type Err = anyhow::Error;
+
+    /// Parse a selection in the form `<name>` or `<name>@<version requirement>`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, req) = match s.split_once('@') {
+            Some((name, req)) => (name, req.parse()?),
+            None => (s, VersionReq::STAR),
+        };
+
+        Ok(Self {
+            name: name.parse()?,
+            req,
+        })
+    }
+}
+
+/// Releases selected for export, ordered by crate name and version.
+type Releases = BTreeMap<(CrateName, Version), Release>;
+
+/// Result of an export.
+pub struct Exported {
+    /// Amount of releases in the bundle.
+    pub releases: usize,
+    /// Dependencies from other registries, which are not part of the bundle.
+    pub external: BTreeSet<String>,
+}
+
+/// Result of an import.
+#[derive(Debug)]
+pub struct Imported {
+    /// Amount of releases that were added.
+    pub added: usize,
+    /// Amount of releases that already existed with the same checksum.
+    pub skipped: usize,
+}
+
+/// Write a bundle with every release matching each selection, plus every matching release for each
+/// requirement in their dependency tree. Including all of them, instead of only the newest ones,
+/// lets existing lock files resolve against the target registry. Yanked releases and
+/// dev-dependencies are skipped, as they are not needed for building dependents.
+pub async fn export(
+    index: &impl index::Service,
+    storage: &impl storage::Service,
+    selection: &[Selection],
+    out: impl Write,
+) -> Result<Exported> {
+    let (releases, external) = resolve(index, selection)?;
+    let mut builder = Builder::new(out);
+
+    let mut by_crate = BTreeMap::<_, Vec<_>>::new();
+    for release in releases.values() {
+        by_crate.entry(&release.name).or_default().push(release);
+    }
+
+    for (name, releases) in by_crate {
+        let mut content = Vec::new();
+        for release in releases {
+            serde_json::to_writer(&mut content, release)?;
+            content.push(b'\n');
+        }
+
+        append(
+            &mut builder,
+            Path::new("index").join(index::crate_path(name)),
+            &content,
+        )?;
+    }
+
+    for release in releases.values() {
+        let mut content = Vec::new();
+        storage
+            .get(&release.name, &release.vers)
+            .await?
+            .with_context(|| {
+                format!(
+                    "crate file of `{}@{}` is missing in the storage",
+                    release.name, release.vers
+                )
+            })?
+            .read_to_end(&mut content)
+            .await?;
+
+        append(
+            &mut builder,
+            crate_file(&release.name, &release.vers),
+            &content,
+        )?;
+    }
+
+    builder.into_inner()?.flush()?;
+
+    Ok(Exported {
+        releases: releases.len(),
+        external,
+    })
+}
+
+/// Find the releases to export, by walking the dependency tree of the selected crates. Returns the
+/// releases and the dependencies from other registries.
+fn resolve(
+    index: &impl index::Service,
+    selection: &[Selection],
+) -> Result<(Releases, BTreeSet<String>)> {
+    let mut known = HashMap::<CrateName, Vec<Release>>::new();
+    let mut selected = BTreeMap::new();
+    let mut external = BTreeSet::new();
+
+    let mut queue = selection
+        .iter()
+        .map(|s| (s.name.clone(), s.req.clone(), None))
+        .collect::<Vec<_>>();
+
+    while let Some((name, req, parent)) = queue.pop() {
+        let releases = match known.entry(name.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(read_releases(index, &name)?),
+        };
+
+        let matching = releases
+            .iter()
+            .filter(|r| !r.yanked && req.matches(&r.vers))
+            .collect::<Vec<_>>();
+
+        if matching.is_empty() {
+            match &parent {
+                Some(parent) => {
+                    bail!("no release of `{name}` matches `{req}`, required by {parent}")
+                }
+                None => bail!("no release of `{name}` matches `{req}`"),
+            }
+        }
+
+        for release in matching {
+            if selected.contains_key(&(name.clone(), release.vers.clone())) {
+                continue;
+            }
+
+            for dep in &release.deps {
+                if matches!(dep.kind, Kind::Dev) {
+                    continue;
+                }
+
+                let dep_name = dep.package.as_deref().unwrap_or(&dep.name);
+                if dep.registry.is_some() {
+                    external.insert(format!("{dep_name} {}", dep.req));
+                    continue;
+                }
+
+                queue.push((
+                    dep_name.parse()?,
+                    dep.req.clone(),
+                    Some(format!("`{}@{}`", release.name, release.vers)),
+                ));
+            }
+
+            selected.insert((name.clone(), release.vers.clone()), release.clone());
+        }
+    }
+
+    Ok((selected, external))
+}
+
+/// Load all releases of a crate from the index.
+fn read_releases(index: &impl index::Service, name: &CrateName) -> Result<Vec<Release>> {
+    let Some(file) = index.read_crate(name)? else {
+        return Ok(Vec::new());
+    };
+
+    file.content
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice(line).map_err(Into::into))
+        .collect()
+}
+
+/// Add a single file to the bundle.
+fn append(builder: &mut Builder<impl Write>, path: PathBuf, content: &[u8]) -> Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+
+    builder
+        .append_data(&mut header, path, content)
+        .map_err(Into::into)
+}
+
+/// Location of a release's crate file within the bundle.
+fn crate_file(name: &CrateName, version: &Version) -> PathBuf {
+    Path::new("crates")
+        .join(name.as_ref())
+        .join(format!("{name}-{version}.crate"))
+}
+
+/// Import all releases from a bundle. Releases that already exist with the same checksum are
+/// skipped, while any other existing version fails the import. Crate files are verified against
+/// the checksum of their index entry before anything is stored.
+pub async fn import(
+    index: &impl index::Service,
+    storage: &impl storage::Service,
+    pool: &DbConnPool,
+    input: impl Read,
+) -> Result<Imported> {
+    let mut archive = Archive::new(input);
+    let mut releases = HashMap::new();
+    let mut imported = Imported {
+        added: 0,
+        skipped: 0,
+    };
+
+    for entry in archive.entries().context("failed reading bundle")? {
+        let mut entry = entry.context("failed reading bundle entry")?;
+        if entry.header().entry_type() == EntryType::Directory {
+            continue;
+        }
+
+        let path = entry.path()?.into_owned();
+
+        if path.starts_with("index") {
+            let mut content = String::new();
+            entry.read_to_string(&mut content)?;
+
+            for line in content.lines().filter(|line| !line.is_empty()) {
+                let release = serde_json::from_str::<Release>(line)
+                    .with_context(|| format!("invalid index entry in `{}`", path.display()))?;
+                releases.insert(crate_file(&release.name, &release.vers), release);
+            }
+        } else if path.starts_with("crates") {
+            let release = releases
+                .remove(&path)
+                .with_context(|| format!("`{}` has no index entry", path.display()))?;
+
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+
+            ensure!(
+                hex::encode(Sha256::digest(&content)) == release.cksum,
+                "checksum of `{}@{}` doesn't match its index entry",
+                release.name,
+                release.vers
+            );
+
+            let crate_size = content.len() as u64;
+            let added = import_release(index, storage, release.clone(), content).await?;
+
+            // Also done for skipped releases, in case an earlier import failed in between.
+            pool.run(move |conn| db::crates::import(conn, &release, crate_size))
+                .await?;
+
+            if added {
+                imported.added += 1;
+            } else {
+                imported.skipped += 1;
+            }
+        } else {
+            bail!("unexpected entry `{}` in bundle", path.display());
+        }
+    }
+
+    if let Some(release) = releases.values().next() {
+        bail!(
+            "bundle is missing the crate file of `{}@{}`",
+            release.name,
+            release.vers
+        );
+    }
+
+    Ok(imported)
+}
+
+/// Store the verified crate file and add the release to the index. Returns `false` if the release
+/// already exists with the same checksum.
+async fn import_release(
+    index: &impl index::Service,
+    storage: &impl storage::Service,
+    release: Release,
+    content: Vec<u8>,
+) -> Result<bool> {
+    if let Some(existing) = index.read_release(&release.name, &release.vers)? {
+        ensure!(
+            existing.cksum == release.cksum,
+            "`{}@{}` already exists with a different checksum",
+            release.name,
+            release.vers
+        );
+        return Ok(false);
+    }
+
+    let len = content.len() as u64;
+    match storage
+        .store(
+            &release.name,
+            &release.vers,
+            len,
+            Box::pin(Cursor::new(content)),
+        )
+        .await
+    {
+        Ok(()) => {}
+        // Left behind by an earlier import that failed before updating the index.
+        Err(e) if e.is::<AlreadyExists>() => {
+            let mut stored = Vec::new();
+            if let Some(mut file) = storage.get(&release.name, &release.vers).await? {
+                file.read_to_end(&mut stored).await?;
+            }
+
+            ensure!(
+                hex::encode(Sha256::digest(&stored)) == release.cksum,
+                "`{}@{}` already exists in the storage with different content",
+                release.name,
+                release.vers
+            );
+        }
+        Err(e) => return Err(e),
+    }
+
+    index.add_release(release)?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        api::models::{Dependency as ApiDependency, Kind as ApiKind},
+        index::{
+            models::{Config, Dependency},
+            Service as _,
+        },
+        settings,
+        storage::Service as _,
+    };
+
+    fn run_git(dir: &Path, args: &[&str]) {
+        assert!(Command::new("git")
+            .current_dir(dir)
+            .args(args)
+            .status()
+            .unwrap()
+            .success());
+    }
+
+    /// An index and storage in a temporary directory.
+    fn registry() -> (TempDir, impl index::Service, Box<dyn storage::Service>) {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("index");
+        std::fs::create_dir(&location).unwrap();
+
+        run_git(&location, &["init", "-q"]);
+        run_git(&location, &["config", "user.email", "test@test.com"]);
+        run_git(&location, &["config", "user.name", "Test"]);
+        run_git(
+            &location,
+            &["commit", "-q", "--allow-empty", "-m", "Initial commit"],
+        );
+
+        let index = index::new(&settings::Index {
+            location,
+            config: Config {
+                dl: "http://localhost:8080/api/v1/crates".parse().unwrap(),
+                api: "http://localhost:8080".parse().unwrap(),
+            },
+        })
+        .unwrap();
+        let storage = storage::new(&settings::Storage::Filesystem {
+            location: dir.path().join("crates"),
+        })
+        .unwrap();
+
+        (dir, index, storage)
+    }
+
+    fn dependency(name: &str, req: &str, kind: Kind) -> Dependency {
+        Dependency {
+            name: name.to_owned(),
+            req: req.parse().unwrap(),
+            features: BTreeSet::new(),
+            optional: false,
+            default_features: true,
+            target: None,
+            kind,
+            registry: None,
+            package: None,
+        }
+    }
+
+    /// Add a release to the index and its content to the storage.
+    async fn publish(
+        index: &impl index::Service,
+        storage: &impl storage::Service,
+        name: &str,
+        version: &str,
+        deps: Vec<Dependency>,
+    ) {
+        let content = format!("{name}-{version}").into_bytes();
+        let release = Release {
+            name: name.parse().unwrap(),
+            vers: version.parse().unwrap(),
+            deps,
+            cksum: hex::encode(Sha256::digest(&content)),
+            features: BTreeMap::new(),
+            features2: None,
+            yanked: false,
+            links: None,
+            v: None,
+            rust_version: None,
+        };
+
+        storage
+            .store(
+                &release.name,
+                &release.vers,
+                content.len() as u64,
+                Box::pin(Cursor::new(content)),
+            )
+            .await
+            .unwrap();
+        index.add_release(release).unwrap();
+    }
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let (_source_dir, source_index, source_storage) = registry();
+        let mut external = dependency("serde", "^1", Kind::Normal);
+        external.registry = Some(
+            "https://github.com/rust-lang/crates.io-index"
+                .parse()
+                .unwrap(),
+        );
+
+        for (name, version, deps) in [
+            (
+                "app",
+                "1.0.0",
+                vec![
+                    dependency("lib", "^1", Kind::Normal),
+                    dependency("test-util", "^1", Kind::Dev),
+                    external,
+                ],
+            ),
+            ("lib", "1.0.0", vec![]),
+            ("lib", "1.1.0", vec![dependency("gen", "^0.1", Kind::Build)]),
+            ("lib", "2.0.0", vec![]),
+            ("gen", "0.1.0", vec![]),
+            ("test-util", "1.0.0", vec![]),
+        ] {
+            publish(&source_index, &source_storage, name, version, deps).await;
+        }
+
+        let mut bundle = Vec::new();
+        let exported = export(
+            &source_index,
+            &source_storage,
+            &["app".parse().unwrap()],
+            &mut bundle,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(4, exported.releases);
+        assert_eq!(BTreeSet::from(["serde ^1".to_owned()]), exported.external);
+
+        let (_target_dir, target_index, target_storage) = registry();
+        let pool = db::memory_pool();
+        let imported = import(&target_index, &target_storage, &pool, bundle.as_slice())
+            .await
+            .unwrap();
+        assert_eq!(4, imported.added);
+
+        for (name, version) in [
+            ("app", "1.0.0"),
+            ("lib", "1.0.0"),
+            ("lib", "1.1.0"),
+            ("gen", "0.1.0"),
+        ] {
+            let (name, version) = (name.parse().unwrap(), version.parse().unwrap());
+            let release = target_index.read_release(&name, &version).unwrap().unwrap();
+            let mut content = Vec::new();
+            target_storage
+                .get(&name, &version)
+                .await
+                .unwrap()
+                .unwrap()
+                .read_to_end(&mut content)
+                .await
+                .unwrap();
+            assert_eq!(release.cksum, hex::encode(Sha256::digest(&content)));
+        }
+
+        for (name, version) in [("lib", "2.0.0"), ("test-util", "1.0.0")] {
+            assert!(target_index
+                .read_release(&name.parse().unwrap(), &version.parse().unwrap())
+                .unwrap()
+                .is_none());
+        }
+
+        // Imported crates are known to the database, but have no owners.
+        let conn = pool.get().unwrap();
+        let lib = "lib".parse().unwrap();
+        let details = db::crates::find(&conn, &lib).unwrap().unwrap();
+        assert_eq!(Version::new(1, 1, 0), details.max_version);
+
+        let versions = db::versions::list(&conn, &lib).unwrap();
+        assert_eq!(2, versions.len());
+        let deps = db::versions::dependencies(&conn, versions[0].id).unwrap();
+        assert_eq!("gen", deps[0].crate_name);
+
+        assert_eq!(
+            Some(0),
+            db::owners::list(&conn, &lib).unwrap().map(|o| o.len())
+        );
+        drop(conn);
+
+        // Importing the same bundle again changes nothing.
+        let imported = import(&target_index, &target_storage, &pool, bundle.as_slice())
+            .await
+            .unwrap();
+        assert_eq!((0, 4), (imported.added, imported.skipped));
+        assert_eq!(
+            2,
+            db::versions::list(&pool.get().unwrap(), &lib)
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[tokio::test]
+    async fn renamed_dependency() {
+        let (_source_dir, source_index, source_storage) = registry();
+
+        // Published with `util = { package = "lib", version = "1" }` in the manifest.
+        let dep = Dependency::from(ApiDependency {
+            name: "lib".to_owned(),
+            version_req: "^1".parse().unwrap(),
+            features: BTreeSet::new(),
+            optional: false,
+            default_features: true,
+            target: None,
+            kind: ApiKind::Normal,
+            registry: None,
+            explicit_name_in_toml: Some("util".to_owned()),
+        });
+        assert_eq!(
+            ("util", Some("lib")),
+            (dep.name.as_str(), dep.package.as_deref())
+        );
+
+        publish(&source_index, &source_storage, "app", "1.0.0", vec![dep]).await;
+        publish(&source_index, &source_storage, "lib", "1.0.0", vec![]).await;
+
+        let mut bundle = Vec::new();
+        let exported = export(
+            &source_index,
+            &source_storage,
+            &["app".parse().unwrap()],
+            &mut bundle,
+        )
+        .await
+        .unwrap();
+        assert_eq!(2, exported.releases);
+
+        let (_target_dir, target_index, target_storage) = registry();
+        let pool = db::memory_pool();
+        let imported = import(&target_index, &target_storage, &pool, bundle.as_slice())
+            .await
+            .unwrap();
+        assert_eq!(2, imported.added);
+
+        let conn = pool.get().unwrap();
+        let versions = db::versions::list(&conn, &"app".parse().unwrap()).unwrap();
+        let deps = db::versions::dependencies(&conn, versions[0].id).unwrap();
+        assert_eq!("lib", deps[0].crate_name);
+    }
+
+    #[tokio::test]
+    async fn verify_checksum() {
+        let (_source_dir, source_index, source_storage) = registry();
+        publish(&source_index, &source_storage, "lib", "1.0.0", vec![]).await;
+
+        let mut bundle = Vec::new();
+        export(
+            &source_index,
+            &source_storage,
+            &["lib@1".parse().unwrap()],
+            &mut bundle,
+        )
+        .await
+        .unwrap();
+
+        // Flip a byte of the crate file content, which is the last entry in the archive.
+        let content = b"lib-1.0.0";
+        let pos = bundle
+            .windows(content.len())
+            .rposition(|w| w == content)
+            .unwrap();
+        bundle[pos] = b'L';
+
+        let (_target_dir, target_index, target_storage) = registry();
+        let err = import(
+            &target_index,
+            &target_storage,
+            &db::memory_pool(),
+            bundle.as_slice(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("checksum"));
+
+        let (name, version) = ("lib".parse().unwrap(), "1.0.0".parse().unwrap());
+        assert!(target_index
+            .read_release(&name, &version)
+            .unwrap()
+            .is_none());
+        assert!(target_storage.get(&name, &version).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_selection() {
+        assert_eq!(
+            Selection {
+                name: "serde".parse().unwrap(),
+                req: VersionReq::STAR,
+            },
+            "serde".parse().unwrap()
+        );
+        assert_eq!(
+            Selection {
+                name: "serde".parse().unwrap(),
+                req: "=1.0.100".parse().unwrap(),
+            },
+            "serde@=1.0.100".parse().unwrap()
+        );
+        assert!("serde@latest".parse::<Selection>().is_err());
+    }
+}
@@ -0,0 +1,25 @@
+//! Small cryptographic building blocks on top of HMAC-SHA256, used for request signing.
+
+use ring::hmac;
+
+/// HMAC-SHA256 as defined in [RFC 2104](https://www.rfc-editor.org/rfc/rfc2104).
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    let mut tag = [0; 32];
+    tag.copy_from_slice(hmac::sign(&key, data).as_ref());
+    tag
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_sha256() {
+        // Test case 2 of RFC 4231.
+        assert_eq!(
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?"))
+        );
+    }
+}
@@ -4,6 +4,7 @@ use anyhow::{Context, Result};
 use r2d2::{ManageConnection, Pool, PooledConnection};
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::Connection;
+use tokio::task;
 
 fn init_connection(conn: &mut Connection) -> Result<(), rusqlite::Error> {
     conn.pragma_update(None, "busy_timeout", 1000)?;
@@ -15,12 +16,24 @@ fn init_connection(conn: &mut Connection) -> Result<(), rusqlite::Error> {
     Ok(())
 }
 
+#[derive(Clone)]
 pub struct DbConnPool(Pool<SqliteConnectionManager>);
 
 impl DbConnPool {
     pub fn get(&self) -> Result<DbConn> {
         self.0.get().map(DbConn).map_err(Into::into)
     }
+
+    /// Run the given closure with a connection from the pool, on the blocking thread pool so it
+    /// doesn't stall the async runtime.
+    pub async fn run<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.clone();
+        task::spawn_blocking(move || f(&mut *pool.get()?)).await?
+    }
 }
 
 pub struct DbConn(PooledConnection<SqliteConnectionManager>);
@@ -64,3 +77,16 @@ pub fn create_pool() -> Result<DbConnPool> {
 
     Ok(DbConnPool(pool))
 }
+
+/// Create a pool around a single in-memory database with all migrations applied. The pool is
+/// limited to one connection, as each connection would otherwise open its own database.
+#[cfg(test)]
+pub fn memory_pool() -> DbConnPool {
+    let manager = SqliteConnectionManager::memory()
+        .with_init(|conn| conn.pragma_update(None, "foreign_keys", "ON"));
+    let pool = Pool::builder().max_size(1).build(manager).unwrap();
+
+    super::run_migrations(&mut pool.get().unwrap()).unwrap();
+
+    DbConnPool(pool)
+}
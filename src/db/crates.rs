@@ -0,0 +1,419 @@
+//! Searchable crate metadata, recorded on every publish.
+
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+use semver::Version;
+
+use super::users::User;
+use crate::{api::models::PublishRequest, index::models::Release, models::CrateName};
+
+/// Full metadata of a single crate, taken from its highest version.
+#[derive(Debug)]
+pub struct CrateDetails {
+    pub name: CrateName,
+    pub max_version: Version,
+    pub description: Option<String>,
+    pub homepage: Option<String>,
+    pub documentation: Option<String>,
+    pub repository: Option<String>,
+    pub keywords: BTreeSet<String>,
+    pub categories: BTreeSet<String>,
+    /// Creation time as seconds since the Unix epoch.
+    pub created_at: i64,
+    /// Last update as seconds since the Unix epoch.
+    pub updated_at: i64,
+}
+
+/// Summary of a single crate as shown in search results.
+#[derive(Debug)]
+pub struct CrateInfo {
+    pub name: CrateName,
+    pub max_version: Version,
+    pub description: Option<String>,
+}
+
+/// Record a newly published version of a crate. The crate wide metadata like description, project
+/// links, keywords and categories is only replaced if the version is the new highest version.
+pub fn upsert(
+    conn: &mut Connection,
+    req: &PublishRequest,
+    metadata: &str,
+    checksum: &str,
+    crate_size: u64,
+    publisher: &User,
+) -> Result<()> {
+    let tx = conn.transaction()?;
+
+    let existing = tx
+        .query_row(
+            "SELECT id, max_version FROM crates WHERE name = ?1",
+            [req.name.as_ref()],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+        )
+        .optional()?;
+
+    let (id, is_max) = match existing {
+        // Crates claimed for their first publish already carry the version being published.
+        Some((id, max_version)) => (id, max_version.parse::<Version>()? <= req.vers),
+        None => {
+            tx.execute(
+                "INSERT INTO crates (name, max_version) VALUES (?1, ?2)",
+                params![req.name.as_ref(), req.vers.to_string()],
+            )?;
+
+            (tx.last_insert_rowid(), true)
+        }
+    };
+
+    if is_max {
+        tx.execute(
+            "UPDATE crates
+            SET max_version = ?2, description = ?3, homepage = ?4, documentation = ?5,
+                repository = ?6, updated_at = strftime('%s', 'now')
+            WHERE id = ?1",
+            params![
+                id,
+                req.vers.to_string(),
+                req.description,
+                req.homepage.as_ref().map(ToString::to_string),
+                req.documentation.as_ref().map(ToString::to_string),
+                req.repository.as_ref().map(ToString::to_string),
+            ],
+        )?;
+        tx.execute("DELETE FROM crate_keywords WHERE crate_id = ?1", [id])?;
+        tx.execute("DELETE FROM crate_categories WHERE crate_id = ?1", [id])?;
+
+        let mut stmt =
+            tx.prepare("INSERT INTO crate_keywords (crate_id, keyword) VALUES (?1, ?2)")?;
+        for keyword in &req.keywords {
+            stmt.execute(params![id, keyword.to_lowercase()])?;
+        }
+
+        let mut stmt =
+            tx.prepare("INSERT INTO crate_categories (crate_id, category) VALUES (?1, ?2)")?;
+        for category in &req.categories {
+            stmt.execute(params![id, category])?;
+        }
+    }
+
+    super::versions::insert(&tx, id, req, metadata, checksum, crate_size, publisher)?;
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Record crates that exist in the index but not in the database yet, like the ones published
+/// before the database was introduced, so they can be found by search. Only the name and highest
+/// version are known for them. Returns the amount of newly recorded crates.
+pub fn backfill(
+    conn: &mut Connection,
+    crates: impl IntoIterator<Item = (CrateName, Version)>,
+) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let mut added = 0;
+
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO crates (name, max_version) VALUES (?1, ?2)
+            ON CONFLICT (name) DO NOTHING",
+        )?;
+        for (name, max_version) in crates {
+            added += stmt.execute(params![name.as_ref(), max_version.to_string()])?;
+        }
+    }
+
+    tx.commit()?;
+
+    Ok(added)
+}
+
+/// Record a version that was imported from a bundle, so it shows up in search and the crate API.
+/// Crates that didn't exist before are recorded without owners, so nobody can claim them by
+/// publishing. Their metadata stays empty, as index entries don't carry it.
+pub fn import(conn: &mut Connection, release: &Release, crate_size: u64) -> Result<()> {
+    let tx = conn.transaction()?;
+
+    tx.execute(
+        "INSERT INTO crates (name, max_version) VALUES (?1, ?2)
+        ON CONFLICT (name) DO NOTHING",
+        params![release.name.as_ref(), release.vers.to_string()],
+    )?;
+
+    let (id, max_version) = tx.query_row(
+        "SELECT id, max_version FROM crates WHERE name = ?1",
+        [release.name.as_ref()],
+        |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+    )?;
+
+    if max_version.parse::<Version>()? < release.vers {
+        tx.execute(
+            "UPDATE crates SET max_version = ?2, updated_at = strftime('%s', 'now') WHERE id = ?1",
+            params![id, release.vers.to_string()],
+        )?;
+    }
+
+    super::versions::import(&tx, id, release, crate_size)?;
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Load the full metadata of a single crate.
+pub fn find(conn: &Connection, name: &CrateName) -> Result<Option<CrateDetails>> {
+    let details = conn
+        .query_row(
+            "SELECT id, name, max_version, description, homepage, documentation, repository,
+                created_at, updated_at
+            FROM crates WHERE name = ?1",
+            [name.as_ref()],
+            |row| {
+                Ok((
+                    row.get::<_, i64>("id")?,
+                    row.get::<_, String>("max_version")?,
+                    CrateDetails {
+                        name: name.clone(),
+                        max_version: Version::new(0, 0, 0),
+                        description: row.get("description")?,
+                        homepage: row.get("homepage")?,
+                        documentation: row.get("documentation")?,
+                        repository: row.get("repository")?,
+                        keywords: BTreeSet::new(),
+                        categories: BTreeSet::new(),
+                        created_at: row.get("created_at")?,
+                        updated_at: row.get("updated_at")?,
+                    },
+                ))
+            },
+        )
+        .optional()?;
+
+    let Some((id, max_version, mut details)) = details else {
+        return Ok(None);
+    };
+
+    details.max_version = max_version.parse()?;
+    details.keywords = conn
+        .prepare("SELECT keyword FROM crate_keywords WHERE crate_id = ?1")?
+        .query_map([id], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    details.categories = conn
+        .prepare("SELECT category FROM crate_categories WHERE crate_id = ?1")?
+        .query_map([id], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(Some(details))
+}
+
+/// Search for crates matching the query in their name, description or keywords. Results are
+/// ranked by exact name matches first, then name prefix, name substring, keyword and finally
+/// description matches. An empty query matches all crates. Returns at most `limit` crates, after
+/// skipping the first `offset` ones, together with the total count of matches.
+pub fn search(
+    conn: &Connection,
+    query: &str,
+    offset: u32,
+    limit: u32,
+) -> Result<(Vec<CrateInfo>, u64)> {
+    let query = query.trim().to_lowercase().replace('_', "-");
+    let pattern = escape_like(&query);
+
+    let total = conn.query_row(
+        "SELECT COUNT(*) FROM crates c WHERE
+            replace(c.name, '_', '-') LIKE '%' || ?1 || '%' ESCAPE '\\'
+            OR lower(c.description) LIKE '%' || ?1 || '%' ESCAPE '\\'
+            OR EXISTS (
+                SELECT 1 FROM crate_keywords k
+                WHERE k.crate_id = c.id AND k.keyword LIKE '%' || ?1 || '%' ESCAPE '\\'
+            )",
+        [&pattern],
+        |row| row.get(0),
+    )?;
+
+    let mut stmt = conn.prepare(
+        "SELECT name, max_version, description FROM (
+            SELECT c.name, c.max_version, c.description, CASE
+                WHEN replace(c.name, '_', '-') = ?1 THEN 0
+                WHEN replace(c.name, '_', '-') LIKE ?2 || '%' ESCAPE '\\' THEN 1
+                WHEN replace(c.name, '_', '-') LIKE '%' || ?2 || '%' ESCAPE '\\' THEN 2
+                WHEN EXISTS (
+                    SELECT 1 FROM crate_keywords k
+                    WHERE k.crate_id = c.id AND k.keyword LIKE '%' || ?2 || '%' ESCAPE '\\'
+                ) THEN 3
+                WHEN lower(c.description) LIKE '%' || ?2 || '%' ESCAPE '\\' THEN 4
+                ELSE NULL
+            END AS rank
+            FROM crates c
+        )
+        WHERE rank IS NOT NULL
+        ORDER BY rank, name
+        LIMIT ?3 OFFSET ?4",
+    )?;
+
+    let crates = stmt
+        .query_map(params![query, pattern, limit, offset], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })?
+        .map(|row| {
+            let (name, max_version, description) = row?;
+            Ok(CrateInfo {
+                name: name.parse()?,
+                max_version: max_version.parse()?,
+                description,
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    Ok((crates, total))
+}
+
+/// List all crates that the user owns, sorted by name.
+pub fn owned_by(conn: &Connection, user: &User) -> Result<Vec<CrateInfo>> {
+    list_by(
+        conn,
+        "SELECT c.name, c.max_version, c.description FROM crates c
+        JOIN crate_owners o ON o.crate_id = c.id
+        WHERE o.user_id = ?1
+        ORDER BY c.name",
+        user,
+    )
+}
+
+/// List all crates that the user published at least one version of, sorted by name.
+pub fn published_by(conn: &Connection, user: &User) -> Result<Vec<CrateInfo>> {
+    list_by(
+        conn,
+        "SELECT c.name, c.max_version, c.description FROM crates c
+        WHERE EXISTS (SELECT 1 FROM versions v WHERE v.crate_id = c.id AND v.published_by = ?1)
+        ORDER BY c.name",
+        user,
+    )
+}
+
+fn list_by(conn: &Connection, sql: &str, user: &User) -> Result<Vec<CrateInfo>> {
+    conn.prepare(sql)?
+        .query_map([user.id], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })?
+        .map(|row| {
+            let (name, max_version, description) = row?;
+            Ok(CrateInfo {
+                name: name.parse()?,
+                max_version: max_version.parse()?,
+                description,
+            })
+        })
+        .collect()
+}
+
+/// Escape all special characters of a `LIKE` pattern so the value is matched literally.
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use maplit::btreeset;
+
+    use super::*;
+    use crate::db::users;
+
+    fn publish(conn: &mut Connection, name: &str, version: &str, description: &str) {
+        let user = users::get_or_create(conn, "test").unwrap();
+        let mut req = PublishRequest::new(name.parse().unwrap(), version.parse().unwrap());
+        req.description = Some(description.to_owned());
+        req.keywords = btreeset!["random".to_owned()];
+
+        upsert(conn, &req, "{}", "", 0, &user).unwrap();
+    }
+
+    #[test]
+    fn upsert_keeps_max_version() {
+        let mut conn = crate::db::memory();
+
+        publish(&mut conn, "rand", "0.8.0", "new");
+        publish(&mut conn, "rand", "0.7.3", "old");
+
+        let (crates, total) = search(&conn, "rand", 0, 10).unwrap();
+        assert_eq!(1, total);
+        assert_eq!(Version::new(0, 8, 0), crates[0].max_version);
+        assert_eq!(Some("new"), crates[0].description.as_deref());
+
+        let details = find(&conn, &"rand".parse().unwrap()).unwrap().unwrap();
+        assert_eq!(Version::new(0, 8, 0), details.max_version);
+        assert_eq!(btreeset!["random".to_owned()], details.keywords);
+
+        assert!(find(&conn, &"other".parse().unwrap()).unwrap().is_none());
+
+        let user = users::get_or_create(&conn, "test").unwrap();
+        let published = published_by(&conn, &user).unwrap();
+        assert_eq!(1, published.len());
+        assert!(owned_by(&conn, &user).unwrap().is_empty());
+    }
+
+    #[test]
+    fn backfill_missing() {
+        let mut conn = crate::db::memory();
+        publish(&mut conn, "rand", "0.8.0", "random numbers");
+
+        let added = backfill(
+            &mut conn,
+            [
+                ("rand".parse().unwrap(), Version::new(0, 7, 0)),
+                ("old".parse().unwrap(), Version::new(1, 2, 3)),
+            ],
+        )
+        .unwrap();
+        assert_eq!(1, added);
+
+        let (crates, total) = search(&conn, "old", 0, 10).unwrap();
+        assert_eq!(1, total);
+        assert_eq!(Version::new(1, 2, 3), crates[0].max_version);
+
+        let rand = find(&conn, &"rand".parse().unwrap()).unwrap().unwrap();
+        assert_eq!(Version::new(0, 8, 0), rand.max_version);
+    }
+
+    #[test]
+    fn search_ranking() {
+        let mut conn = crate::db::memory();
+
+        publish(&mut conn, "rand_core", "0.6.0", "Core traits");
+        publish(&mut conn, "fastrand", "1.0.0", "A simple generator");
+        publish(&mut conn, "rand", "0.8.0", "Random numbers");
+        publish(&mut conn, "uuid", "1.0.0", "Generate and parse UUIDs");
+
+        let (crates, total) = search(&conn, "rand", 0, 10).unwrap();
+        let names = crates.iter().map(|c| c.name.as_ref()).collect::<Vec<_>>();
+
+        assert_eq!(4, total);
+        assert_eq!(vec!["rand", "rand_core", "fastrand", "uuid"], names);
+
+        let (crates, total) = search(&conn, "rand", 2, 2).unwrap();
+        let names = crates.iter().map(|c| c.name.as_ref()).collect::<Vec<_>>();
+        assert_eq!(4, total);
+        assert_eq!(vec!["fastrand", "uuid"], names);
+
+        let (crates, total) = search(&conn, "", 0, 10).unwrap();
+        assert_eq!(4, total);
+        assert_eq!(4, crates.len());
+
+        let (crates, total) = search(&conn, "uuids", 0, 10).unwrap();
+        assert_eq!(1, total);
+        assert_eq!("uuid", crates[0].name.as_ref());
+    }
+}
@@ -1,12 +1,11 @@
 use anyhow::Result;
-
-use super::DbConn;
+use rusqlite::Connection;
 
 mod embedded {
     refinery::embed_migrations!("migrations");
 }
 
-pub fn run(mut conn: DbConn) -> Result<()> {
-    embedded::migrations::runner().run(&mut *conn)?;
+pub fn run(conn: &mut Connection) -> Result<()> {
+    embedded::migrations::runner().run(conn)?;
     Ok(())
 }
@@ -1,7 +1,25 @@
+#[cfg(test)]
+pub use self::connection::memory_pool;
 pub use self::{
-    connection::{create_pool, DbConn},
+    connection::{create_pool, DbConnPool},
     migrations::run as run_migrations,
 };
 
 mod connection;
+pub mod crates;
 mod migrations;
+pub mod owners;
+pub mod sessions;
+pub mod tokens;
+pub mod upstream;
+pub mod users;
+pub mod versions;
+
+/// Create a fresh in-memory database with all migrations applied.
+#[cfg(test)]
+pub fn memory() -> rusqlite::Connection {
+    let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+    conn.pragma_update(None, "foreign_keys", "ON").unwrap();
+    run_migrations(&mut conn).unwrap();
+    conn
+}
@@ -0,0 +1,258 @@
+//! Ownership of crates. Only owners of a crate are allowed to publish new versions, yank existing
+//! ones or manage the list of owners.
+
+use anyhow::{ensure, Context, Result};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use semver::Version;
+
+use super::users::User;
+use crate::models::CrateName;
+
+/// Outcome of [`claim`]ing a crate for publishing.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Claim {
+    /// The user is one of the owners of the existing crate.
+    Owner,
+    /// The crate didn't exist before and now belongs to the user.
+    New,
+    /// The user isn't allowed to publish the crate.
+    Denied,
+}
+
+/// Check whether the user may publish a new version of the crate. That is the case if the user is
+/// one of its owners or the crate doesn't exist yet, in which case it is recorded with the user as
+/// its owner right away. Both happens in a single transaction, so two users can't claim the same
+/// crate at once.
+///
+/// Crates that exist in the index without being recorded here (`in_index`) have no owners, so
+/// nobody may publish them. The same goes for crates that were mirrored from the upstream registry.
+pub fn claim(
+    conn: &mut Connection,
+    name: &CrateName,
+    version: &Version,
+    user: &User,
+    in_index: bool,
+) -> Result<Claim> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    let claim = match crate_id(&tx, name)? {
+        Some(id) if is_owner_by_id(&tx, id, user)? => Claim::Owner,
+        Some(_) => Claim::Denied,
+        None if in_index || super::upstream::is_mirrored(&tx, name.as_ref())? => Claim::Denied,
+        None => {
+            tx.execute(
+                "INSERT INTO crates (name, max_version) VALUES (?1, ?2)",
+                params![name.as_ref(), version.to_string()],
+            )?;
+            tx.execute(
+                "INSERT INTO crate_owners (crate_id, user_id) VALUES (?1, ?2)",
+                params![tx.last_insert_rowid(), user.id],
+            )?;
+
+            Claim::New
+        }
+    };
+
+    tx.commit()?;
+
+    Ok(claim)
+}
+
+/// Give up a crate that was [`Claim::New`]ly claimed, if it didn't get any versions, because the
+/// publish failed.
+pub fn unclaim(conn: &Connection, name: &CrateName) -> Result<()> {
+    conn.execute(
+        "DELETE FROM crates
+        WHERE name = ?1 AND NOT EXISTS (SELECT 1 FROM versions v WHERE v.crate_id = crates.id)",
+        [name.as_ref()],
+    )?;
+
+    Ok(())
+}
+
+/// Check whether the user is one of the owners of an existing crate.
+pub fn is_owner(conn: &Connection, name: &CrateName, user: &User) -> Result<bool> {
+    match crate_id(conn, name)? {
+        Some(id) => is_owner_by_id(conn, id, user),
+        None => Ok(false),
+    }
+}
+
+/// List all owners of the crate, if it exists.
+pub fn list(conn: &Connection, name: &CrateName) -> Result<Option<Vec<User>>> {
+    let Some(id) = crate_id(conn, name)? else {
+        return Ok(None);
+    };
+
+    let mut stmt = conn.prepare(
+        "SELECT u.id, u.name FROM crate_owners o
+        JOIN users u ON u.id = o.user_id
+        WHERE o.crate_id = ?1
+        ORDER BY u.name",
+    )?;
+
+    let users = stmt
+        .query_map([id], |row| {
+            Ok(User {
+                id: row.get(0)?,
+                name: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(Some(users))
+}
+
+/// Add the users as owners of the crate. Users that already are owners are skipped.
+pub fn add(conn: &mut Connection, name: &CrateName, users: &[User]) -> Result<()> {
+    let id = crate_id(conn, name)?.with_context(|| format!("crate `{name}` does not exist"))?;
+    let tx = conn.transaction()?;
+
+    {
+        let mut stmt =
+            tx.prepare("INSERT OR IGNORE INTO crate_owners (crate_id, user_id) VALUES (?1, ?2)")?;
+        for user in users {
+            stmt.execute(params![id, user.id])?;
+        }
+    }
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Remove the users from the owners of the crate. At least one owner must remain afterwards.
+pub fn remove(conn: &mut Connection, name: &CrateName, users: &[User]) -> Result<()> {
+    let id = crate_id(conn, name)?.with_context(|| format!("crate `{name}` does not exist"))?;
+    let tx = conn.transaction()?;
+
+    {
+        let mut stmt =
+            tx.prepare("DELETE FROM crate_owners WHERE crate_id = ?1 AND user_id = ?2")?;
+        for user in users {
+            stmt.execute(params![id, user.id])?;
+        }
+    }
+
+    let remaining = tx.query_row(
+        "SELECT COUNT(*) FROM crate_owners WHERE crate_id = ?1",
+        [id],
+        |row| row.get::<_, u64>(0),
+    )?;
+    ensure!(remaining > 0, "cannot remove all owners of a crate");
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+fn crate_id(conn: &Connection, name: &CrateName) -> Result<Option<i64>> {
+    conn.query_row(
+        "SELECT id FROM crates WHERE name = ?1",
+        [name.as_ref()],
+        |row| row.get(0),
+    )
+    .optional()
+    .map_err(Into::into)
+}
+
+fn is_owner_by_id(conn: &Connection, crate_id: i64, user: &User) -> Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM crate_owners WHERE crate_id = ?1 AND user_id = ?2)",
+        params![crate_id, user.id],
+        |row| row.get(0),
+    )
+    .map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use super::*;
+    use crate::{
+        api::models::PublishRequest,
+        db::{crates, users},
+    };
+
+    #[test]
+    fn ownership_lifecycle() {
+        let mut conn = crate::db::memory();
+        let name = "test".parse().unwrap();
+        let version = "1.0.0".parse().unwrap();
+        let alice = users::get_or_create(&conn, "alice").unwrap();
+        let bob = users::get_or_create(&conn, "bob").unwrap();
+
+        assert_eq!(
+            Claim::New,
+            claim(&mut conn, &name, &version, &alice, false).unwrap()
+        );
+        assert_eq!(
+            Claim::Denied,
+            claim(&mut conn, &name, &version, &bob, false).unwrap()
+        );
+
+        crates::upsert(
+            &mut conn,
+            &PublishRequest::new(name.clone(), version.clone()),
+            "{}",
+            "",
+            0,
+            &alice,
+        )
+        .unwrap();
+
+        // Crates with versions stay claimed.
+        unclaim(&conn, &name).unwrap();
+        assert!(is_owner(&conn, &name, &alice).unwrap());
+        assert_eq!(
+            Claim::Owner,
+            claim(&mut conn, &name, &version, &alice, false).unwrap()
+        );
+
+        add(&mut conn, &name, slice::from_ref(&bob)).unwrap();
+        assert_eq!(2, list(&conn, &name).unwrap().unwrap().len());
+
+        remove(&mut conn, &name, slice::from_ref(&alice)).unwrap();
+        assert!(!is_owner(&conn, &name, &alice).unwrap());
+
+        assert!(remove(&mut conn, &name, slice::from_ref(&bob)).is_err());
+        assert!(is_owner(&conn, &name, &bob).unwrap());
+    }
+
+    #[test]
+    fn claim_unknown_crates() {
+        let mut conn = crate::db::memory();
+        let name = "test".parse().unwrap();
+        let version = "1.0.0".parse().unwrap();
+        let alice = users::get_or_create(&conn, "alice").unwrap();
+
+        // Crates that only exist in the index have no owners.
+        assert_eq!(
+            Claim::Denied,
+            claim(&mut conn, &name, &version, &alice, true).unwrap()
+        );
+
+        // Neither do crates that were mirrored from the upstream registry.
+        crate::db::upstream::put(&conn, "mirrored", b"", None).unwrap();
+        assert_eq!(
+            Claim::Denied,
+            claim(
+                &mut conn,
+                &"mirrored".parse().unwrap(),
+                &version,
+                &alice,
+                false
+            )
+            .unwrap()
+        );
+
+        // Failed publishes give the name free again.
+        assert_eq!(
+            Claim::New,
+            claim(&mut conn, &name, &version, &alice, false).unwrap()
+        );
+        unclaim(&conn, &name).unwrap();
+        assert!(list(&conn, &name).unwrap().is_none());
+    }
+}
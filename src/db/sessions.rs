@@ -0,0 +1,85 @@
+//! Login sessions of the web UI. The session token is kept in a cookie by the browser, and like
+//! with API tokens, only a SHA-256 hash of it is stored.
+
+use anyhow::Result;
+use rand::{distributions::Alphanumeric, Rng};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{tokens, users::User};
+
+/// Amount of random characters in a session token.
+const TOKEN_LENGTH: usize = 48;
+/// Time in seconds after which a session expires and the user has to log in again.
+pub const LIFETIME: i64 = 7 * 24 * 60 * 60;
+
+/// Start a new session for the user and return the plain session token.
+pub fn create(conn: &Connection, user: &User) -> Result<String> {
+    let token = rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect::<String>();
+
+    conn.execute(
+        "DELETE FROM sessions WHERE expires_at <= strftime('%s', 'now')",
+        [],
+    )?;
+    conn.execute(
+        "INSERT INTO sessions (user_id, token_hash, expires_at)
+        VALUES (?1, ?2, strftime('%s', 'now') + ?3)",
+        params![user.id, tokens::hash(&token), LIFETIME],
+    )?;
+
+    Ok(token)
+}
+
+/// Look up the user of a session, if the session exists and hasn't expired yet.
+pub fn authenticate(conn: &Connection, token: &str) -> Result<Option<User>> {
+    conn.query_row(
+        "SELECT u.id, u.name FROM sessions s
+        JOIN users u ON u.id = s.user_id
+        WHERE s.token_hash = ?1 AND s.expires_at > strftime('%s', 'now')",
+        [tokens::hash(token)],
+        |row| {
+            Ok(User {
+                id: row.get(0)?,
+                name: row.get(1)?,
+            })
+        },
+    )
+    .optional()
+    .map_err(Into::into)
+}
+
+/// End a session, logging the user out.
+pub fn delete(conn: &Connection, token: &str) -> Result<()> {
+    conn.execute(
+        "DELETE FROM sessions WHERE token_hash = ?1",
+        [tokens::hash(token)],
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::users;
+
+    #[test]
+    fn session_lifecycle() {
+        let conn = crate::db::memory();
+        let user = users::get_or_create(&conn, "alice").unwrap();
+
+        let token = create(&conn, &user).unwrap();
+        assert_eq!(user.id, authenticate(&conn, &token).unwrap().unwrap().id);
+
+        delete(&conn, &token).unwrap();
+        assert!(authenticate(&conn, &token).unwrap().is_none());
+
+        let token = create(&conn, &user).unwrap();
+        conn.execute("UPDATE sessions SET expires_at = 0", [])
+            .unwrap();
+        assert!(authenticate(&conn, &token).unwrap().is_none());
+    }
+}
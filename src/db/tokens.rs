@@ -0,0 +1,144 @@
+//! API tokens that cargo sends in the `Authorization` header. Only a SHA-256 hash of each token is
+//! stored, the plain token is shown once to the user when it's created.
+
+use anyhow::Result;
+use rand::{distributions::Alphanumeric, Rng};
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+
+use super::users::User;
+
+/// Prefix of all generated tokens, to make them easy to recognize.
+const TOKEN_PREFIX: &str = "asg_";
+/// Amount of random characters in a token, after the prefix.
+const TOKEN_LENGTH: usize = 32;
+
+/// Details about a token, without the token value itself.
+#[derive(Debug)]
+pub struct TokenInfo {
+    pub id: i64,
+    pub name: String,
+    /// Creation time as seconds since the Unix epoch.
+    pub created_at: i64,
+    /// Last use as seconds since the Unix epoch, if it was used at all.
+    pub last_used_at: Option<i64>,
+}
+
+/// Create a new token for the given user and return the plain token value.
+pub fn create(conn: &Connection, user: &User, name: &str) -> Result<String> {
+    let token = rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect::<String>();
+    let token = format!("{TOKEN_PREFIX}{token}");
+
+    conn.execute(
+        "INSERT INTO api_tokens (user_id, name, token_hash) VALUES (?1, ?2, ?3)",
+        params![user.id, name, hash(&token)],
+    )?;
+
+    Ok(token)
+}
+
+/// Look up the user that the given token belongs to, if the token is known.
+pub fn authenticate(conn: &Connection, token: &str) -> Result<Option<User>> {
+    let hash = hash(token);
+    let user = conn
+        .query_row(
+            "SELECT u.id, u.name FROM api_tokens t
+            JOIN users u ON u.id = t.user_id
+            WHERE t.token_hash = ?1",
+            [&hash],
+            |row| {
+                Ok(User {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                })
+            },
+        )
+        .optional()?;
+
+    if user.is_some() {
+        conn.execute(
+            "UPDATE api_tokens SET last_used_at = strftime('%s', 'now') WHERE token_hash = ?1",
+            [&hash],
+        )?;
+    }
+
+    Ok(user)
+}
+
+/// List all tokens of the user, newest first.
+pub fn list(conn: &Connection, user: &User) -> Result<Vec<TokenInfo>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, name, created_at, last_used_at FROM api_tokens
+        WHERE user_id = ?1
+        ORDER BY created_at DESC, id DESC",
+    )?;
+
+    let tokens = stmt
+        .query_map([user.id], |row| {
+            Ok(TokenInfo {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                created_at: row.get(2)?,
+                last_used_at: row.get(3)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(tokens)
+}
+
+/// Revoke one of the user's tokens. Returns whether the token existed.
+pub fn revoke(conn: &Connection, user: &User, id: i64) -> Result<bool> {
+    let deleted = conn.execute(
+        "DELETE FROM api_tokens WHERE id = ?1 AND user_id = ?2",
+        params![id, user.id],
+    )?;
+
+    Ok(deleted > 0)
+}
+
+pub(super) fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::users;
+
+    #[test]
+    fn create_and_authenticate() {
+        let conn = crate::db::memory();
+        let user = users::get_or_create(&conn, "alice").unwrap();
+
+        let token = create(&conn, &user, "laptop").unwrap();
+        assert!(token.starts_with(TOKEN_PREFIX));
+
+        let found = authenticate(&conn, &token).unwrap().unwrap();
+        assert_eq!(user.id, found.id);
+
+        assert!(authenticate(&conn, "asg_invalid").unwrap().is_none());
+    }
+
+    #[test]
+    fn list_and_revoke() {
+        let conn = crate::db::memory();
+        let alice = users::get_or_create(&conn, "alice").unwrap();
+        let bob = users::get_or_create(&conn, "bob").unwrap();
+
+        let token = create(&conn, &alice, "laptop").unwrap();
+        create(&conn, &bob, "ci").unwrap();
+
+        let tokens = list(&conn, &alice).unwrap();
+        assert_eq!(1, tokens.len());
+        assert_eq!("laptop", tokens[0].name);
+
+        assert!(!revoke(&conn, &bob, tokens[0].id).unwrap());
+        assert!(revoke(&conn, &alice, tokens[0].id).unwrap());
+        assert!(authenticate(&conn, &token).unwrap().is_none());
+    }
+}
@@ -0,0 +1,109 @@
+//! Index files of crates from the upstream registry. They are cached, so they can still be served
+//! while the upstream is unreachable.
+//!
+//! The names of all crates that were ever mirrored are kept as well, even after they are removed
+//! from the upstream, so they can't be claimed by publishing a local crate of the same name.
+
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+
+/// The last known index file of an upstream crate.
+pub struct CachedCrate {
+    /// Newline delimited list of JSON encoded releases, as served by the upstream.
+    pub content: Vec<u8>,
+    /// Entity tag of the upstream response, to check for changes with the next request.
+    pub etag: Option<String>,
+    /// Last time the content changed, as UNIX timestamp.
+    pub modified_at: i64,
+}
+
+/// Get the cached index file of a crate, if it was fetched before.
+pub fn get(conn: &Connection, name: &str) -> Result<Option<CachedCrate>> {
+    conn.query_row(
+        "SELECT content, etag, modified_at FROM upstream_crates WHERE name = ?1",
+        [name],
+        |row| {
+            Ok(CachedCrate {
+                content: row.get(0)?,
+                etag: row.get(1)?,
+                modified_at: row.get(2)?,
+            })
+        },
+    )
+    .optional()
+    .map_err(Into::into)
+}
+
+/// Store the latest index file of a crate and record it as mirrored. The modification time is only
+/// updated if the content actually changed.
+pub fn put(conn: &Connection, name: &str, content: &[u8], etag: Option<&str>) -> Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO mirrored_crates (name, mirrored_at)
+        VALUES (?1, strftime('%s', 'now'))",
+        [name],
+    )?;
+    conn.execute(
+        "INSERT INTO upstream_crates (name, content, etag, modified_at)
+        VALUES (?1, ?2, ?3, strftime('%s', 'now'))
+        ON CONFLICT (name) DO UPDATE SET
+            modified_at = CASE
+                WHEN content = excluded.content THEN modified_at
+                ELSE excluded.modified_at
+            END,
+            content = excluded.content,
+            etag = excluded.etag",
+        params![name, content, etag],
+    )?;
+
+    Ok(())
+}
+
+/// Remove the cached index file of a crate that doesn't exist upstream anymore. It stays recorded
+/// as mirrored.
+pub fn delete(conn: &Connection, name: &str) -> Result<()> {
+    conn.execute("DELETE FROM upstream_crates WHERE name = ?1", [name])?;
+
+    Ok(())
+}
+
+/// Check whether the crate was ever mirrored from the upstream registry.
+pub fn is_mirrored(conn: &Connection, name: &str) -> Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM mirrored_crates WHERE name = ?1)",
+        [name],
+        |row| row.get(0),
+    )
+    .map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_lifecycle() {
+        let conn = crate::db::memory();
+        assert!(get(&conn, "foo").unwrap().is_none());
+        assert!(!is_mirrored(&conn, "foo").unwrap());
+
+        put(&conn, "foo", b"v1", Some("\"a\"")).unwrap();
+        conn.execute("UPDATE upstream_crates SET modified_at = 1", [])
+            .unwrap();
+
+        put(&conn, "foo", b"v1", Some("\"b\"")).unwrap();
+        let cached = get(&conn, "foo").unwrap().unwrap();
+        assert_eq!(b"v1", cached.content.as_slice());
+        assert_eq!(Some("\"b\""), cached.etag.as_deref());
+        assert_eq!(1, cached.modified_at);
+
+        put(&conn, "foo", b"v2", None).unwrap();
+        let cached = get(&conn, "foo").unwrap().unwrap();
+        assert_eq!(b"v2", cached.content.as_slice());
+        assert!(cached.etag.is_none());
+        assert!(cached.modified_at > 1);
+
+        delete(&conn, "foo").unwrap();
+        assert!(get(&conn, "foo").unwrap().is_none());
+        assert!(is_mirrored(&conn, "foo").unwrap());
+    }
+}
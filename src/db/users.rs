@@ -0,0 +1,249 @@
+//! User accounts that own crates and authenticate against the API.
+
+use std::num::NonZeroU32;
+
+use anyhow::Result;
+use rand::RngCore;
+use ring::pbkdf2;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+/// Amount of PBKDF2 rounds for newly hashed passwords. Kept low in tests, as the hashing is slow
+/// without optimizations.
+const PASSWORD_ROUNDS: NonZeroU32 =
+    NonZeroU32::new(if cfg!(test) { 1_000 } else { 100_000 }).unwrap();
+
+/// A single registered user.
+#[derive(Clone, Debug)]
+pub struct User {
+    pub id: i64,
+    pub name: String,
+}
+
+impl User {
+    fn from_row(row: &Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            name: row.get("name")?,
+        })
+    }
+}
+
+/// Find a user by its unique login name.
+pub fn find_by_name(conn: &Connection, name: &str) -> Result<Option<User>> {
+    conn.query_row(
+        "SELECT id, name FROM users WHERE name = ?1",
+        [name],
+        User::from_row,
+    )
+    .optional()
+    .map_err(Into::into)
+}
+
+/// Load the user with the given name, creating it first if it doesn't exist yet.
+pub fn get_or_create(conn: &Connection, name: &str) -> Result<User> {
+    conn.execute("INSERT OR IGNORE INTO users (name) VALUES (?1)", [name])?;
+    conn.query_row(
+        "SELECT id, name FROM users WHERE name = ?1",
+        [name],
+        User::from_row,
+    )
+    .map_err(Into::into)
+}
+
+/// Load the user linked to an account of an external identity provider. On the first login, a new
+/// user with the given name is created for the account. Returns `None` if a user with that name
+/// already exists, as linking it would let the provider account take over a local user or one of
+/// another account.
+pub fn get_or_create_by_identity(
+    conn: &Connection,
+    issuer: &str,
+    subject: &str,
+    name: &str,
+) -> Result<Option<User>> {
+    let linked = conn
+        .query_row(
+            "SELECT u.id, u.name FROM identities i
+            JOIN users u ON u.id = i.user_id
+            WHERE i.issuer = ?1 AND i.subject = ?2",
+            [issuer, subject],
+            User::from_row,
+        )
+        .optional()?;
+
+    if linked.is_some() {
+        return Ok(linked);
+    }
+
+    let created = conn.execute("INSERT OR IGNORE INTO users (name) VALUES (?1)", [name])?;
+    if created == 0 {
+        return Ok(None);
+    }
+
+    let user = User {
+        id: conn.last_insert_rowid(),
+        name: name.to_owned(),
+    };
+    conn.execute(
+        "INSERT INTO identities (issuer, subject, user_id) VALUES (?1, ?2, ?3)",
+        params![issuer, subject, user.id],
+    )?;
+
+    Ok(Some(user))
+}
+
+/// Set the password that the user logs into the web UI with.
+pub fn set_password(conn: &Connection, user: &User, password: &str) -> Result<()> {
+    let mut salt = [0; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+
+    let mut hash = [0; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        PASSWORD_ROUNDS,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    let encoded = format!(
+        "pbkdf2-sha256${PASSWORD_ROUNDS}${}${}",
+        hex::encode(salt),
+        hex::encode(hash)
+    );
+
+    conn.execute(
+        "UPDATE users SET password_hash = ?2 WHERE id = ?1",
+        params![user.id, encoded],
+    )?;
+
+    Ok(())
+}
+
+/// Check the login name and password, returning the user if both are correct. Users without a
+/// password can't log in.
+pub fn verify_password(conn: &Connection, name: &str, password: &str) -> Result<Option<User>> {
+    let found = conn
+        .query_row(
+            "SELECT id, name, password_hash FROM users WHERE name = ?1",
+            [name],
+            |row| Ok((User::from_row(row)?, row.get::<_, Option<String>>(2)?)),
+        )
+        .optional()?;
+
+    let Some((user, Some(encoded))) = found else {
+        return Ok(None);
+    };
+
+    let mut parts = encoded.split('$');
+    let (Some("pbkdf2-sha256"), Some(rounds), Some(salt), Some(hash), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        anyhow::bail!("invalid password hash for user `{}`", user.name);
+    };
+
+    let valid = pbkdf2::verify(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        rounds.parse()?,
+        &hex::decode(salt)?,
+        password.as_bytes(),
+        &hex::decode(hash)?,
+    );
+
+    Ok(valid.is_ok().then_some(user))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pbkdf2_known_answers() {
+        // First block of the test vectors in section 11 of RFC 7914.
+        for (password, salt, rounds, expected) in [
+            (
+                "passwd",
+                "salt",
+                1,
+                "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc",
+            ),
+            (
+                "Password",
+                "NaCl",
+                80000,
+                "4ddcd8f60b98be21830cee5ef22701f9641a4418d04c0414aeff08876b34ab56",
+            ),
+        ] {
+            let rounds = NonZeroU32::new(rounds).unwrap();
+            let expected = hex::decode(expected).unwrap();
+            let mut hash = [0; 32];
+            pbkdf2::derive(
+                pbkdf2::PBKDF2_HMAC_SHA256,
+                rounds,
+                salt.as_bytes(),
+                password.as_bytes(),
+                &mut hash,
+            );
+
+            assert_eq!(expected, hash);
+            assert!(pbkdf2::verify(
+                pbkdf2::PBKDF2_HMAC_SHA256,
+                rounds,
+                salt.as_bytes(),
+                password.as_bytes(),
+                &expected,
+            )
+            .is_ok());
+        }
+    }
+
+    #[test]
+    fn passwords() {
+        let conn = crate::db::memory();
+        let user = get_or_create(&conn, "alice").unwrap();
+
+        assert!(verify_password(&conn, "alice", "").unwrap().is_none());
+
+        set_password(&conn, &user, "secret").unwrap();
+        let found = verify_password(&conn, "alice", "secret").unwrap().unwrap();
+        assert_eq!(user.id, found.id);
+
+        assert!(verify_password(&conn, "alice", "wrong").unwrap().is_none());
+        assert!(verify_password(&conn, "bob", "secret").unwrap().is_none());
+    }
+
+    #[test]
+    fn identities() {
+        let conn = crate::db::memory();
+        get_or_create(&conn, "alice").unwrap();
+
+        // Provider accounts can't take over existing users with the same name.
+        assert!(get_or_create_by_identity(&conn, "http://idp", "1", "alice")
+            .unwrap()
+            .is_none());
+
+        let bob = get_or_create_by_identity(&conn, "http://idp", "1", "bob")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            Some(bob.id),
+            find_by_name(&conn, "bob").unwrap().map(|u| u.id)
+        );
+
+        // Renaming the account at the provider keeps the link.
+        let user = get_or_create_by_identity(&conn, "http://idp", "1", "bob2")
+            .unwrap()
+            .unwrap();
+        assert_eq!(bob.id, user.id);
+
+        // Neither other accounts of the same provider nor ones of other providers.
+        assert!(get_or_create_by_identity(&conn, "http://idp", "2", "bob")
+            .unwrap()
+            .is_none());
+        assert!(get_or_create_by_identity(&conn, "http://other", "1", "bob")
+            .unwrap()
+            .is_none());
+    }
+}
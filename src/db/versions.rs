@@ -0,0 +1,445 @@
+//! Published versions of crates and their dependencies, as exposed by the read-only crate API.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use semver::{Version, VersionReq};
+
+use super::users::User;
+use crate::{
+    api::models::{Kind, PublishRequest},
+    index::models::Release,
+    models::CrateName,
+};
+
+/// Columns selected for a [`VersionInfo`], expecting the `versions` table as `v` and the
+/// publishing user as `u`.
+const VERSION_COLUMNS: &str = "v.id, v.num, v.checksum, v.crate_size, v.features, v.links, \
+    v.license, v.rust_version, v.yanked, v.created_at, u.id AS user_id, u.name AS user_name";
+
+/// Columns selected for a [`DependencyInfo`], expecting the `dependencies` table as `d`.
+const DEPENDENCY_COLUMNS: &str = "d.id AS dep_id, d.version_id, d.crate_name, d.req, \
+    d.features AS dep_features, d.optional, d.default_features, d.target, d.kind, d.registry";
+
+/// A single published version of a crate.
+#[derive(Debug)]
+pub struct VersionInfo {
+    pub id: i64,
+    pub num: Version,
+    pub checksum: String,
+    pub crate_size: u64,
+    pub features: BTreeMap<String, BTreeSet<String>>,
+    pub links: Option<String>,
+    pub license: Option<String>,
+    /// Minimum supported Rust version.
+    pub rust_version: Option<String>,
+    pub yanked: bool,
+    /// The user that published the version, if the account still exists.
+    pub published_by: Option<User>,
+    /// Publishing time as seconds since the Unix epoch.
+    pub created_at: i64,
+}
+
+impl VersionInfo {
+    fn from_row(row: &Row<'_>) -> Result<Self> {
+        let user_id = row.get::<_, Option<i64>>("user_id")?;
+        let user_name = row.get::<_, Option<String>>("user_name")?;
+
+        Ok(Self {
+            id: row.get("id")?,
+            num: row.get::<_, String>("num")?.parse()?,
+            checksum: row.get("checksum")?,
+            crate_size: row.get("crate_size")?,
+            features: serde_json::from_str(&row.get::<_, String>("features")?)?,
+            links: row.get("links")?,
+            license: row.get("license")?,
+            rust_version: row.get("rust_version")?,
+            yanked: row.get("yanked")?,
+            published_by: user_id.zip(user_name).map(|(id, name)| User { id, name }),
+            created_at: row.get("created_at")?,
+        })
+    }
+}
+
+/// A dependency of a single crate version.
+#[derive(Debug)]
+pub struct DependencyInfo {
+    pub id: i64,
+    pub version_id: i64,
+    /// Name of the dependency. Not a [`CrateName`] as it could come from another registry.
+    pub crate_name: String,
+    pub req: VersionReq,
+    pub features: BTreeSet<String>,
+    pub optional: bool,
+    pub default_features: bool,
+    pub target: Option<String>,
+    pub kind: Kind,
+    /// Index URL of the registry the dependency comes from, if not this one.
+    pub registry: Option<String>,
+}
+
+impl DependencyInfo {
+    fn from_row(row: &Row<'_>) -> Result<Self> {
+        Ok(Self {
+            id: row.get("dep_id")?,
+            version_id: row.get("version_id")?,
+            crate_name: row.get("crate_name")?,
+            req: row.get::<_, String>("req")?.parse()?,
+            features: serde_json::from_str(&row.get::<_, String>("dep_features")?)?,
+            optional: row.get("optional")?,
+            default_features: row.get("default_features")?,
+            target: row.get("target")?,
+            kind: row.get::<_, String>("kind")?.parse()?,
+            registry: row.get("registry")?,
+        })
+    }
+}
+
+/// A crate version that depends on another crate, as returned by [`reverse_dependencies`].
+#[derive(Debug)]
+pub struct ReverseDependency {
+    pub name: CrateName,
+    pub version: VersionInfo,
+    pub dependency: DependencyInfo,
+}
+
+/// Record a newly published version of a crate, together with all its dependencies and the raw
+/// metadata JSON of the publish request.
+pub(super) fn insert(
+    conn: &Connection,
+    crate_id: i64,
+    req: &PublishRequest,
+    metadata: &str,
+    checksum: &str,
+    crate_size: u64,
+    publisher: &User,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO versions
+        (crate_id, num, checksum, crate_size, features, links, license, rust_version, published_by)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            crate_id,
+            req.vers.to_string(),
+            checksum,
+            crate_size,
+            serde_json::to_string(&req.features)?,
+            req.links,
+            req.license,
+            req.rust_version,
+            publisher.id,
+        ],
+    )?;
+
+    let version_id = conn.last_insert_rowid();
+
+    conn.execute(
+        "INSERT INTO metadata (version_id, content) VALUES (?1, ?2)",
+        params![version_id, metadata],
+    )?;
+
+    if let Some(readme) = &req.readme {
+        conn.execute(
+            "INSERT INTO readmes (version_id, content) VALUES (?1, ?2)",
+            params![version_id, readme],
+        )?;
+    }
+
+    let mut stmt = conn.prepare(
+        "INSERT INTO dependencies
+        (version_id, crate_name, req, features, optional, default_features, target, kind, registry)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+    )?;
+
+    for dep in &req.deps {
+        stmt.execute(params![
+            version_id,
+            dep.name,
+            dep.version_req.to_string(),
+            serde_json::to_string(&dep.features)?,
+            dep.optional,
+            dep.default_features,
+            dep.target,
+            dep.kind.as_str(),
+            dep.registry.as_ref().map(ToString::to_string),
+        ])?;
+    }
+
+    Ok(())
+}
+
+/// Record a version that was imported from the index entry of another registry. Only the parts
+/// that the entry carries are known, so there is no license, README, metadata or publisher.
+/// Versions that are already recorded are left as they are.
+pub(super) fn import(
+    conn: &Connection,
+    crate_id: i64,
+    release: &Release,
+    crate_size: u64,
+) -> Result<()> {
+    let mut features = release.features.clone();
+    features.extend(release.features2.clone().unwrap_or_default());
+
+    let inserted = conn.execute(
+        "INSERT INTO versions
+        (crate_id, num, checksum, crate_size, features, links, rust_version, yanked)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+        ON CONFLICT (crate_id, num) DO NOTHING",
+        params![
+            crate_id,
+            release.vers.to_string(),
+            release.cksum,
+            crate_size,
+            serde_json::to_string(&features)?,
+            release.links,
+            release.rust_version,
+            release.yanked,
+        ],
+    )?;
+    if inserted == 0 {
+        return Ok(());
+    }
+
+    let version_id = conn.last_insert_rowid();
+    let mut stmt = conn.prepare(
+        "INSERT INTO dependencies
+        (version_id, crate_name, req, features, optional, default_features, target, kind, registry)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+    )?;
+
+    for dep in &release.deps {
+        // Index entries name dependencies by their renamed name, with the crate in `package`.
+        stmt.execute(params![
+            version_id,
+            dep.package.as_ref().unwrap_or(&dep.name),
+            dep.req.to_string(),
+            serde_json::to_string(&dep.features)?,
+            dep.optional,
+            dep.default_features,
+            dep.target,
+            Kind::from(&dep.kind).as_str(),
+            dep.registry.as_ref().map(ToString::to_string),
+        ])?;
+    }
+
+    Ok(())
+}
+
+/// List all versions of a crate, sorted from highest to lowest version.
+pub fn list(conn: &Connection, name: &CrateName) -> Result<Vec<VersionInfo>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {VERSION_COLUMNS} FROM versions v
+        JOIN crates c ON c.id = v.crate_id
+        LEFT JOIN users u ON u.id = v.published_by
+        WHERE c.name = ?1"
+    ))?;
+
+    let mut rows = stmt.query([name.as_ref()])?;
+    let mut versions = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        versions.push(VersionInfo::from_row(row)?);
+    }
+
+    versions.sort_by(|a, b| b.num.cmp(&a.num));
+
+    Ok(versions)
+}
+
+/// Find a single version of a crate.
+pub fn find(conn: &Connection, name: &CrateName, version: &Version) -> Result<Option<VersionInfo>> {
+    conn.query_row(
+        &format!(
+            "SELECT {VERSION_COLUMNS} FROM versions v
+            JOIN crates c ON c.id = v.crate_id
+            LEFT JOIN users u ON u.id = v.published_by
+            WHERE c.name = ?1 AND v.num = ?2"
+        ),
+        params![name.as_ref(), version.to_string()],
+        |row| Ok(VersionInfo::from_row(row)),
+    )
+    .optional()?
+    .transpose()
+}
+
+/// List the dependencies of a single crate version, sorted by name.
+pub fn dependencies(conn: &Connection, version_id: i64) -> Result<Vec<DependencyInfo>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {DEPENDENCY_COLUMNS} FROM dependencies d
+        WHERE d.version_id = ?1
+        ORDER BY d.crate_name, d.kind"
+    ))?;
+
+    let mut rows = stmt.query([version_id])?;
+    let mut deps = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        deps.push(DependencyInfo::from_row(row)?);
+    }
+
+    Ok(deps)
+}
+
+/// Load the README of a single crate version, if it was published with one.
+pub fn readme(conn: &Connection, version_id: i64) -> Result<Option<String>> {
+    conn.query_row(
+        "SELECT content FROM readmes WHERE version_id = ?1",
+        [version_id],
+        |row| row.get(0),
+    )
+    .optional()
+    .map_err(Into::into)
+}
+
+/// Load the raw metadata JSON of a single crate version, as it was sent by cargo. Only recorded for
+/// versions that were published after upgrading to a registry version that stores it.
+pub fn metadata(conn: &Connection, version_id: i64) -> Result<Option<String>> {
+    conn.query_row(
+        "SELECT content FROM metadata WHERE version_id = ?1",
+        [version_id],
+        |row| row.get(0),
+    )
+    .optional()
+    .map_err(Into::into)
+}
+
+/// List all crates that depend on the given crate in their highest version, sorted by name.
+pub fn reverse_dependencies(conn: &Connection, name: &CrateName) -> Result<Vec<ReverseDependency>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT c.name AS crate, {VERSION_COLUMNS}, {DEPENDENCY_COLUMNS} FROM dependencies d
+        JOIN versions v ON v.id = d.version_id
+        JOIN crates c ON c.id = v.crate_id AND c.max_version = v.num
+        LEFT JOIN users u ON u.id = v.published_by
+        WHERE d.crate_name = ?1 AND d.registry IS NULL
+        ORDER BY c.name"
+    ))?;
+
+    let mut rows = stmt.query([name.as_ref()])?;
+    let mut deps = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        deps.push(ReverseDependency {
+            name: row.get::<_, String>("crate")?.parse()?,
+            version: VersionInfo::from_row(row)?,
+            dependency: DependencyInfo::from_row(row)?,
+        });
+    }
+
+    Ok(deps)
+}
+
+/// Update the yanked state of a single crate version.
+pub fn set_yanked(
+    conn: &Connection,
+    name: &CrateName,
+    version: &Version,
+    yanked: bool,
+) -> Result<()> {
+    conn.execute(
+        "UPDATE versions SET yanked = ?3
+        WHERE crate_id = (SELECT id FROM crates WHERE name = ?1) AND num = ?2",
+        params![name.as_ref(), version.to_string(), yanked],
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::models::Dependency,
+        db::{crates, users},
+    };
+
+    fn publish(conn: &mut Connection, name: &str, version: &str, deps: &[&str]) {
+        let user = users::get_or_create(conn, "alice").unwrap();
+        let mut req = PublishRequest::new(name.parse().unwrap(), version.parse().unwrap());
+        req.license = Some("MIT".to_owned());
+        req.rust_version = Some("1.60".to_owned());
+        req.readme = Some(format!("# {name}"));
+        req.deps = deps
+            .iter()
+            .map(|&dep| Dependency {
+                name: dep.to_owned(),
+                version_req: "^1".parse().unwrap(),
+                features: BTreeSet::new(),
+                optional: false,
+                default_features: true,
+                target: None,
+                kind: Kind::Normal,
+                registry: None,
+                explicit_name_in_toml: None,
+            })
+            .collect();
+
+        let metadata = serde_json::to_string(&req).unwrap();
+        crates::upsert(conn, &req, &metadata, "abc", 10, &user).unwrap();
+    }
+
+    #[test]
+    fn versions_roundtrip() {
+        let mut conn = crate::db::memory();
+        let name = "test".parse().unwrap();
+
+        publish(&mut conn, "test", "1.0.0", &[]);
+        publish(&mut conn, "test", "1.1.0", &["serde", "rand"]);
+        publish(&mut conn, "test", "0.9.0", &[]);
+
+        let versions = list(&conn, &name).unwrap();
+        let nums = versions
+            .iter()
+            .map(|v| v.num.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["1.1.0", "1.0.0", "0.9.0"], nums);
+        assert_eq!(Some("MIT"), versions[0].license.as_deref());
+        assert_eq!(Some("1.60"), versions[0].rust_version.as_deref());
+        assert_eq!(
+            Some("alice"),
+            versions[0].published_by.as_ref().map(|u| u.name.as_str())
+        );
+
+        let version = find(&conn, &name, &Version::new(1, 1, 0)).unwrap().unwrap();
+        let metadata = metadata(&conn, version.id).unwrap().unwrap();
+        assert!(metadata.contains(r#""vers":"1.1.0""#));
+
+        let deps = dependencies(&conn, version.id).unwrap();
+        let names = deps
+            .iter()
+            .map(|d| d.crate_name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["rand", "serde"], names);
+
+        assert!(find(&conn, &name, &Version::new(2, 0, 0))
+            .unwrap()
+            .is_none());
+
+        set_yanked(&conn, &name, &Version::new(1, 0, 0), true).unwrap();
+        let version = find(&conn, &name, &Version::new(1, 0, 0)).unwrap().unwrap();
+        assert!(version.yanked);
+    }
+
+    #[test]
+    fn reverse_dependencies_use_max_version() {
+        let mut conn = crate::db::memory();
+
+        publish(&mut conn, "serde", "1.0.0", &[]);
+        publish(&mut conn, "b", "1.0.0", &["serde"]);
+        publish(&mut conn, "a", "1.0.0", &["serde"]);
+        publish(&mut conn, "a", "2.0.0", &["serde"]);
+        publish(&mut conn, "c", "1.0.0", &["serde"]);
+        publish(&mut conn, "c", "2.0.0", &[]);
+
+        let deps = reverse_dependencies(&conn, &"serde".parse().unwrap()).unwrap();
+        let found = deps
+            .iter()
+            .map(|d| (d.name.as_ref(), d.version.num.to_string()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![("a", "2.0.0".to_owned()), ("b", "1.0.0".to_owned())],
+            found
+        );
+    }
+}
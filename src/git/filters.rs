@@ -0,0 +1,64 @@
+use std::{convert::Infallible, path::PathBuf, sync::Arc};
+
+use hyper::body::Bytes;
+use warp::{Filter, Rejection, Reply};
+
+use super::handlers;
+use crate::api::error;
+
+/// All git related routes prefixed with `/git/index/...`.
+pub fn git(location: PathBuf) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let location = Arc::new(location);
+
+    warp::path!("git" / "index" / ..)
+        .and(info_refs(Arc::clone(&location)).or(upload_pack(location)))
+}
+
+/// `GET /git/index/info/refs?service=git-upload-pack`
+fn info_refs(
+    location: Arc<PathBuf>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("info" / "refs")
+        .and(warp::get())
+        .and(raw_query())
+        .and(warp::header::headers_cloned())
+        .and(with_location(location))
+        .and_then(|query, headers, location| {
+            handlers::backend("GET", "/info/refs", query, headers, Bytes::new(), location)
+        })
+        .recover(error::recover)
+}
+
+/// `POST /git/index/git-upload-pack`
+fn upload_pack(
+    location: Arc<PathBuf>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("git-upload-pack")
+        .and(warp::post())
+        .and(warp::header::headers_cloned())
+        .and(warp::body::content_length_limit(10_000_000))
+        .and(warp::body::bytes())
+        .and(with_location(location))
+        .and_then(|headers, body, location| {
+            handlers::backend(
+                "POST",
+                "/git-upload-pack",
+                String::new(),
+                headers,
+                body,
+                location,
+            )
+        })
+        .recover(error::recover)
+}
+
+/// Extract the raw query string, or an empty one if the request has none.
+fn raw_query() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    warp::query::raw().or(warp::any().map(String::new)).unify()
+}
+
+fn with_location(
+    location: Arc<PathBuf>,
+) -> impl Filter<Extract = (Arc<PathBuf>,), Error = Infallible> + Clone {
+    warp::any().map(move || Arc::clone(&location))
+}
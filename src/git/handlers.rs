@@ -0,0 +1,170 @@
+use std::{path::PathBuf, process::Stdio, sync::Arc};
+
+use anyhow::{bail, ensure, Context};
+use hyper::{body::Bytes, Body, HeaderMap, StatusCode};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::Command,
+};
+use tokio_util::codec::{BytesCodec, FramedRead};
+use tracing::{error, instrument, warn};
+use warp::reply::Response;
+
+use crate::api::error::{ApiError, Result, ServerError};
+
+/// Request headers that are passed on to the CGI program, together with the variable name they are
+/// passed as.
+const FORWARDED_HEADERS: &[(&str, &str)] = &[
+    ("content-type", "CONTENT_TYPE"),
+    ("content-encoding", "HTTP_CONTENT_ENCODING"),
+    ("git-protocol", "GIT_PROTOCOL"),
+];
+
+/// Run `git http-backend` for a single request and stream its output back to the client.
+#[instrument(skip(headers, body, location))]
+pub async fn backend(
+    method: &'static str,
+    path_info: &'static str,
+    query: String,
+    headers: HeaderMap,
+    body: Bytes,
+    location: Arc<PathBuf>,
+) -> Result<Response> {
+    if query.contains("git-receive-pack") {
+        return Err(ApiError::Forbidden("pushing to the index is not allowed".to_owned()).into());
+    }
+
+    run_backend(method, path_info, &query, &headers, body, &location)
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "failed running git http-backend");
+            ServerError(e).into()
+        })
+}
+
+async fn run_backend(
+    method: &str,
+    path_info: &str,
+    query: &str,
+    headers: &HeaderMap,
+    body: Bytes,
+    location: &PathBuf,
+) -> anyhow::Result<Response> {
+    let mut cmd = Command::new("git");
+    cmd.arg("http-backend")
+        .env("GIT_PROJECT_ROOT", location)
+        .env("GIT_HTTP_EXPORT_ALL", "1")
+        .env("REQUEST_METHOD", method)
+        .env("PATH_INFO", path_info)
+        .env("QUERY_STRING", query)
+        .env("CONTENT_LENGTH", body.len().to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped());
+
+    for (header, var) in FORWARDED_HEADERS {
+        if let Some(value) = headers.get(*header).and_then(|v| v.to_str().ok()) {
+            cmd.env(var, value);
+        }
+    }
+
+    let mut child = cmd.spawn().context("failed to run git http-backend")?;
+
+    let mut stdin = child.stdin.take().context("missing stdin")?;
+    tokio::spawn(async move {
+        if let Err(e) = stdin.write_all(&body).await {
+            warn!(error = ?e, "failed writing request body to git http-backend");
+        }
+    });
+
+    let mut stdout = BufReader::new(child.stdout.take().context("missing stdout")?);
+    tokio::spawn(async move { child.wait().await });
+
+    let resp = read_cgi_headers(&mut stdout).await?;
+
+    let stream = FramedRead::new(stdout, BytesCodec::new());
+
+    resp.body(Body::wrap_stream(stream)).map_err(Into::into)
+}
+
+/// Read the headers of a CGI response into a response builder, leaving the reader at the start of
+/// the body. Fails if the output ends before the headers are complete or there aren't any, which
+/// happens when the program couldn't run at all.
+async fn read_cgi_headers(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> anyhow::Result<hyper::http::response::Builder> {
+    let mut resp = hyper::Response::builder();
+    let mut line = String::new();
+    let mut count = 0;
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            bail!("CGI output ended before the end of the headers");
+        }
+
+        let Some((name, value)) = parse_cgi_header(&line) else {
+            break;
+        };
+
+        resp = if name.eq_ignore_ascii_case("status") {
+            let code = value.split_whitespace().next().unwrap_or_default();
+            resp.status(code.parse::<StatusCode>()?)
+        } else {
+            resp.header(name, value)
+        };
+        count += 1;
+    }
+
+    ensure!(count > 0, "CGI output has no headers");
+
+    Ok(resp)
+}
+
+/// Parse a single header line of a CGI response, returning `None` for the empty line that
+/// separates the headers from the body.
+fn parse_cgi_header(line: &str) -> Option<(&str, &str)> {
+    let (name, value) = line.trim_end().split_once(':')?;
+    Some((name.trim(), value.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cgi_header() {
+        assert_eq!(
+            Some((
+                "Content-Type",
+                "application/x-git-upload-pack-advertisement"
+            )),
+            parse_cgi_header("Content-Type: application/x-git-upload-pack-advertisement\r\n")
+        );
+        assert_eq!(
+            Some(("Status", "404 Not Found")),
+            parse_cgi_header("Status: 404 Not Found\n")
+        );
+        assert_eq!(None, parse_cgi_header("\r\n"));
+        assert_eq!(None, parse_cgi_header(""));
+    }
+
+    #[tokio::test]
+    async fn test_read_cgi_headers() {
+        let mut reader = &b"Status: 404 Not Found\r\nContent-Type: text/plain\r\n\r\nbody"[..];
+        let resp = read_cgi_headers(&mut reader)
+            .await
+            .unwrap()
+            .body(())
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        assert_eq!("text/plain", resp.headers()["content-type"]);
+        assert_eq!(b"body", reader);
+
+        // Programs that fail to run don't write anything.
+        assert!(read_cgi_headers(&mut &b""[..]).await.is_err());
+        assert!(read_cgi_headers(&mut &b"Content-Type: text/plain\r\n"[..])
+            .await
+            .is_err());
+        assert!(read_cgi_headers(&mut &b"\r\nbody"[..]).await.is_err());
+    }
+}
@@ -0,0 +1,6 @@
+//! Read-only access to the git index over the
+//! [smart HTTP](https://git-scm.com/docs/http-protocol) protocol. The protocol itself is handled by
+//! `git http-backend`, which is run as CGI program for each request.
+
+pub mod filters;
+mod handlers;
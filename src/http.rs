@@ -0,0 +1,48 @@
+//! HTTP client for requests to other services, like object stores or identity providers.
+
+use hyper::{client::HttpConnector, Client};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use tokio_rustls::rustls::{Certificate, ClientConfig, RootCertStore};
+use tracing::warn;
+
+/// Client that connects to both `http` and `https` URLs. Server certificates are verified against
+/// the [`root_certificates`].
+pub type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+/// Create a new [`HttpClient`].
+pub fn client() -> HttpClient {
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_certificates())
+        .with_no_client_auth();
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(config)
+        .https_or_http()
+        .enable_http1()
+        .build();
+
+    Client::builder().build(connector)
+}
+
+/// Load the root certificates of the system that TLS connections are verified against. A
+/// different bundle can be used by pointing the `SSL_CERT_FILE` environment variable to it.
+pub fn root_certificates() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+
+    match rustls_native_certs::load_native_certs() {
+        Ok(certificates) => {
+            for certificate in certificates {
+                if let Err(error) = roots.add(&Certificate(certificate.0)) {
+                    warn!(%error, "skipping invalid root certificate");
+                }
+            }
+        }
+        Err(error) => warn!(%error, "failed loading the root certificates"),
+    }
+
+    if roots.is_empty() {
+        warn!("no root certificates found, TLS connections will fail");
+    }
+
+    roots
+}
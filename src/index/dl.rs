@@ -0,0 +1,302 @@
+//! Handling of the `dl` download template from the index [`Config`](super::models::Config).
+//!
+//! Cargo replaces the markers `{crate}`, `{version}`, `{prefix}`, `{lowerprefix}` and
+//! `{sha256-checksum}` in the template to build the download URL of a crate. If none of the markers
+//! is present, it appends `/{crate}/{version}/download` instead. This module does the reverse and
+//! extracts the crate information from a request path.
+
+use anyhow::{ensure, Result};
+use percent_encoding::percent_decode_str;
+use semver::Version;
+use url::Url;
+
+use crate::models::CrateName;
+
+/// Parsed form of the path component of the `dl` template.
+#[derive(Debug)]
+pub struct Template(Vec<Part>);
+
+#[derive(Debug, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Marker(Marker),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Marker {
+    Crate,
+    Version,
+    Prefix,
+    LowerPrefix,
+    Checksum,
+}
+
+impl Marker {
+    const ALL: [Self; 5] = [
+        Self::Crate,
+        Self::Version,
+        Self::Prefix,
+        Self::LowerPrefix,
+        Self::Checksum,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Crate => "{crate}",
+            Self::Version => "{version}",
+            Self::Prefix => "{prefix}",
+            Self::LowerPrefix => "{lowerprefix}",
+            Self::Checksum => "{sha256-checksum}",
+        }
+    }
+
+    /// Whether the value is acceptable for this marker. Only a quick check to limit the possible
+    /// matches, the values are fully validated once the whole path matched.
+    fn accepts(self, value: &str) -> bool {
+        match self {
+            Self::Crate | Self::Version => !value.contains('/'),
+            Self::Prefix | Self::LowerPrefix => true,
+            Self::Checksum => value.len() == 64 && value.bytes().all(|b| b.is_ascii_hexdigit()),
+        }
+    }
+}
+
+/// Crate information extracted from a download path.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Download {
+    pub name: CrateName,
+    pub version: Version,
+    /// The SHA-256 checksum of the crate tarball, if the template contains it.
+    pub checksum: Option<String>,
+}
+
+impl Template {
+    /// Parse the `dl` value from the index configuration. It must include at least the `{crate}`
+    /// and `{version}` markers if it uses any markers at all, as they are needed to identify the
+    /// crate to download.
+    pub fn parse(dl: &str) -> Result<Self> {
+        let url = dl.parse::<Url>()?;
+        let mut path = percent_decode_str(url.path()).decode_utf8()?.into_owned();
+
+        if !Marker::ALL.iter().any(|m| path.contains(m.as_str())) {
+            path = format!(
+                "{}/{{crate}}/{{version}}/download",
+                path.trim_end_matches('/')
+            );
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = path.as_str();
+
+        while !rest.is_empty() {
+            let next = Marker::ALL
+                .iter()
+                .filter_map(|&m| rest.find(m.as_str()).map(|pos| (pos, m)))
+                .min_by_key(|(pos, _)| *pos);
+
+            match next {
+                Some((pos, marker)) => {
+                    if pos > 0 {
+                        parts.push(Part::Literal(rest[..pos].to_owned()));
+                    }
+                    parts.push(Part::Marker(marker));
+                    rest = &rest[pos + marker.as_str().len()..];
+                }
+                None => {
+                    parts.push(Part::Literal(rest.to_owned()));
+                    rest = "";
+                }
+            }
+        }
+
+        for marker in [Marker::Crate, Marker::Version] {
+            ensure!(
+                parts.contains(&Part::Marker(marker)),
+                "download template `{dl}` is missing the `{}` marker",
+                marker.as_str()
+            );
+        }
+
+        Ok(Self(parts))
+    }
+
+    /// Build the download path of a crate version, by filling in all markers of the template.
+    pub fn render(&self, name: &CrateName, version: &Version, checksum: &str) -> String {
+        let prefix = super::crate_prefix(name);
+
+        self.0
+            .iter()
+            .map(|part| match part {
+                Part::Literal(literal) => literal.clone(),
+                Part::Marker(Marker::Crate) => name.to_string(),
+                Part::Marker(Marker::Version) => version.to_string(),
+                Part::Marker(Marker::Prefix) => prefix.clone(),
+                Part::Marker(Marker::LowerPrefix) => prefix.to_lowercase(),
+                Part::Marker(Marker::Checksum) => checksum.to_owned(),
+            })
+            .collect()
+    }
+
+    /// Extract the crate information from a request path, if it matches the template.
+    pub fn matches(&self, path: &str) -> Option<Download> {
+        let path = percent_decode_str(path).decode_utf8().ok()?;
+        match_parts(&self.0, &path, &mut Vec::new())
+    }
+}
+
+/// Match the path against the template parts, trying all possible lengths for marker values until
+/// the captured values form a valid [`Download`].
+fn match_parts<'a>(
+    parts: &[Part],
+    path: &'a str,
+    captures: &mut Vec<(Marker, &'a str)>,
+) -> Option<Download> {
+    let Some((part, rest)) = parts.split_first() else {
+        return if path.is_empty() {
+            extract(captures)
+        } else {
+            None
+        };
+    };
+
+    match part {
+        Part::Literal(literal) => path
+            .strip_prefix(literal.as_str())
+            .and_then(|path| match_parts(rest, path, captures)),
+        Part::Marker(marker) => {
+            for (end, _) in path.char_indices().skip(1).chain([(path.len(), ' ')]) {
+                let value = &path[..end];
+                if !marker.accepts(value) {
+                    continue;
+                }
+
+                captures.push((*marker, value));
+                if let Some(download) = match_parts(rest, &path[end..], captures) {
+                    return Some(download);
+                }
+                captures.pop();
+            }
+
+            None
+        }
+    }
+}
+
+/// Validate the captured marker values and turn them into a [`Download`].
+fn extract(captures: &[(Marker, &str)]) -> Option<Download> {
+    let name = first_value(captures, Marker::Crate)?
+        .parse::<CrateName>()
+        .ok()?;
+    let version = first_value(captures, Marker::Version)?
+        .parse::<Version>()
+        .ok()?;
+    let checksum = first_value(captures, Marker::Checksum);
+    let prefix = super::crate_prefix(&name);
+
+    // Markers can appear multiple times, but must always have the same value.
+    let consistent = captures.iter().all(|&(marker, value)| match marker {
+        Marker::Crate => value == name.as_ref(),
+        Marker::Version => value == version.to_string(),
+        Marker::Prefix => value == prefix,
+        Marker::LowerPrefix => value == prefix.to_lowercase(),
+        Marker::Checksum => Some(value) == checksum,
+    });
+
+    consistent.then(|| Download {
+        name,
+        version,
+        checksum: checksum.map(str::to_lowercase),
+    })
+}
+
+/// First captured value of the marker.
+fn first_value<'a>(captures: &[(Marker, &'a str)], marker: Marker) -> Option<&'a str> {
+    captures
+        .iter()
+        .find_map(|&(m, value)| (m == marker).then_some(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn download(name: &str, version: &str, checksum: Option<&str>) -> Option<Download> {
+        Some(Download {
+            name: name.parse().unwrap(),
+            version: version.parse().unwrap(),
+            checksum: checksum.map(ToOwned::to_owned),
+        })
+    }
+
+    #[test]
+    fn without_markers() {
+        let template = Template::parse("http://localhost:8080/api/v1/crates").unwrap();
+
+        assert_eq!(
+            download("foo-bar", "1.0.0+build", None),
+            template.matches("/api/v1/crates/foo-bar/1.0.0+build/download")
+        );
+        assert_eq!(None, template.matches("/api/v1/crates/foo-bar/1.0.0"));
+        assert_eq!(
+            None,
+            template.matches("/api/v1/crates/foo-bar/1.x/download")
+        );
+    }
+
+    #[test]
+    fn with_markers() {
+        let template = Template::parse(
+            "http://localhost:8080/crates/{prefix}/{crate}/{crate}-{version}.crate",
+        )
+        .unwrap();
+
+        assert_eq!(
+            download("foo-bar", "1.0.0-rc.1", None),
+            template.matches("/crates/fo/o-/foo-bar/foo-bar-1.0.0-rc.1.crate")
+        );
+        assert_eq!(
+            download("abc", "2.0.0", None),
+            template.matches("/crates/3/a/abc/abc-2.0.0.crate")
+        );
+        assert_eq!(
+            None,
+            template.matches("/crates/ab/cd/foo-bar/foo-bar-1.0.0.crate")
+        );
+        assert_eq!(
+            None,
+            template.matches("/crates/fo/o-/foo-bar/foo-baz-1.0.0.crate")
+        );
+    }
+
+    #[test]
+    fn with_checksum() {
+        let checksum = "a".repeat(64);
+        let template =
+            Template::parse("http://localhost:8080/dl/{crate}/{version}/{sha256-checksum}")
+                .unwrap();
+
+        assert_eq!(
+            download("foo", "1.0.0", Some(&checksum)),
+            template.matches(&format!("/dl/foo/1.0.0/{checksum}"))
+        );
+        assert_eq!(None, template.matches("/dl/foo/1.0.0/abc"));
+    }
+
+    #[test]
+    fn render() {
+        let template = Template::parse(
+            "http://localhost:8080/crates/{prefix}/{crate}/{crate}-{version}.crate",
+        )
+        .unwrap();
+        let path = template.render(&"foo-bar".parse().unwrap(), &"1.0.0".parse().unwrap(), "");
+
+        assert_eq!("/crates/fo/o-/foo-bar/foo-bar-1.0.0.crate", path);
+        assert_eq!(download("foo-bar", "1.0.0", None), template.matches(&path));
+    }
+
+    #[test]
+    fn missing_markers() {
+        assert!(Template::parse("http://localhost:8080/dl/{crate}.crate").is_err());
+        assert!(Template::parse("http://localhost:8080/dl/{version}/{prefix}").is_err());
+    }
+}
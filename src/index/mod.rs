@@ -1,142 +1,285 @@
 use std::{
-    fs::{self, File, OpenOptions},
-    io::{prelude::*, BufReader, BufWriter, ErrorKind},
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{prelude::*, BufReader, ErrorKind},
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 
-use anyhow::{bail, ensure, Context, Result};
+use anyhow::{bail, Context, Result};
 use git2::{build::CheckoutBuilder, ErrorCode, Repository, RepositoryInitOptions};
 use parking_lot::Mutex;
 use semver::Version;
-use tracing::instrument;
+use tracing::{error, instrument};
 
-use self::models::Release;
-use crate::{api::models::PublishRequest, models::CrateName, settings};
+use self::models::{Config, Release};
+use crate::{
+    api::models::PublishRequest,
+    models::{AlreadyExists, CrateName},
+    settings,
+};
 
+pub mod dl;
 pub mod models;
 
 /// The index service that handles all functionality of the crate index. This index holds metadata
 /// information about all crates like existing versions, dependencies and so on.
+///
+/// All changes to the index are serialized by the service itself, so callers don't need any
+/// locking of their own, and readers never see a partially written crate.
 pub trait Service: Send + Sync + 'static {
-    /// Add a new crate or version to the index. If previous versions exist, then the new version
-    /// must have a higher semver version that any other version.
-    fn add_crate(&self, req: PublishRequest, data: &[u8]) -> Result<()>;
+    /// Check whether the crate version could be added to the index with
+    /// [`add_crate`](Self::add_crate), without modifying the index.
+    fn check_crate(&self, req: &PublishRequest) -> Result<()>;
+    /// Add a new crate or version to the index. The version must not exist yet, ignoring build
+    /// metadata, but may be lower than already published versions. Fails with [`AlreadyExists`]
+    /// otherwise. If the index can't be updated, it is left unchanged. The checksum is the hex
+    /// encoded SHA-256 hash of the crate file.
+    fn add_crate(&self, req: PublishRequest, checksum: &str) -> Result<()>;
+    /// Add a release as it is, like one that was exported from another registry. Fails with
+    /// [`AlreadyExists`] like [`add_crate`](Self::add_crate).
+    fn add_release(&self, release: Release) -> Result<()>;
     /// Yank or unyank a single version of an existing crate. This means that the version will not
-    /// be available for download anymore (or be available again).
+    /// be available for download anymore (or be available again). Nothing changes if the version
+    /// is already in the requested state.
     fn yank(&self, name: CrateName, version: Version, yank: bool) -> Result<()>;
+    /// Read all releases of a crate, in the order they were published. The list is empty if the
+    /// crate doesn't exist.
+    fn read_releases(&self, name: &CrateName) -> Result<Vec<Release>>;
+    /// List the names of all crates in the index.
+    fn list_crates(&self) -> Result<Vec<CrateName>>;
+    /// Read a single release of a crate, if it exists.
+    fn read_release(&self, name: &CrateName, version: &Version) -> Result<Option<Release>>;
+    /// Read the raw index file of a crate, containing one JSON encoded [`Release`] per line, if
+    /// the crate exists.
+    fn read_crate(&self, name: &CrateName) -> Result<Option<IndexFile>>;
+}
+
+/// Raw content of a crate's index file, as served by the sparse index protocol.
+pub struct IndexFile {
+    /// Newline delimited list of JSON encoded [`Release`]s.
+    pub content: Vec<u8>,
+    /// Last time the file was changed.
+    pub modified: SystemTime,
 }
 
 /// Main implementation of the index [`Service`].
 pub struct ServiceImpl {
+    /// Base path of the repository's working tree.
+    path: PathBuf,
+    /// The repository, locked by every change to the index for its whole duration, from checking
+    /// the current content to committing the new one.
     repo: Mutex<Repository>,
 }
 
 impl ServiceImpl {
-    /// Load all information about a single crate and get the latest release.
-    fn read_latest_release(&self, crate_path: &Path) -> Result<Option<Release>> {
-        let f = match File::open(crate_path) {
+    /// Load all releases of a single crate, in the order they were published.
+    fn load_releases(&self, name: &CrateName) -> Result<Vec<Release>> {
+        let f = match File::open(self.path.join(crate_path(name))) {
             Ok(f) => f,
-            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
             Err(e) => bail!(e),
         };
 
-        let f = BufReader::new(f);
-
-        Ok(match f.lines().last() {
-            Some(line) => serde_json::from_str(&line?)?,
-            None => None,
-        })
+        BufReader::new(f)
+            .lines()
+            .map(|l| {
+                l.map_err(Into::into)
+                    .and_then(|l| serde_json::from_str(&l).map_err(Into::into))
+            })
+            .collect()
     }
 
-    /// Get the base path of the currently open repository.
-    fn repo_path(&self) -> PathBuf {
-        let repo = self.repo.lock();
-        repo.path()
-            .parent()
-            .unwrap_or_else(|| repo.path())
-            .to_owned()
-    }
+    /// Check that the version doesn't exist in the releases yet, ignoring build metadata.
+    fn check_version(releases: &[Release], name: &CrateName, version: &Version) -> Result<()> {
+        // Cargo considers versions that only differ in build metadata to be the same.
+        let exists = releases.iter().any(|r| {
+            (&r.vers.major, &r.vers.minor, &r.vers.patch, &r.vers.pre)
+                == (&version.major, &version.minor, &version.patch, &version.pre)
+        });
+
+        if exists {
+            bail!(AlreadyExists {
+                name: name.clone(),
+                version: version.clone(),
+            });
+        }
 
-    /// Add and commit a single file to the index.
-    fn commit_file(&self, path: &Path, message: &str) -> Result<()> {
-        let repo = self.repo.lock();
-        commit_file(&repo, path, message)
+        Ok(())
     }
-}
 
-impl Service for ServiceImpl {
-    #[instrument(skip_all)]
-    fn add_crate(&self, req: PublishRequest, data: &[u8]) -> Result<()> {
-        let path = crate_path(&req.name);
-        let repo_path = self.repo_path().join(&path);
+    /// Replace the releases of a crate and commit the change. If the index can't be updated, the
+    /// crate file is restored. The repository lock must be held by the caller, from loading the
+    /// releases until here.
+    fn write_releases(
+        &self,
+        repo: &Repository,
+        name: &CrateName,
+        releases: &[Release],
+        message: &str,
+    ) -> Result<()> {
+        let path = crate_path(name);
+        let repo_path = self.path.join(&path);
+
+        let previous = match fs::read(&repo_path) {
+            Ok(content) => Some(content),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => bail!(e),
+        };
 
-        if let Some(latest) = self.read_latest_release(&repo_path)? {
-            ensure!(latest.vers < req.vers, "only newer version allowed");
+        let mut content = Vec::new();
+        for release in releases {
+            serde_json::to_writer(&mut content, release)?;
+            content.push(b'\n');
         }
 
-        fs::create_dir_all(repo_path.parent().context("no parent file")?)?;
+        let result =
+            write_file(&repo_path, &content).and_then(|()| commit_file(repo, &path, message));
 
-        let mut file = BufWriter::new(
-            OpenOptions::new()
-                .write(true)
-                .create(true)
-                .append(true)
-                .open(&repo_path)?,
-        );
+        if result.is_err() {
+            // Bring the working tree back in line with the last commit, so the failed change
+            // doesn't show up in the index.
+            let restored = match previous {
+                Some(content) => write_file(&repo_path, &content),
+                None => fs::remove_file(&repo_path).map_err(Into::into),
+            };
 
-        let release = Release::from((req, data));
-        writeln!(&mut file, "{}", serde_json::to_string(&release)?)?;
+            if let Err(e) = restored {
+                error!(error = ?e, path = %repo_path.display(), "failed restoring index file");
+            }
+        }
 
-        file.flush()?;
+        result
+    }
 
-        self.commit_file(
-            &path,
-            &format!("Publish crate \"{}@{}\"", release.name, release.vers),
-        )?;
+    /// Append a new release to the index and commit it, with the action as part of the commit
+    /// message.
+    fn insert_release(&self, release: Release, action: &str) -> Result<()> {
+        let repo = self.repo.lock();
 
-        Ok(())
+        let mut releases = self.load_releases(&release.name)?;
+        Self::check_version(&releases, &release.name, &release.vers)?;
+
+        let message = format!("{action} crate \"{}@{}\"", release.name, release.vers);
+        let name = release.name.clone();
+        releases.push(release);
+
+        self.write_releases(&repo, &name, &releases, &message)
     }
+}
 
+impl Service for ServiceImpl {
     #[instrument(skip_all)]
-    fn yank(&self, name: CrateName, version: Version, yank: bool) -> Result<()> {
-        let path = crate_path(&name);
-        let repo_path = self.repo_path().join(&path);
+    fn check_crate(&self, req: &PublishRequest) -> Result<()> {
+        Self::check_version(&self.load_releases(&req.name)?, &req.name, &req.vers)
+    }
 
-        let f = BufReader::new(File::open(&repo_path)?);
+    #[instrument(skip_all)]
+    fn add_crate(&self, req: PublishRequest, checksum: &str) -> Result<()> {
+        self.insert_release(Release::from((req, checksum)), "Publish")
+    }
 
-        let mut releases = f
-            .lines()
-            .map(|l| {
-                l.map_err(Into::into)
-                    .and_then(|l| serde_json::from_str(&l).map_err(Into::into))
-            })
-            .collect::<Result<Vec<Release>>>()?;
+    #[instrument(skip_all)]
+    fn add_release(&self, release: Release) -> Result<()> {
+        self.insert_release(release, "Import")
+    }
+
+    #[instrument(skip_all)]
+    fn yank(&self, name: CrateName, version: Version, yank: bool) -> Result<()> {
+        let repo = self.repo.lock();
+
+        let mut releases = self.load_releases(&name)?;
 
-        let mut rel = releases
+        let rel = releases
             .iter_mut()
             .find(|r| r.vers == version)
             .context("version doesn't exist")?;
 
-        rel.yanked = yank;
-
-        let mut f = BufWriter::new(File::create(repo_path)?);
-        for rel in releases {
-            writeln!(&mut f, "{}", serde_json::to_string(&rel)?)?;
+        if rel.yanked == yank {
+            return Ok(());
         }
 
-        f.flush()?;
+        rel.yanked = yank;
 
-        self.commit_file(
-            &path,
+        self.write_releases(
+            &repo,
+            &name,
+            &releases,
             &format!(
                 "{} crate \"{}@{}\"",
                 if yank { "Yank" } else { "Unyank" },
                 name,
                 version
             ),
-        )?;
+        )
+    }
 
-        Ok(())
+    #[instrument(skip_all)]
+    fn read_releases(&self, name: &CrateName) -> Result<Vec<Release>> {
+        self.load_releases(name)
+    }
+
+    #[instrument(skip_all)]
+    fn list_crates(&self) -> Result<Vec<CrateName>> {
+        let base = &self.path;
+        let mut names = Vec::new();
+        let mut dirs = vec![base.clone()];
+
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let path = entry.path();
+
+                if entry.file_type()?.is_dir() {
+                    if entry.file_name() != ".git" {
+                        dirs.push(path);
+                    }
+                    continue;
+                }
+
+                // Only files at their expected location are crates, which skips the config and
+                // any other files at the root.
+                let Some(name) = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.parse::<CrateName>().ok())
+                else {
+                    continue;
+                };
+
+                if path.strip_prefix(base).ok() == Some(crate_path(&name).as_path()) {
+                    names.push(name);
+                }
+            }
+        }
+
+        names.sort();
+
+        Ok(names)
+    }
+
+    #[instrument(skip_all)]
+    fn read_release(&self, name: &CrateName, version: &Version) -> Result<Option<Release>> {
+        Ok(self
+            .load_releases(name)?
+            .into_iter()
+            .find(|r| &r.vers == version))
+    }
+
+    #[instrument(skip_all)]
+    fn read_crate(&self, name: &CrateName) -> Result<Option<IndexFile>> {
+        // Crate files are only ever replaced as a whole, so the open file is always complete and
+        // its metadata belongs to the same content.
+        let mut file = match File::open(self.path.join(crate_path(name))) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => bail!(e),
+        };
+
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        let modified = file.metadata()?.modified()?;
+
+        Ok(Some(IndexFile { content, modified }))
     }
 }
 
@@ -162,16 +305,53 @@ pub fn new(settings: &settings::Index) -> Result<impl Service> {
 
     update_config(&repo, settings)?;
 
+    let path = repo
+        .path()
+        .parent()
+        .unwrap_or_else(|| repo.path())
+        .to_owned();
+
     Ok(ServiceImpl {
+        path,
         repo: Mutex::new(repo),
     })
 }
 
+/// Exclusive lock on the index directory, released when dropped or when the process exits.
+pub struct Lock {
+    _file: File,
+}
+
+/// Take the exclusive lock on the index directory, so only a single process at a time commits to
+/// the repository. Fails right away if another process, like a running server, holds it already.
+pub fn lock(settings: &settings::Index) -> Result<Lock> {
+    fs::create_dir_all(&settings.location)?;
+
+    let path = settings.location.join(".asgard.lock");
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("failed opening lock file `{}`", path.display()))?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(Lock { _file: file }),
+        Err(TryLockError::WouldBlock) => bail!(
+            "the index at `{}` is in use by another process, like a running server",
+            settings.location.display()
+        ),
+        Err(TryLockError::Error(e)) => {
+            Err(e).with_context(|| format!("failed locking `{}`", path.display()))
+        }
+    }
+}
+
 fn update_config(repo: &Repository, settings: &settings::Index) -> Result<()> {
     let config_path = settings.location.join("config.json");
 
     let current_config = match File::open(&config_path) {
-        Ok(file) => Some(serde_json::from_reader::<_, settings::IndexConfig>(file)?),
+        Ok(file) => Some(serde_json::from_reader::<_, Config>(file)?),
         Err(e) if e.kind() == ErrorKind::NotFound => None,
         Err(e) => bail!(e),
     };
@@ -207,6 +387,19 @@ fn create_initial_commit(repo: &Repository) -> Result<()> {
     Ok(())
 }
 
+/// Replace the content of a file by writing it to a temporary file next to it first, so readers
+/// never see a partially written file.
+fn write_file(path: &Path, content: &[u8]) -> Result<()> {
+    fs::create_dir_all(path.parent().context("no parent file")?)?;
+
+    // Crate names can't contain dots, so the temporary file never collides with a crate.
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
 fn commit_file(repo: &Repository, path: impl AsRef<Path>, message: &str) -> Result<()> {
     let sig = repo.signature()?;
     let tree = {
@@ -237,16 +430,20 @@ fn commit_file(repo: &Repository, path: impl AsRef<Path>, message: &str) -> Resu
 ///   directory is the first two characters of the package name, and the next subdirectory is the
 ///   third and fourth characters of the package name. For example, `cargo` would be stored in a
 ///   file named ca/rg/cargo.
-fn crate_path(name: &CrateName) -> PathBuf {
-    let name = name.as_ref();
-    let path = match name.len() {
-        1 => PathBuf::from("1"),
-        2 => PathBuf::from("2"),
-        3 => PathBuf::from("3").join(&name[..1]),
-        _ => PathBuf::from(&name[..2]).join(&name[2..4]),
-    };
+pub fn crate_path(name: &CrateName) -> PathBuf {
+    PathBuf::from(crate_prefix(name)).join(name.as_ref())
+}
 
-    path.join(name)
+/// Directory of the crate within the index, following the rules of [`crate_path`]. This is the
+/// value of the `{prefix}` marker in the download template.
+pub fn crate_prefix(name: &CrateName) -> String {
+    let name = name.as_ref();
+    match name.len() {
+        1 => "1".to_owned(),
+        2 => "2".to_owned(),
+        3 => format!("3/{}", &name[..1]),
+        _ => format!("{}/{}", &name[..2], &name[2..4]),
+    }
 }
 
 #[cfg(test)]
@@ -281,12 +478,31 @@ mod tests {
         dir
     }
 
+    #[test]
+    fn exclusive_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = settings::Index {
+            location: dir.path().join("index"),
+            config: Config {
+                dl: "http://localhost:8080/api/v1/crates".parse().unwrap(),
+                api: "http://localhost:8080".parse().unwrap(),
+            },
+        };
+
+        let held = lock(&settings).unwrap();
+        let err = lock(&settings).err().unwrap();
+        assert!(err.to_string().contains("in use by another process"));
+
+        drop(held);
+        lock(&settings).unwrap();
+    }
+
     #[test]
     fn service_roundtrip() {
         let dir = create_repo();
         let settings = settings::Index {
             location: dir.path().to_owned(),
-            config: settings::IndexConfig {
+            config: Config {
                 dl: "http://localhost:8080/api/v1/crates".parse().unwrap(),
                 api: "http://localhost:8080".parse().unwrap(),
             },
@@ -296,20 +512,142 @@ mod tests {
         service
             .add_crate(
                 PublishRequest::new("test".parse().unwrap(), "1.0.0".parse().unwrap()),
-                &[],
+                "abc",
             )
             .unwrap();
 
         service
             .add_crate(
                 PublishRequest::new("test".parse().unwrap(), "1.1.0".parse().unwrap()),
-                &[1, 2, 3],
+                "def",
+            )
+            .unwrap();
+
+        assert!(service
+            .check_crate(&PublishRequest::new(
+                "test".parse().unwrap(),
+                "1.1.0+build".parse().unwrap()
+            ))
+            .unwrap_err()
+            .is::<AlreadyExists>());
+        assert!(service
+            .check_crate(&PublishRequest::new(
+                "test".parse().unwrap(),
+                "1.2.0".parse().unwrap()
+            ))
+            .is_ok());
+
+        service
+            .add_crate(
+                PublishRequest::new("test".parse().unwrap(), "1.0.5".parse().unwrap()),
+                "abc",
             )
             .unwrap();
 
         service
             .yank("test".parse().unwrap(), "1.0.0".parse().unwrap(), true)
             .unwrap();
+        service
+            .yank("test".parse().unwrap(), "1.0.0".parse().unwrap(), true)
+            .unwrap();
+        assert!(service
+            .yank("test".parse().unwrap(), "3.0.0".parse().unwrap(), true)
+            .is_err());
+        assert!(
+            service
+                .read_release(&"test".parse().unwrap(), &"1.0.0".parse().unwrap())
+                .unwrap()
+                .unwrap()
+                .yanked
+        );
+
+        let file = service
+            .read_crate(&"test".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            3,
+            file.content
+                .split(|&b| b == b'\n')
+                .filter(|l| !l.is_empty())
+                .count()
+        );
+
+        assert!(service
+            .read_crate(&"other".parse().unwrap())
+            .unwrap()
+            .is_none());
+
+        service
+            .add_crate(
+                PublishRequest::new("a".parse().unwrap(), "0.1.0".parse().unwrap()),
+                "abc",
+            )
+            .unwrap();
+
+        assert_eq!(
+            vec!["a".parse::<CrateName>().unwrap(), "test".parse().unwrap()],
+            service.list_crates().unwrap()
+        );
+        assert_eq!(
+            3,
+            service
+                .read_releases(&"test".parse().unwrap())
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[test]
+    fn concurrent_writers() {
+        let dir = create_repo();
+        let settings = settings::Index {
+            location: dir.path().to_owned(),
+            config: Config {
+                dl: "http://localhost:8080/api/v1/crates".parse().unwrap(),
+                api: "http://localhost:8080".parse().unwrap(),
+            },
+        };
+        let service = new(&settings).unwrap();
+        let name = "test".parse::<CrateName>().unwrap();
+
+        service
+            .add_crate(
+                PublishRequest::new(name.clone(), "0.1.0".parse().unwrap()),
+                "abc",
+            )
+            .unwrap();
+
+        std::thread::scope(|s| {
+            for i in 1..=8 {
+                let (service, name) = (&service, name.clone());
+                s.spawn(move || {
+                    service
+                        .add_crate(
+                            PublishRequest::new(name.clone(), format!("1.{i}.0").parse().unwrap()),
+                            "abc",
+                        )
+                        .unwrap();
+                    service
+                        .yank(name, "0.1.0".parse().unwrap(), i % 2 == 0)
+                        .unwrap();
+                });
+            }
+        });
+
+        let releases = service.read_releases(&name).unwrap();
+        assert_eq!(9, releases.len());
+        assert_eq!(
+            9,
+            service
+                .read_crate(&name)
+                .unwrap()
+                .unwrap()
+                .content
+                .split(|&b| b == b'\n')
+                .filter(|l| !l.is_empty())
+                .count()
+        );
     }
 
     #[test]
@@ -4,7 +4,6 @@ use std::collections::{BTreeMap, BTreeSet};
 
 use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 use url::Url;
 
 use crate::{
@@ -15,17 +14,20 @@ use crate::{
 /// The configuration of the index. This structure is placed as a JSON file with the name
 /// `config.json` at the root of the index repository. It tells cargo where to find the crate
 /// repository's API and where to download the crate data.
-#[derive(Serialize, Deserialize)]
+///
+/// The values are kept as plain strings, as cargo appends paths to them verbatim and a [`Url`]
+/// would add a trailing slash.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct Config {
     /// Download path for crate packages.
-    pub dl: Url,
+    pub dl: String,
     /// Main API endpoint for the crate repository.
-    pub api: Url,
+    pub api: String,
 }
 
 /// A single release of a crate. It describes all basic information about a crate release and is
 /// stored within the index.
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Release {
     /// Name of the crate.
     pub name: CrateName,
@@ -37,29 +39,55 @@ pub struct Release {
     pub cksum: String,
     /// List of features that the crate supports.
     pub features: BTreeMap<String, BTreeSet<String>>,
+    /// Features that use the `dep:` or `pkg?/feat` syntax, which cargo versions before 1.60 can't
+    /// parse. Newer versions merge them with the `features`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub features2: Option<BTreeMap<String, BTreeSet<String>>>,
     /// Whether this release is yanked (disabled for download).
     pub yanked: bool,
     /// Linking value that is passed to the compiler to link in external libraries.
     pub links: Option<String>,
+    /// Version of the entry format. Set to `2` if `features2` is present, so older cargo versions
+    /// skip the release.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<u32>,
+    /// Minimum supported Rust version, used by the resolver to pick compatible releases.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rust_version: Option<String>,
 }
 
-impl From<(PublishRequest, &[u8])> for Release {
-    fn from((p, d): (PublishRequest, &[u8])) -> Self {
+impl From<(PublishRequest, &str)> for Release {
+    fn from((p, cksum): (PublishRequest, &str)) -> Self {
+        let (features2, features) = p
+            .features
+            .into_iter()
+            .partition::<BTreeMap<_, _>, _>(|(_, values)| values.iter().any(|v| is_feature2(v)));
+        let features2 = (!features2.is_empty()).then_some(features2);
+
         Self {
             name: p.name,
             vers: p.vers,
             deps: p.deps.into_iter().map(Into::into).collect(),
-            cksum: hex::encode(Sha256::digest(d)),
-            features: p.features,
+            cksum: cksum.to_owned(),
+            features,
+            v: features2.as_ref().map(|_| 2),
+            features2,
             yanked: false,
             links: p.links,
+            rust_version: p.rust_version,
         }
     }
 }
 
+/// Whether the feature value uses the syntax introduced with cargo 1.60, namely `dep:pkg` for
+/// optional dependencies and `pkg?/feat` for weak dependency features.
+fn is_feature2(value: &str) -> bool {
+    value.starts_with("dep:") || value.contains("?/")
+}
+
 /// A dependency describes the reference from a crate [`Release`] to another existing crate that it
 /// uses.
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Dependency {
     /// Name of this dependency. Not a [`CrateName`] as this crate could come from another registry
     /// with different naming restrictions.
@@ -83,9 +111,17 @@ pub struct Dependency {
 }
 
 impl From<ApiDependency> for Dependency {
+    /// The API names dependencies by their crate, with the name in the manifest in
+    /// `explicit_name_in_toml` if it was renamed. The index uses the renamed name instead, with the
+    /// crate in `package`.
     fn from(d: ApiDependency) -> Self {
+        let (name, package) = match d.explicit_name_in_toml {
+            Some(renamed) => (renamed, Some(d.name)),
+            None => (d.name, None),
+        };
+
         Self {
-            name: d.name,
+            name,
             req: d.version_req,
             features: d.features,
             optional: d.optional,
@@ -93,14 +129,14 @@ impl From<ApiDependency> for Dependency {
             target: d.target,
             kind: d.kind.into(),
             registry: d.registry,
-            package: d.explicit_name_in_toml,
+            package,
         }
     }
 }
 
 /// Different kinds of dependencies. This means in what stage of the build process a dependency is
 /// needed.
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum Kind {
     /// Only during development like tests, benchmarks or examples.
@@ -121,6 +157,16 @@ impl From<ApiKind> for Kind {
     }
 }
 
+impl From<&Kind> for ApiKind {
+    fn from(k: &Kind) -> Self {
+        match k {
+            Kind::Dev => Self::Dev,
+            Kind::Build => Self::Build,
+            Kind::Normal => Self::Normal,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use maplit::{btreemap, btreeset};
@@ -132,8 +178,8 @@ mod tests {
         println!(
             "{}",
             serde_json::to_string_pretty(&Config {
-                dl: "https://crates.io/api/v1/crates".parse().unwrap(),
-                api: "https://crates.io".parse().unwrap(),
+                dl: "https://crates.io/api/v1/crates".to_owned(),
+                api: "https://crates.io".to_owned(),
             })
             .unwrap()
         );
@@ -162,10 +208,56 @@ mod tests {
                 features: btreemap! {
                     "extras".to_owned() => btreeset!["rand/simd_support".to_owned()],
                 },
+                features2: None,
                 yanked: false,
                 links: None,
+                v: None,
+                rust_version: None,
             })
             .unwrap()
         );
     }
+
+    #[test]
+    fn split_features2() {
+        let mut req = PublishRequest::new("foo".parse().unwrap(), "1.0.0".parse().unwrap());
+        req.rust_version = Some("1.60".to_owned());
+        req.features = btreemap! {
+            "default".to_owned() => btreeset!["std".to_owned()],
+            "std".to_owned() => btreeset!["serde/std".to_owned()],
+            "serde".to_owned() => btreeset!["dep:serde".to_owned()],
+            "weak".to_owned() => btreeset!["rand?/std".to_owned(), "std".to_owned()],
+        };
+
+        let release = Release::from((req.clone(), "abc"));
+        let json = serde_json::to_value(&release).unwrap();
+
+        assert_eq!(
+            serde_json::json!({
+                "default": ["std"],
+                "std": ["serde/std"],
+            }),
+            json["features"]
+        );
+        assert_eq!(
+            serde_json::json!({
+                "serde": ["dep:serde"],
+                "weak": ["rand?/std", "std"],
+            }),
+            json["features2"]
+        );
+        assert_eq!(2, json["v"]);
+        assert_eq!("1.60", json["rust_version"]);
+
+        // Releases with only old-style features keep the original format.
+        req.features
+            .retain(|name, _| name == "default" || name == "std");
+        req.rust_version = None;
+        let json = serde_json::to_value(Release::from((req, "abc"))).unwrap();
+        let object = json.as_object().unwrap();
+
+        assert!(!object.contains_key("features2"));
+        assert!(!object.contains_key("v"));
+        assert!(!object.contains_key("rust_version"));
+    }
 }
@@ -1,9 +1,15 @@
 #![forbid(unsafe_code)]
 #![deny(rust_2018_idioms, clippy::all)]
 
-use std::sync::Arc;
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter},
+    path::Path,
+    sync::Arc,
+};
 
-use anyhow::Result;
+use anyhow::{bail, ensure, Context, Result};
 use opentelemetry::{
     global, runtime,
     sdk::{trace, Resource},
@@ -11,19 +17,28 @@ use opentelemetry::{
 use opentelemetry_otlp::WithExportConfig;
 use opentelemetry_semantic_conventions::resource;
 use settings::{Settings, Tracing};
-use tokio::sync::Mutex;
-use tracing::error;
+use tracing::{error, info, warn};
 use tracing_subscriber::{fmt::format::FmtSpan, prelude::*, EnvFilter};
 use warp::Filter;
 
 mod api;
+mod auth;
+mod bundle;
+mod crypto;
 mod db;
+mod git;
+mod http;
 mod index;
+mod markdown;
 mod models;
+mod oidc;
+mod package;
 mod settings;
+mod sparse;
 mod storage;
 mod templates;
 mod ui;
+mod upstream;
 
 #[cfg(debug_assertions)]
 const ADDRESS: [u8; 4] = [127, 0, 0, 1];
@@ -89,12 +104,146 @@ async fn main() -> Result<()> {
         .transpose()?;
 
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer().with_span_events(FmtSpan::CLOSE))
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_span_events(FmtSpan::CLOSE)
+                .with_writer(std::io::stderr),
+        )
         .with(opentelemetry)
         .with(EnvFilter::builder().parse("info,asgard=trace,warp=debug")?)
         .init();
 
-    launch_warp(settings).await
+    let mut args = env::args().skip(1);
+    match args.next().as_deref() {
+        None => launch_warp(settings).await,
+        Some("token") => {
+            let user = args.next().context("missing user name")?;
+            let name = args.next().unwrap_or_else(|| "cli".to_owned());
+            create_token(&user, &name)
+        }
+        Some("passwd") => {
+            let user = args.next().context("missing user name")?;
+            set_password(&user)
+        }
+        Some("export") => {
+            let path = args.next().context("missing bundle path")?;
+            let selection = args.map(|s| s.parse()).collect::<Result<Vec<_>>>()?;
+            ensure!(!selection.is_empty(), "missing crates to export");
+            export_bundle(&settings, path.as_ref(), &selection).await
+        }
+        Some("import") => {
+            let path = args.next().context("missing bundle path")?;
+            import_bundle(&settings, path.as_ref()).await
+        }
+        Some(cmd) => bail!("unknown command `{cmd}`"),
+    }
+}
+
+/// Create a new API token for the given user (creating the user if needed) and print it.
+fn create_token(user: &str, name: &str) -> Result<()> {
+    let pool = db::create_pool()?;
+    let mut conn = pool.get()?;
+    db::run_migrations(&mut conn)?;
+
+    let user = db::users::get_or_create(&conn, user)?;
+    let token = db::tokens::create(&conn, &user, name)?;
+
+    println!("{token}");
+
+    Ok(())
+}
+
+/// Set the web UI password of the given user (creating the user if needed), reading it from the
+/// first line of the standard input.
+fn set_password(user: &str) -> Result<()> {
+    let mut password = String::new();
+    io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    ensure!(!password.is_empty(), "password must not be empty");
+
+    let pool = db::create_pool()?;
+    let mut conn = pool.get()?;
+    db::run_migrations(&mut conn)?;
+
+    let user = db::users::get_or_create(&conn, user)?;
+    db::users::set_password(&conn, &user, password)?;
+
+    Ok(())
+}
+
+/// Write the selected crates and their dependencies into a bundle, for importing them into a
+/// registry without network access.
+async fn export_bundle(
+    settings: &Settings,
+    path: &Path,
+    selection: &[bundle::Selection],
+) -> Result<()> {
+    let index = index::new(&settings.index)?;
+    let storage = storage::new(&settings.storage)?;
+
+    let file = File::create(path)
+        .with_context(|| format!("failed creating bundle `{}`", path.display()))?;
+    let exported = match bundle::export(&index, &storage, selection, BufWriter::new(file)).await {
+        Ok(exported) => exported,
+        Err(e) => {
+            fs::remove_file(path).ok();
+            return Err(e);
+        }
+    };
+
+    for dependency in &exported.external {
+        warn!(%dependency, "dependency from another registry is not part of the bundle");
+    }
+
+    println!("exported {} releases", exported.releases);
+
+    Ok(())
+}
+
+/// Add all releases from a bundle to the index, storage and database. Fails if the server is
+/// running at the same time, as both would be committing to the index repository.
+async fn import_bundle(settings: &Settings, path: &Path) -> Result<()> {
+    let _lock = index::lock(&settings.index)?;
+
+    let pool = db::create_pool()?;
+    db::run_migrations(&mut *pool.get()?)?;
+
+    let index = index::new(&settings.index)?;
+    let storage = storage::new(&settings.storage)?;
+
+    let file =
+        File::open(path).with_context(|| format!("failed opening bundle `{}`", path.display()))?;
+    let imported = bundle::import(&index, &storage, &pool, BufReader::new(file)).await?;
+
+    println!(
+        "imported {} releases, skipped {} existing ones",
+        imported.added, imported.skipped
+    );
+
+    Ok(())
+}
+
+/// Record the crates from the index in the database that it doesn't know about yet, like the ones
+/// published before the database was introduced.
+fn backfill_crates(index: &impl index::Service, conn: &mut rusqlite::Connection) -> Result<()> {
+    let mut crates = Vec::new();
+    for name in index.list_crates()? {
+        let max_version = index
+            .read_releases(&name)?
+            .into_iter()
+            .map(|r| r.vers)
+            .max();
+        if let Some(max_version) = max_version {
+            crates.push((name, max_version));
+        }
+    }
+
+    let added = db::crates::backfill(conn, crates)?;
+    if added > 0 {
+        info!(added, "recorded crates from the index in the database");
+    }
+
+    Ok(())
 }
 
 // async fn launch_rocket() -> Result<()> {
@@ -105,13 +254,55 @@ async fn main() -> Result<()> {
 // }
 
 async fn launch_warp(settings: Settings) -> Result<()> {
+    // Held while serving, keeping bundle imports from committing to the index at the same time.
+    let _lock = index::lock(&settings.index)?;
+
     let pool = db::create_pool()?;
-    db::run_migrations(pool.get()?)?;
+    db::run_migrations(&mut *pool.get()?)?;
 
     let index = Arc::new(index::new(&settings.index)?);
-    let storage = Arc::new(Mutex::new(storage::new(&settings.storage.location)));
+    backfill_crates(&*index, &mut *pool.get()?)?;
+
+    let storage = Arc::new(storage::new(&settings.storage)?);
+    let auth = Arc::new(auth::new(settings.auth.as_ref(), pool.clone())?);
+
+    let dl = index::dl::Template::parse(&settings.index.config.dl)?;
+
+    let oidc = match &settings.oidc {
+        Some(oidc) => Some(Arc::<dyn oidc::Provider>::from(oidc::new(oidc).await?)),
+        None => None,
+    };
+
+    // Browsers drop secure cookies that are set over plain `http`.
+    let cookies = ui::Cookies {
+        secure: settings.index.config.api.starts_with("https://"),
+    };
+
+    let upstream = match &settings.upstream {
+        Some(upstream) => Some(Arc::new(upstream::new(
+            upstream,
+            Arc::clone(&storage),
+            pool.clone(),
+        )?) as Arc<dyn upstream::Service>),
+        None => None,
+    };
 
-    let routes = api::filters::api(index, storage).or(ui::filters::ui());
+    let routes = api::filters::api(
+        Arc::clone(&index),
+        storage,
+        auth,
+        pool.clone(),
+        dl,
+        settings.publish,
+        upstream.clone(),
+    )
+    .or(sparse::filters::sparse(
+        index,
+        settings.index.config,
+        upstream,
+    ))
+    .or(git::filters::git(settings.index.location))
+    .or(ui::filters::ui(pool, oidc, cookies));
 
     warp::serve(routes).run((ADDRESS, settings.port)).await;
 
@@ -0,0 +1,235 @@
+//! Rendering of crate READMEs from Markdown to HTML for the web UI.
+//!
+//! Documents are parsed with [`pulldown_cmark`], following [CommonMark](https://commonmark.org)
+//! and the GitHub flavored extensions for tables and strikethrough. The resulting HTML is cleaned
+//! with [`ammonia`], so it is safe to embed into a page. Only a known set of harmless tags and
+//! attributes is kept, and links or images can only point to relative locations or `http`, `https`
+//! and `mailto` URLs.
+//!
+//! Rendering takes time proportional to the document size, so it shouldn't be done on the async
+//! runtime directly.
+
+use std::collections::HashSet;
+
+use ammonia::{Builder, UrlRelative};
+use pulldown_cmark::{html, Event, Options, Parser};
+
+/// Largest document that is rendered, in bytes. Anything bigger is shown as plain text.
+const MAX_SIZE: usize = 512 * 1024;
+
+/// Deepest nesting of elements that is kept. Elements nested deeper are dropped, but their text
+/// is still shown.
+const MAX_DEPTH: usize = 32;
+
+/// Render a Markdown document to sanitized HTML.
+pub fn render(input: &str) -> String {
+    if input.len() > MAX_SIZE {
+        return plain(input);
+    }
+
+    let mut depth = 0;
+    let events = Parser::new_ext(
+        input,
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH,
+    )
+    .filter(|event| match event {
+        Event::Start(_) => {
+            depth += 1;
+            depth <= MAX_DEPTH
+        }
+        Event::End(_) => {
+            depth -= 1;
+            depth < MAX_DEPTH
+        }
+        _ => true,
+    });
+
+    let mut out = String::new();
+    html::push_html(&mut out, events);
+
+    sanitizer().clean(&out).to_string()
+}
+
+/// Show the document as is, without interpreting any Markdown.
+fn plain(input: &str) -> String {
+    format!("<pre><code>{}</code></pre>\n", ammonia::clean_text(input))
+}
+
+/// Create the HTML sanitizer, that keeps the formatting of rendered Markdown, like syntax
+/// highlighting hints of code blocks and the alignment of table cells.
+fn sanitizer() -> Builder<'static> {
+    let mut builder = Builder::default();
+    builder
+        .url_schemes(HashSet::from(["http", "https", "mailto"]))
+        .url_relative(UrlRelative::PassThrough)
+        .link_rel(Some("nofollow noopener"))
+        .add_tag_attributes("code", ["class"])
+        .add_tag_attributes("th", ["style"])
+        .add_tag_attributes("td", ["style"])
+        .attribute_filter(|element, attribute, value| match (element, attribute) {
+            ("code", "class") => {
+                let language = value.strip_prefix("language-")?;
+                language
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'+'))
+                    .then_some(value.into())
+            }
+            ("th" | "td", "style") => matches!(
+                value,
+                "text-align: left" | "text-align: center" | "text-align: right"
+            )
+            .then_some(value.into()),
+            _ => Some(value.into()),
+        });
+
+    builder
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn blocks() {
+        let input =
+            "# Title\n\nSome *text*\nwith **bold** and `code`.\n\n- one\n- two\n  - nested\n\n\
+            1. first\n2. second\n\n> quote\n\n---\n\n```rust\nfn main() {}\n```\n";
+
+        assert_eq!(
+            "<h1>Title</h1>\n\
+            <p>Some <em>text</em>\nwith <strong>bold</strong> and <code>code</code>.</p>\n\
+            <ul>\n<li>one</li>\n<li>two\n<ul>\n<li>nested</li>\n</ul>\n</li>\n</ul>\n\
+            <ol>\n<li>first</li>\n<li>second</li>\n</ol>\n\
+            <blockquote>\n<p>quote</p>\n</blockquote>\n\
+            <hr>\n\
+            <pre><code class=\"language-rust\">fn main() {}\n</code></pre>\n",
+            render(input)
+        );
+    }
+
+    #[test]
+    fn links_and_images() {
+        let input = "[![CI](https://ci.example.com/badge.svg)][ci] and [docs](https://docs.rs \"Docs\")\n\n\
+            [ci]: https://ci.example.com\n";
+
+        assert_eq!(
+            "<p><a href=\"https://ci.example.com\" rel=\"nofollow noopener\">\
+            <img src=\"https://ci.example.com/badge.svg\" alt=\"CI\"></a> and \
+            <a href=\"https://docs.rs\" title=\"Docs\" rel=\"nofollow noopener\">docs</a></p>\n",
+            render(input)
+        );
+    }
+
+    #[test]
+    fn table() {
+        let input = "| Name | Value |\n|:-----|------:|\n| a | `1` |\n";
+
+        assert_eq!(
+            "<table><thead><tr>\
+            <th style=\"text-align: left\">Name</th><th style=\"text-align: right\">Value</th>\
+            </tr></thead><tbody>\n\
+            <tr><td style=\"text-align: left\">a</td><td style=\"text-align: right\"><code>1</code></td></tr>\n\
+            </tbody></table>\n",
+            render(input)
+        );
+    }
+
+    #[test]
+    fn sanitize() {
+        assert_eq!("", render("<script>alert(\"x\")</script>"));
+        assert_eq!(
+            "<p><b>bold</b> text</p>\n",
+            render("<b onclick=\"alert(1)\">bold</b> text")
+        );
+        assert_eq!(
+            "<p><a rel=\"nofollow noopener\">click</a></p>\n",
+            render("[click](javascript:alert(1))")
+        );
+        assert_eq!(
+            "<p><a rel=\"nofollow noopener\">javascript:alert(1)</a></p>\n",
+            render("<javascript:alert(1)>")
+        );
+        assert_eq!(
+            "<p><img alt=\"img\"></p>\n",
+            render("![img](data:image/png;base64,AAAA)")
+        );
+        assert_eq!(
+            "<pre><code>fn main() {}\n</code></pre>\n",
+            render("```\" onclick=\"x\nfn main() {}\n```")
+        );
+        assert_eq!(
+            "<table><tbody><tr><th>a</th></tr></tbody></table>",
+            render("<table><tr><th style=\"color: red\">a</th></tr></table>")
+        );
+        assert_eq!("<pre><code>&lt;b&gt;\n</code></pre>\n", render("    <b>\n"));
+    }
+
+    #[test]
+    fn malformed_input() {
+        for input in [
+            "[",
+            "![",
+            "[a](",
+            "[a](<b",
+            "`",
+            "**",
+            "*a",
+            "_a_b",
+            "~~",
+            "<",
+            "<a",
+            "\\",
+            "- ",
+            "1.",
+            "1)",
+            "#",
+            "|",
+            "|a|\n|-|",
+            "```",
+            "> ",
+            "    ",
+            "\u{3000}- a",
+            "[a]: ",
+            "* * *",
+            "- a\n\n\n  b",
+            "*\u{e9}*\u{e9}_\u{e9}_",
+        ] {
+            render(input);
+        }
+    }
+
+    #[test]
+    fn deep_nesting() {
+        for input in [
+            ">".repeat(10_000),
+            "- ".repeat(10_000),
+            "*a ".repeat(10_000),
+        ] {
+            let html = render(&input);
+            assert!(html.matches("<blockquote>").count() <= MAX_DEPTH);
+            assert!(html.matches("<ul>").count() <= MAX_DEPTH);
+        }
+
+        let html = render(&format!("{}text", "> ".repeat(1000)));
+        assert_eq!(MAX_DEPTH, html.matches("<blockquote>").count());
+        assert!(html.contains("text"));
+    }
+
+    #[test]
+    fn unmatched_brackets() {
+        for input in ["[a](", "![", "[", "[a][", "[a](<"].map(|s| s.repeat(100_000)) {
+            let start = Instant::now();
+            render(&input);
+            assert!(start.elapsed() < Duration::from_secs(5));
+        }
+    }
+
+    #[test]
+    fn oversized() {
+        let input = format!("<b>{}</b>", "a".repeat(MAX_SIZE));
+        let html = render(&input);
+        assert!(html.starts_with("<pre><code>&lt;b&gt;aaa"));
+    }
+}
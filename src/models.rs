@@ -5,9 +5,10 @@ use std::{
 
 use anyhow::ensure;
 use derive_more::Display;
+use semver::Version;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Display, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Display, Serialize, Deserialize)]
 #[serde(try_from = "String")]
 pub struct CrateName(String);
 
@@ -17,7 +18,11 @@ impl TryFrom<String> for CrateName {
     fn try_from(value: String) -> Result<Self, Self::Error> {
         ensure!(
             !value.is_empty()
-                && ('a'..='z').contains(&value.chars().next().unwrap_or_default())
+                && value
+                    .chars()
+                    .next()
+                    .unwrap_or_default()
+                    .is_ascii_lowercase()
                 && value
                     .chars()
                     .all(|c| matches!(c, '0'..='9' | 'a'..='z' | '-' | '_')),
@@ -41,3 +46,12 @@ impl AsRef<str> for CrateName {
         &self.0
     }
 }
+
+/// Error returned when trying to publish a crate version that already exists. Published versions
+/// are immutable and must never be replaced.
+#[derive(Debug, thiserror::Error)]
+#[error("crate {name} with version {version} already exists")]
+pub struct AlreadyExists {
+    pub name: CrateName,
+    pub version: Version,
+}
@@ -0,0 +1,754 @@
+//! Login through an external identity provider with [OpenID Connect](https://openid.net/connect/),
+//! using the authorization code flow with PKCE.
+//!
+//! The signature of the ID token is verified against the provider's published keys, which may be
+//! RSA (`RS256`) or P-256 (`ES256`) keys. Providers should be reached over `https`, otherwise the
+//! keys themselves could be replaced on the way.
+
+use anyhow::{bail, ensure, Context, Result};
+use async_trait::async_trait;
+use hyper::{
+    header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE},
+    Body, Method, Request,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use tracing::instrument;
+use url::{form_urlencoded, Url};
+
+use crate::{
+    http::{self, HttpClient},
+    settings,
+};
+
+/// Amount of random characters in each value of a [`LoginState`].
+const STATE_LENGTH: usize = 32;
+
+/// Identity of a user as confirmed by the provider.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Identity {
+    /// Issuer URL of the provider.
+    pub issuer: String,
+    /// Stable identifier of the account at the provider.
+    pub subject: String,
+    /// Name of the registry user, taken from the configured claim.
+    pub name: String,
+}
+
+/// Random values of a single login attempt. They are kept in a short-lived cookie between
+/// redirecting the user to the provider and handling the callback.
+#[derive(Debug, PartialEq, Eq)]
+pub struct LoginState {
+    /// Returned by the provider with the callback, protecting against forged requests.
+    pub state: String,
+    /// Included in the ID token, protecting against replayed tokens.
+    pub nonce: String,
+    /// Secret of the PKCE code challenge.
+    pub verifier: String,
+}
+
+impl LoginState {
+    pub fn generate() -> Self {
+        let random = || {
+            rand::thread_rng()
+                .sample_iter(Alphanumeric)
+                .take(STATE_LENGTH)
+                .map(char::from)
+                .collect()
+        };
+
+        Self {
+            state: random(),
+            nonce: random(),
+            verifier: random(),
+        }
+    }
+
+    /// Encode as cookie value.
+    pub fn encode(&self) -> String {
+        format!("{}.{}.{}", self.state, self.nonce, self.verifier)
+    }
+
+    /// Decode from a cookie value, as created by [`encode`](Self::encode).
+    pub fn decode(value: &str) -> Option<Self> {
+        let mut parts = value.split('.');
+        let (Some(state), Some(nonce), Some(verifier), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return None;
+        };
+
+        Some(Self {
+            state: state.to_owned(),
+            nonce: nonce.to_owned(),
+            verifier: verifier.to_owned(),
+        })
+    }
+
+    /// The PKCE code challenge that is derived from the verifier.
+    fn challenge(&self) -> String {
+        base64::encode_config(Sha256::digest(&self.verifier), base64::URL_SAFE_NO_PAD)
+    }
+}
+
+/// A provider that users can log into the web UI with.
+#[async_trait]
+pub trait Provider: Send + Sync + 'static {
+    /// Name of the provider, shown on the login page.
+    fn name(&self) -> &str;
+    /// URL of the provider's login page that the user is redirected to.
+    fn authorize_url(&self, login: &LoginState) -> String;
+    /// Exchange the authorization code from the login callback for the identity of the user.
+    async fn authenticate(&self, code: &str, login: &LoginState) -> Result<Identity>;
+}
+
+/// Provider metadata from the discovery document, as far as needed for the login.
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: Url,
+    token_endpoint: Url,
+    jwks_uri: Url,
+    #[serde(default)]
+    userinfo_endpoint: Option<Url>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+    access_token: String,
+}
+
+/// Claims of the ID token that are validated, plus all others for the user name.
+#[derive(Deserialize)]
+struct IdClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    #[serde(default)]
+    nonce: Option<String>,
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Self::Single(aud) => aud == client_id,
+            Self::Multiple(aud) => aud.iter().any(|a| a == client_id),
+        }
+    }
+}
+
+/// Header of the ID token, naming the algorithm and key of its signature.
+#[derive(Deserialize)]
+struct IdHeader {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+/// Public keys of the provider, that ID tokens are signed with.
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+/// A single public key, as defined in [RFC 7517](https://www.rfc-editor.org/rfc/rfc7517).
+#[derive(Deserialize)]
+struct Jwk {
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(flatten)]
+    key: JwkKey,
+}
+
+/// Parameters of a public key, with all values base64url encoded.
+#[derive(Deserialize)]
+#[serde(tag = "kty")]
+enum JwkKey {
+    #[serde(rename = "RSA")]
+    Rsa { n: String, e: String },
+    #[serde(rename = "EC")]
+    Ec { crv: String, x: String, y: String },
+    /// Key types that aren't supported, like symmetric keys.
+    #[serde(other)]
+    Other,
+}
+
+impl Jwk {
+    /// Verify the signature of the message with this key. Fails if the key doesn't fit the
+    /// algorithm.
+    fn verify(&self, alg: &str, message: &[u8], sig: &[u8]) -> bool {
+        let decode = |value: &str| base64::decode_config(value, base64::URL_SAFE_NO_PAD).ok();
+
+        match (&self.key, alg) {
+            (JwkKey::Rsa { n, e }, "RS256") => {
+                let (Some(n), Some(e)) = (decode(n), decode(e)) else {
+                    return false;
+                };
+
+                RsaPublicKeyComponents { n, e }
+                    .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, sig)
+                    .is_ok()
+            }
+            (JwkKey::Ec { crv, x, y }, "ES256") if crv == "P-256" => {
+                let (Some(x), Some(y)) = (decode(x), decode(y)) else {
+                    return false;
+                };
+
+                // Uncompressed form of the curve point.
+                let point = [&[4][..], &x, &y].concat();
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, sig)
+                    .is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Generic OpenID Connect [`Provider`].
+struct ProviderImpl {
+    client: HttpClient,
+    name: String,
+    client_id: String,
+    client_secret: String,
+    redirect_url: String,
+    scope: String,
+    username_claim: String,
+    discovery: Discovery,
+}
+
+#[async_trait]
+impl Provider for ProviderImpl {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn authorize_url(&self, login: &LoginState) -> String {
+        let mut url = self.discovery.authorization_endpoint.clone();
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair("scope", &self.scope)
+            .append_pair("state", &login.state)
+            .append_pair("nonce", &login.nonce)
+            .append_pair("code_challenge", &login.challenge())
+            .append_pair("code_challenge_method", "S256");
+
+        url.into()
+    }
+
+    #[instrument(skip_all)]
+    async fn authenticate(&self, code: &str, login: &LoginState) -> Result<Identity> {
+        let body = form_urlencoded::Serializer::new(String::new())
+            .append_pair("grant_type", "authorization_code")
+            .append_pair("code", code)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair("code_verifier", &login.verifier)
+            .finish();
+
+        let tokens = self
+            .send::<TokenResponse>(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(self.discovery.token_endpoint.as_str())
+                    .header(AUTHORIZATION, self.basic_auth())
+                    .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                    .body(body.into())?,
+            )
+            .await
+            .context("failed requesting the ID token")?;
+
+        // Keys are loaded for every login, so rotated keys are picked up right away.
+        let keys = self
+            .send::<JwkSet>(
+                Request::builder()
+                    .uri(self.discovery.jwks_uri.as_str())
+                    .body(Body::empty())?,
+            )
+            .await
+            .context("failed requesting the provider keys")?;
+
+        let claims = decode_id_token(&tokens.id_token, &keys)?;
+
+        ensure!(
+            claims.iss == self.discovery.issuer,
+            "ID token was issued by `{}`",
+            claims.iss
+        );
+        ensure!(
+            claims.aud.contains(&self.client_id),
+            "ID token was issued for another client"
+        );
+        ensure!(
+            claims.exp > OffsetDateTime::now_utc().unix_timestamp(),
+            "ID token is expired"
+        );
+        ensure!(
+            claims.nonce.as_deref() == Some(login.nonce.as_str()),
+            "ID token belongs to another login"
+        );
+
+        let name = match self.claim(&claims.sub, &claims.other) {
+            Some(name) => name,
+            None => self.userinfo(&claims.sub, &tokens.access_token).await?,
+        };
+        ensure!(
+            !name.trim().is_empty(),
+            "claim `{}` is empty",
+            self.username_claim
+        );
+
+        Ok(Identity {
+            issuer: claims.iss,
+            subject: claims.sub,
+            name,
+        })
+    }
+}
+
+impl ProviderImpl {
+    /// Client credentials for the token endpoint, in the `client_secret_basic` form.
+    fn basic_auth(&self) -> String {
+        let encode =
+            |value: &str| form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>();
+        let credentials = format!(
+            "{}:{}",
+            encode(&self.client_id),
+            encode(&self.client_secret)
+        );
+
+        format!("Basic {}", base64::encode(credentials))
+    }
+
+    /// Value of the configured user name claim.
+    fn claim(&self, sub: &str, claims: &Map<String, Value>) -> Option<String> {
+        if self.username_claim == "sub" {
+            return Some(sub.to_owned());
+        }
+
+        claims
+            .get(&self.username_claim)
+            .and_then(Value::as_str)
+            .map(ToOwned::to_owned)
+    }
+
+    /// Load the user name claim from the user info endpoint, for providers that don't include it
+    /// in the ID token.
+    async fn userinfo(&self, sub: &str, access_token: &str) -> Result<String> {
+        let Some(endpoint) = &self.discovery.userinfo_endpoint else {
+            bail!("ID token is missing the `{}` claim", self.username_claim);
+        };
+
+        let mut claims = self
+            .send::<Map<String, Value>>(
+                Request::builder()
+                    .uri(endpoint.as_str())
+                    .header(AUTHORIZATION, format!("Bearer {access_token}"))
+                    .body(Body::empty())?,
+            )
+            .await
+            .context("failed requesting the user info")?;
+
+        ensure!(
+            claims.remove("sub").as_ref().and_then(Value::as_str) == Some(sub),
+            "user info belongs to another user"
+        );
+
+        self.claim(sub, &claims)
+            .with_context(|| format!("user info is missing the `{}` claim", self.username_claim))
+    }
+
+    /// Send a request to the provider and parse the JSON response.
+    async fn send<T: DeserializeOwned>(&self, mut req: Request<Body>) -> Result<T> {
+        req.headers_mut()
+            .insert(ACCEPT, "application/json".parse()?);
+
+        let resp = self.client.request(req).await?;
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await?;
+
+        if !status.is_success() {
+            bail!(
+                "provider responded with {status}: {}",
+                String::from_utf8_lossy(&body)
+            );
+        }
+
+        serde_json::from_slice(&body).map_err(Into::into)
+    }
+}
+
+/// Verify the signature of an ID token with the provider's keys and extract its claims.
+fn decode_id_token(token: &str, keys: &JwkSet) -> Result<IdClaims> {
+    let mut parts = token.split('.');
+    let (Some(header), Some(payload), Some(sig), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        bail!("ID token is not a JWT");
+    };
+
+    let decode = |value: &str, part: &str| {
+        base64::decode_config(value, base64::URL_SAFE_NO_PAD)
+            .with_context(|| format!("ID token has an invalid {part}"))
+    };
+
+    let header = serde_json::from_slice::<IdHeader>(&decode(header, "header")?)
+        .context("ID token has an invalid header")?;
+    ensure!(
+        matches!(header.alg.as_str(), "RS256" | "ES256"),
+        "ID token is signed with the unsupported algorithm `{}`",
+        header.alg
+    );
+
+    let message = &token[..token.rfind('.').unwrap_or_default()];
+    let sig = decode(sig, "signature")?;
+    let valid = keys
+        .keys
+        .iter()
+        .filter(|key| header.kid.is_none() || key.kid == header.kid)
+        .any(|key| key.verify(&header.alg, message.as_bytes(), &sig));
+    ensure!(valid, "ID token has an invalid signature");
+
+    serde_json::from_slice(&decode(payload, "payload")?).context("ID token has invalid claims")
+}
+
+/// Create the provider from the settings, by loading the discovery document of the issuer.
+pub async fn new(settings: &settings::Oidc) -> Result<Box<dyn Provider>> {
+    let client = http::client();
+    let issuer = settings.issuer.as_str().trim_end_matches('/');
+
+    let discovery = hyper::body::to_bytes(
+        client
+            .get(format!("{issuer}/.well-known/openid-configuration").parse()?)
+            .await
+            .context("failed loading the provider discovery document")?
+            .into_body(),
+    )
+    .await?;
+    let discovery = serde_json::from_slice::<Discovery>(&discovery)
+        .context("invalid provider discovery document")?;
+
+    ensure!(
+        discovery.issuer.trim_end_matches('/') == issuer,
+        "discovery document is for issuer `{}`",
+        discovery.issuer
+    );
+
+    Ok(Box::new(ProviderImpl {
+        client,
+        name: settings.name.clone(),
+        client_id: settings.client_id.clone(),
+        client_secret: settings.client_secret.clone(),
+        redirect_url: settings.redirect_url.to_string(),
+        scope: settings.scopes.join(" "),
+        username_claim: settings.username_claim.clone(),
+        discovery,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use hyper::{Client, StatusCode};
+    use parking_lot::Mutex;
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+    };
+    use serde_json::json;
+    use warp::Filter;
+
+    use super::*;
+
+    const CLIENT_ID: &str = "asgard";
+    const CLIENT_SECRET: &str = "s3cr3t/+";
+    const REDIRECT_URL: &str = "http://localhost:8080/login/oidc/callback";
+
+    /// Generate a new P-256 key pair, for signing ID tokens.
+    fn generate_key() -> EcdsaKeyPair {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng).unwrap()
+    }
+
+    /// Public part of the key pair, with the given key ID.
+    fn public_jwk(key: &EcdsaKeyPair, kid: &str) -> Value {
+        // Uncompressed curve point, with a leading marker byte.
+        let point = key.public_key().as_ref();
+        let encode = |value: &[u8]| base64::encode_config(value, base64::URL_SAFE_NO_PAD);
+
+        json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "kid": kid,
+            "x": encode(&point[1..33]),
+            "y": encode(&point[33..]),
+        })
+    }
+
+    /// Create an `ES256` signed JWT with the given claims.
+    fn sign(key: &EcdsaKeyPair, kid: &str, claims: &Value) -> String {
+        let encode = |value: &[u8]| base64::encode_config(value, base64::URL_SAFE_NO_PAD);
+        let message = format!(
+            "{}.{}",
+            encode(json!({ "alg": "ES256", "kid": kid }).to_string().as_bytes()),
+            encode(claims.to_string().as_bytes())
+        );
+        let sig = key.sign(&SystemRandom::new(), message.as_bytes()).unwrap();
+
+        format!("{message}.{}", encode(sig.as_ref()))
+    }
+
+    /// Minimal stand-in for an identity provider. It hands out the code `code` for the last
+    /// authorization request and only includes the user name in the user info.
+    fn start_mock_provider() -> Url {
+        // Code challenge and nonce of the last authorization request.
+        let pending = Arc::new(Mutex::new(None::<(String, String)>));
+        let key = Arc::new(generate_key());
+
+        let discovery = warp::path!(".well-known" / "openid-configuration")
+            .and(warp::header::<String>("host"))
+            .map(|host: String| {
+                warp::reply::json(&json!({
+                    "issuer": format!("http://{host}"),
+                    "authorization_endpoint": format!("http://{host}/authorize"),
+                    "token_endpoint": format!("http://{host}/token"),
+                    "jwks_uri": format!("http://{host}/jwks"),
+                    "userinfo_endpoint": format!("http://{host}/userinfo"),
+                }))
+            });
+
+        let jwks = {
+            // Another key that isn't used, to check that the right one is picked.
+            let keys = json!({
+                "keys": [public_jwk(&generate_key(), "old"), public_jwk(&key, "current")],
+            });
+            warp::path!("jwks").map(move || warp::reply::json(&keys))
+        };
+
+        let authorize = {
+            let pending = Arc::clone(&pending);
+            warp::path!("authorize")
+                .and(warp::query::<HashMap<String, String>>())
+                .map(move |query: HashMap<String, String>| {
+                    *pending.lock() =
+                        Some((query["code_challenge"].clone(), query["nonce"].clone()));
+
+                    let mut location = Url::parse(&query["redirect_uri"]).unwrap();
+                    location
+                        .query_pairs_mut()
+                        .append_pair("code", "code")
+                        .append_pair("state", &query["state"]);
+
+                    warp::http::Response::builder()
+                        .status(StatusCode::FOUND)
+                        .header("location", location.as_str())
+                        .body(String::new())
+                })
+        };
+
+        let token = warp::path!("token")
+            .and(warp::post())
+            .and(warp::header::<String>("host"))
+            .and(warp::header::<String>("authorization"))
+            .and(warp::body::form::<HashMap<String, String>>())
+            .map(
+                move |host: String, auth: String, form: HashMap<String, String>| {
+                    let credentials = base64::encode("asgard:s3cr3t%2F%2B");
+                    let valid = pending.lock().take().filter(|(challenge, _)| {
+                        let login = LoginState {
+                            state: String::new(),
+                            nonce: String::new(),
+                            verifier: form["code_verifier"].clone(),
+                        };
+
+                        auth == format!("Basic {credentials}")
+                            && form["grant_type"] == "authorization_code"
+                            && form["code"] == "code"
+                            && form["redirect_uri"] == REDIRECT_URL
+                            && *challenge == login.challenge()
+                    });
+
+                    let Some((_, nonce)) = valid else {
+                        return warp::reply::with_status(
+                            warp::reply::json(&json!({ "error": "invalid_grant" })),
+                            StatusCode::BAD_REQUEST,
+                        );
+                    };
+
+                    let claims = json!({
+                        "iss": format!("http://{host}"),
+                        "sub": "1234",
+                        "aud": [CLIENT_ID],
+                        "exp": OffsetDateTime::now_utc().unix_timestamp() + 60,
+                        "nonce": nonce,
+                    });
+                    let id_token = sign(&key, "current", &claims);
+
+                    warp::reply::with_status(
+                        warp::reply::json(&json!({
+                            "id_token": id_token,
+                            "access_token": "access",
+                            "token_type": "Bearer",
+                        })),
+                        StatusCode::OK,
+                    )
+                },
+            );
+
+        let userinfo = warp::path!("userinfo")
+            .and(warp::header::exact("authorization", "Bearer access"))
+            .map(|| warp::reply::json(&json!({ "sub": "1234", "preferred_username": "alice" })));
+
+        let (addr, server) = warp::serve(discovery.or(jwks).or(authorize).or(token).or(userinfo))
+            .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        format!("http://{addr}").parse().unwrap()
+    }
+
+    async fn provider() -> Box<dyn Provider> {
+        new(&settings::Oidc {
+            name: "Mock".to_owned(),
+            issuer: start_mock_provider(),
+            client_id: CLIENT_ID.to_owned(),
+            client_secret: CLIENT_SECRET.to_owned(),
+            redirect_url: REDIRECT_URL.parse().unwrap(),
+            scopes: vec!["openid".to_owned()],
+            username_claim: "preferred_username".to_owned(),
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Follow the redirect to the provider, returning the code and state of the callback.
+    async fn authorize(provider: &dyn Provider, login: &LoginState) -> (String, String) {
+        let resp = Client::new()
+            .get(provider.authorize_url(login).parse().unwrap())
+            .await
+            .unwrap();
+        let location = Url::parse(resp.headers()["location"].to_str().unwrap()).unwrap();
+        let query = location.query_pairs().collect::<HashMap<_, _>>();
+
+        (query["code"].to_string(), query["state"].to_string())
+    }
+
+    #[tokio::test]
+    async fn login_flow() {
+        let provider = provider().await;
+        let login = LoginState::generate();
+
+        let (code, state) = authorize(&*provider, &login).await;
+        assert_eq!(login.state, state);
+
+        let identity = provider.authenticate(&code, &login).await.unwrap();
+        assert_eq!("1234", identity.subject);
+        assert_eq!("alice", identity.name);
+
+        // Codes can only be used once.
+        assert!(provider.authenticate(&code, &login).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reject_other_login() {
+        let provider = provider().await;
+        let login = LoginState::generate();
+        let (code, _) = authorize(&*provider, &login).await;
+
+        let other = LoginState::generate();
+        assert!(provider.authenticate(&code, &other).await.is_err());
+
+        // Even with the right code verifier, the ID token must contain the nonce of the login.
+        let (code, _) = authorize(&*provider, &login).await;
+        let other = LoginState {
+            nonce: other.nonce,
+            ..login
+        };
+        let err = provider.authenticate(&code, &other).await.unwrap_err();
+        assert_eq!("ID token belongs to another login", err.to_string());
+    }
+
+    #[test]
+    fn verify_id_token() {
+        let key = generate_key();
+        let keys =
+            serde_json::from_value::<JwkSet>(json!({ "keys": [public_jwk(&key, "a")] })).unwrap();
+        let claims = json!({ "iss": "issuer", "sub": "1234", "aud": CLIENT_ID, "exp": 0 });
+
+        let token = sign(&key, "a", &claims);
+        assert_eq!("1234", decode_id_token(&token, &keys).unwrap().sub);
+
+        // Signed with another key.
+        let token = sign(&generate_key(), "a", &claims);
+        assert!(decode_id_token(&token, &keys).is_err());
+
+        // Changed claims.
+        let (header, rest) = token.split_once('.').unwrap();
+        let (_, sig) = rest.split_once('.').unwrap();
+        let payload = base64::encode_config(
+            json!({ "iss": "issuer", "sub": "5678", "aud": CLIENT_ID, "exp": 0 }).to_string(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        assert!(decode_id_token(&format!("{header}.{payload}.{sig}"), &keys).is_err());
+
+        // Unsigned.
+        let header = base64::encode_config(r#"{"alg":"none"}"#, base64::URL_SAFE_NO_PAD);
+        assert!(decode_id_token(&format!("{header}.{payload}."), &keys).is_err());
+    }
+
+    /// Example of an `RS256` signature from appendix A.2 of RFC 7515.
+    #[test]
+    fn verify_rs256() {
+        let key = serde_json::from_value::<Jwk>(json!({
+            "kty": "RSA",
+            "n": "ofgWCuLjybRlzo0tZWJjNiuSfb4p4fAkd_wWJcyQoTbji9k0l8W26mPddxHmfHQp-Vaw-4qPCJrcS2mJPMEz\
+                  P1Pt0Bm4d4QlL-yRT-SFd2lZS-pCgNMsD1W_YpRPEwOWvG6b32690r2jZ47soMZo9wGzjb_7OMg0LOL-bSf6\
+                  3kpaSHSXndS5z5rexMdbBYUsLA9e-KXBdQOS-UTo7WTBEMa2R2CapHg665xsmtdVMTBQY4uDZlxvb3qCo5Zw\
+                  Kh9kG4LT6_I5IhlJH7aGhyxXFvUK-DWNmoudF8NAco9_h9iaGNj8q2ethFkMLs91kzk2PAcDTW9gb54h4FRW\
+                  yuXpoQ",
+            "e": "AQAB",
+        }))
+        .unwrap();
+        let message =
+            "eyJhbGciOiJSUzI1NiJ9.eyJpc3MiOiJqb2UiLA0KICJleHAiOjEzMDA4MTkzODAsDQogImh0dHA6\
+                       Ly9leGFtcGxlLmNvbS9pc19yb290Ijp0cnVlfQ";
+        let sig = base64::decode_config(
+            "cC4hiUPoj9Eetdgtv3hF80EGrhuB__dzERat0XF9g2VtQgr9PJbu3XOiZj5RZmh7AAuHIm4Bh-0Qc_lF5YKt_O8W\
+             2Fp5jujGbds9uJdbF9CUAr7t1dnZcAcQjbKBYNX4BAynRFdiuB--f_nZLgrnbyTyWzO75vRK5h6xBArLIARNPvkS\
+             jtQBMHlb1L07Qe7K0GarZRmB_eSN9383LcOLn6_dO--xi12jzDwusC-eOkHWEsqtFZESc6BfI7noOPqvhJ1phCnv\
+             Wh6IeYI2w9QOYEUipUTI8np6LbgGY9Fs98rqVt5AXLIhWkWywlVmtVrBp0igcN_IoypGlUPQGe77Rw",
+            base64::URL_SAFE_NO_PAD,
+        )
+        .unwrap();
+
+        assert!(key.verify("RS256", message.as_bytes(), &sig));
+        assert!(!key.verify("ES256", message.as_bytes(), &sig));
+        assert!(!key.verify("RS256", b"other", &sig));
+    }
+
+    #[test]
+    fn login_state_roundtrip() {
+        let login = LoginState::generate();
+
+        assert_eq!(Some(&login), LoginState::decode(&login.encode()).as_ref());
+        assert_eq!(None, LoginState::decode("a.b"));
+        assert_eq!(None, LoginState::decode("a.b.c.d"));
+    }
+}
@@ -0,0 +1,243 @@
+//! Validation of the `.crate` package files that cargo uploads when publishing a new release. These
+//! are gzip compressed tarballs, containing all files of the crate inside a single directory named
+//! `<name>-<version>`.
+
+use std::{
+    io::Read,
+    path::{Component, Path},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use flate2::read::GzDecoder;
+use semver::Version;
+use serde::Deserialize;
+use tar::{Archive, EntryType};
+
+use crate::{api::models::PublishRequest, settings};
+
+/// The parts of the `Cargo.toml` manifest that are checked against the publish request.
+#[derive(Deserialize)]
+struct Manifest {
+    package: ManifestPackage,
+}
+
+#[derive(Deserialize)]
+struct ManifestPackage {
+    name: String,
+    version: Version,
+}
+
+/// Validate the package contents against the publish request. The package must only contain
+/// regular files and directories within the `<name>-<version>` directory, stay within the size
+/// limits and contain a `Cargo.toml` manifest with matching name and version.
+///
+/// The package is read as a stream, so it doesn't have to be held in memory as a whole. Reading
+/// stops as soon as a violation is found.
+pub fn validate(req: &PublishRequest, data: impl Read, limits: settings::Publish) -> Result<()> {
+    validate_inner(req, data, limits).context("invalid crate package")
+}
+
+fn validate_inner(req: &PublishRequest, data: impl Read, limits: settings::Publish) -> Result<()> {
+    let (max_entry_size, max_total_size) = (limits.max_entry_size(), limits.max_unpacked_size);
+    let root = format!("{}-{}", req.name, req.vers);
+    let manifest_path = Path::new(&root).join("Cargo.toml");
+
+    let mut archive = Archive::new(GzDecoder::new(data));
+    let mut total_size = 0;
+    let mut manifest = None;
+
+    for entry in archive.entries().context("failed reading package")? {
+        let mut entry = entry.context("failed reading package entry")?;
+        let path = entry.path().context("invalid entry path")?.into_owned();
+
+        ensure!(
+            matches!(
+                entry.header().entry_type(),
+                EntryType::Regular | EntryType::Directory
+            ),
+            "entry `{}` is not a regular file or directory",
+            path.display()
+        );
+        ensure!(
+            path.components().all(|c| matches!(c, Component::Normal(_))),
+            "entry `{}` contains an invalid path component",
+            path.display()
+        );
+        ensure!(
+            path.starts_with(&root),
+            "entry `{}` is outside of the `{root}` directory",
+            path.display()
+        );
+
+        let size = entry.size();
+        ensure!(
+            size <= max_entry_size,
+            "entry `{}` is too large ({size} bytes, max {max_entry_size} bytes)",
+            path.display()
+        );
+
+        total_size += size;
+        ensure!(
+            total_size <= max_total_size,
+            "package content is too large (max {max_total_size} bytes)"
+        );
+
+        if path == manifest_path {
+            let mut content = String::new();
+            entry
+                .read_to_string(&mut content)
+                .context("failed reading Cargo.toml")?;
+            manifest = Some(content);
+        }
+    }
+
+    let Some(manifest) = manifest else {
+        bail!("package doesn't contain `{}`", manifest_path.display());
+    };
+
+    let manifest = toml::from_str::<Manifest>(&manifest).context("failed parsing Cargo.toml")?;
+
+    ensure!(
+        manifest.package.name == req.name.as_ref(),
+        "crate name `{}` in Cargo.toml doesn't match the published name `{}`",
+        manifest.package.name,
+        req.name
+    );
+    ensure!(
+        manifest.package.version == req.vers,
+        "version `{}` in Cargo.toml doesn't match the published version `{}`",
+        manifest.package.version,
+        req.vers
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{write::GzEncoder, Compression};
+    use tar::{Builder, Header};
+
+    use super::*;
+
+    const LIMIT: settings::Publish = settings::Publish {
+        max_crate_size: 1024 * 1024,
+        max_unpacked_size: 1024 * 1024,
+        max_entry_size: None,
+    };
+
+    /// Create a gzip compressed tarball with the given files.
+    fn create_package(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+
+        for (path, content) in files {
+            let mut header = Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+
+            builder.append(&header, *content).unwrap();
+        }
+
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    /// Create a minimal valid package for the crate.
+    fn valid_package(name: &str, version: &str) -> Vec<u8> {
+        create_package(&[
+            (
+                &format!("{name}-{version}/Cargo.toml"),
+                format!("[package]\nname = \"{name}\"\nversion = \"{version}\"\n").as_bytes(),
+            ),
+            (&format!("{name}-{version}/src/lib.rs"), b""),
+        ])
+    }
+
+    fn request() -> PublishRequest {
+        PublishRequest::new("test".parse().unwrap(), "1.0.0".parse().unwrap())
+    }
+
+    #[test]
+    fn valid() {
+        validate(&request(), valid_package("test", "1.0.0").as_slice(), LIMIT).unwrap();
+    }
+
+    #[test]
+    fn mismatch() {
+        assert!(validate(
+            &request(),
+            valid_package("other", "1.0.0").as_slice(),
+            LIMIT
+        )
+        .is_err());
+        assert!(validate(&request(), valid_package("test", "1.0.1").as_slice(), LIMIT).is_err());
+
+        let data = create_package(&[(
+            "test-1.0.0/Cargo.toml",
+            b"[package]\nname = \"other\"\nversion = \"1.0.0\"\n",
+        )]);
+        assert!(validate(&request(), data.as_slice(), LIMIT).is_err());
+    }
+
+    #[test]
+    fn missing_manifest() {
+        let data = create_package(&[("test-1.0.0/src/lib.rs", b"")]);
+        assert!(validate(&request(), data.as_slice(), LIMIT).is_err());
+    }
+
+    #[test]
+    fn path_traversal() {
+        let data = create_package(&[
+            (
+                "test-1.0.0/Cargo.toml",
+                b"[package]\nname = \"test\"\nversion = \"1.0.0\"\n",
+            ),
+            ("test-1.0.0/../../etc/passwd", b""),
+        ]);
+        let err = validate(&request(), data.as_slice(), LIMIT).unwrap_err();
+        assert!(format!("{err:#}").contains("invalid path component"));
+    }
+
+    #[test]
+    fn not_gzip() {
+        assert!(validate(&request(), &b"not a tarball"[..], LIMIT).is_err());
+    }
+
+    #[test]
+    fn too_large() {
+        let content = vec![b'a'; 2048];
+        let data = create_package(&[
+            (
+                "test-1.0.0/Cargo.toml",
+                b"[package]\nname = \"test\"\nversion = \"1.0.0\"\n",
+            ),
+            ("test-1.0.0/src/lib.rs", &content),
+        ]);
+
+        validate(&request(), data.as_slice(), LIMIT).unwrap();
+
+        let limits = settings::Publish {
+            max_unpacked_size: 2048,
+            max_entry_size: Some(4096),
+            ..LIMIT
+        };
+        let err = validate(&request(), data.as_slice(), limits).unwrap_err();
+        assert!(format!("{err:#}").contains("package content is too large"));
+
+        let limits = settings::Publish {
+            max_entry_size: Some(1024),
+            ..LIMIT
+        };
+        let err = validate(&request(), data.as_slice(), limits).unwrap_err();
+        assert!(format!("{err:#}").contains("entry `test-1.0.0/src/lib.rs` is too large"));
+
+        // Single files are limited by the combined size by default.
+        let limits = settings::Publish {
+            max_unpacked_size: 1024,
+            ..LIMIT
+        };
+        let err = validate(&request(), data.as_slice(), limits).unwrap_err();
+        assert!(format!("{err:#}").contains("entry `test-1.0.0/src/lib.rs` is too large"));
+    }
+}
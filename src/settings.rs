@@ -1,7 +1,10 @@
 use std::{fs, path::PathBuf};
 
 use anyhow::{bail, Result};
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
+use url::Url;
+
+use crate::index::models::Config as IndexConfig;
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
@@ -9,7 +12,19 @@ pub struct Settings {
     pub index: Index,
     pub storage: Storage,
     #[serde(default)]
+    pub publish: Publish,
+    #[serde(default)]
     pub tracing: Option<Tracing>,
+    /// Backend that API tokens are checked against. Tokens created through the web UI or the
+    /// `token` command are used if not set.
+    #[serde(default)]
+    pub auth: Option<Auth>,
+    /// Login to the web UI through an external identity provider.
+    #[serde(default)]
+    pub oidc: Option<Oidc>,
+    /// Registry that unknown crates are fetched from, turning the registry into a caching mirror.
+    #[serde(default)]
+    pub upstream: Option<Upstream>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -18,15 +33,132 @@ pub struct Index {
     pub config: IndexConfig,
 }
 
-#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
-pub struct IndexConfig {
-    pub dl: String,
-    pub api: String,
+/// Backend that crate tarballs are stored in. Either a local directory, or an S3 compatible object
+/// store when configured with a `[storage.s3]` section.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Storage {
+    Filesystem { location: PathBuf },
+    S3 { s3: Box<S3> },
 }
 
 #[derive(Debug, Deserialize)]
-pub struct Storage {
-    pub location: PathBuf,
+pub struct S3 {
+    /// Base URL of the object store, like `http://localhost:9000`.
+    pub endpoint: Url,
+    #[serde(default = "default_region")]
+    pub region: String,
+    pub bucket: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Optional prefix for all object keys, allowing to share a bucket with other applications.
+    #[serde(default)]
+    pub prefix: String,
+    /// Endpoint that clients are redirected to for downloads, if it differs from `endpoint`.
+    #[serde(default)]
+    pub public_endpoint: Option<Url>,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_owned()
+}
+
+/// Limits for newly published crates.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct Publish {
+    /// Maximum size of the uploaded `.crate` file, in bytes.
+    pub max_crate_size: u64,
+    /// Maximum size of all files inside the `.crate` file combined, after decompression.
+    pub max_unpacked_size: u64,
+    /// Maximum size of a single file inside the `.crate` file, defaulting to the combined limit.
+    pub max_entry_size: Option<u64>,
+}
+
+impl Default for Publish {
+    fn default() -> Self {
+        Self {
+            max_crate_size: 10 * 1024 * 1024,
+            max_unpacked_size: 200 * 1024 * 1024,
+            max_entry_size: None,
+        }
+    }
+}
+
+impl Publish {
+    /// Maximum size of a single file inside the `.crate` file, after decompression.
+    pub fn max_entry_size(&self) -> u64 {
+        self.max_entry_size.unwrap_or(self.max_unpacked_size)
+    }
+}
+
+/// Alternative backend for authenticating API requests. Either a fixed list of tokens in a
+/// `[auth]` section, or an LDAP server when configured with an `[auth.ldap]` section.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Auth {
+    Static { tokens: Vec<StaticToken> },
+    Ldap { ldap: Box<Ldap> },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StaticToken {
+    pub user: String,
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Ldap {
+    /// Address of the LDAP server, like `ldap://localhost:389`, or `ldaps://localhost:636` for
+    /// connections that are secured with TLS.
+    pub url: Url,
+    /// Upgrade plain `ldap` connections to TLS with the StartTLS operation.
+    #[serde(default)]
+    pub start_tls: bool,
+    /// Distinguished name to bind as, where `{user}` is replaced by the user name.
+    pub bind_dn: String,
+}
+
+/// Client settings for an [OpenID Connect](https://openid.net/connect/) identity provider.
+#[derive(Debug, Deserialize)]
+pub struct Oidc {
+    /// Name of the provider, shown on the login page.
+    #[serde(default = "default_oidc_name")]
+    pub name: String,
+    /// Issuer URL of the provider, which serves the discovery document at
+    /// `<issuer>/.well-known/openid-configuration`.
+    pub issuer: Url,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Public address of the login callback, `<registry address>/login/oidc/callback`.
+    pub redirect_url: Url,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+    /// Claim that holds the name of the registry user.
+    #[serde(default = "default_oidc_username_claim")]
+    pub username_claim: String,
+}
+
+fn default_oidc_name() -> String {
+    "SSO".to_owned()
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec!["openid".to_owned(), "profile".to_owned()]
+}
+
+fn default_oidc_username_claim() -> String {
+    "preferred_username".to_owned()
+}
+
+/// Registry like crates.io, that crates are fetched from if they don't exist in the local index.
+#[derive(Debug, Deserialize)]
+pub struct Upstream {
+    /// Base URL of the upstream's sparse index, like `https://index.crates.io/`.
+    pub index: Url,
+    /// Download template, overriding the `dl` value from the upstream's `config.json`.
+    #[serde(default)]
+    pub dl: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -45,7 +177,9 @@ pub fn load() -> Result<Settings> {
         concat!("/app/", env!("CARGO_PKG_NAME"), ".toml"),
         concat!(env!("CARGO_PKG_NAME"), ".toml"),
     ];
-    let buf = locations.iter().find_map(|loc| fs::read_to_string(loc).ok());
+    let buf = locations
+        .iter()
+        .find_map(|loc| fs::read_to_string(loc).ok());
 
     match buf {
         Some(buf) => Ok(toml::from_str(&buf)?),
@@ -0,0 +1,53 @@
+use std::{convert::Infallible, sync::Arc};
+
+use warp::{Filter, Rejection, Reply};
+
+use super::handlers;
+use crate::{
+    api::error,
+    index::{models::Config, Service as IndexService},
+    upstream::Service as UpstreamService,
+};
+
+/// All sparse index routes prefixed with `/index/...`. Crates that don't exist locally are looked
+/// up in the upstream registry, if there is one.
+pub fn sparse(
+    index: Arc<impl IndexService>,
+    config: Config,
+    upstream: Option<Arc<dyn UpstreamService>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path("index").and(config_json(config).or(crate_file(index, upstream)))
+}
+
+/// `GET /index/config.json`
+fn config_json(config: Config) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("config.json")
+        .and(warp::get())
+        .map(move || warp::reply::json(&config))
+}
+
+/// `GET /index/<crate_path>`
+fn crate_file(
+    index: Arc<impl IndexService>,
+    upstream: Option<Arc<dyn UpstreamService>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path::tail()
+        .and(warp::get())
+        .and(warp::header::headers_cloned())
+        .and(with_index(index))
+        .and(with_upstream(upstream))
+        .and_then(handlers::crate_file)
+        .recover(error::recover)
+}
+
+fn with_index(
+    service: Arc<impl IndexService>,
+) -> impl Filter<Extract = (Arc<impl IndexService>,), Error = Infallible> + Clone {
+    warp::any().map(move || Arc::clone(&service))
+}
+
+fn with_upstream(
+    upstream: Option<Arc<dyn UpstreamService>>,
+) -> impl Filter<Extract = (Option<Arc<dyn UpstreamService>>,), Error = Infallible> + Clone {
+    warp::any().map(move || upstream.clone())
+}
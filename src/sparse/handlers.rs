@@ -0,0 +1,102 @@
+use std::{path::Path, sync::Arc};
+
+use headers::{ETag, HeaderMapExt, IfModifiedSince, IfNoneMatch, LastModified};
+use hyper::{Body, HeaderMap, StatusCode};
+use sha2::{Digest, Sha256};
+use tokio::task;
+use tracing::instrument;
+use warp::{path::Tail, reply::Response, Reply};
+
+use crate::{
+    api::error::{Result, ServerError},
+    index::{self, IndexFile},
+    models::CrateName,
+    upstream,
+};
+
+#[instrument(skip(headers, index, upstream))]
+pub async fn crate_file(
+    tail: Tail,
+    headers: HeaderMap,
+    index: Arc<impl index::Service>,
+    upstream: Option<Arc<dyn upstream::Service>>,
+) -> Result<impl Reply> {
+    let name = parse_crate_path(tail.as_str()).ok_or_else(warp::reject::not_found)?;
+
+    let file = {
+        let name = name.clone();
+        task::spawn_blocking(move || index.read_crate(&name))
+            .await
+            .map_err(ServerError::from)?
+            .map_err(ServerError)?
+    };
+
+    // Local crates always take precedence over the ones from the upstream registry.
+    let file = match (file, upstream) {
+        (Some(file), _) => file,
+        (None, Some(upstream)) => upstream
+            .read_crate(&name)
+            .await
+            .map_err(ServerError)?
+            .ok_or_else(warp::reject::not_found)?,
+        (None, None) => return Err(warp::reject::not_found()),
+    };
+
+    Ok(conditional_response(file, &headers))
+}
+
+/// Build the response for an index file, answering with `304 Not Modified` if the client already
+/// has the current version according to its `If-None-Match` or `If-Modified-Since` headers.
+fn conditional_response(file: IndexFile, headers: &HeaderMap) -> Response {
+    let etag = format!("\"{}\"", hex::encode(Sha256::digest(&file.content)))
+        .parse::<ETag>()
+        .expect("hex encoded hash is a valid etag");
+    let last_modified = LastModified::from(file.modified);
+
+    let not_modified = match headers.typed_get::<IfNoneMatch>() {
+        Some(if_none_match) => !if_none_match.precondition_passes(&etag),
+        None => headers
+            .typed_get::<IfModifiedSince>()
+            .is_some_and(|since| !since.is_modified(file.modified)),
+    };
+
+    let mut resp = if not_modified {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::NOT_MODIFIED;
+        resp
+    } else {
+        Response::new(Body::from(file.content))
+    };
+
+    resp.headers_mut().typed_insert(etag);
+    resp.headers_mut().typed_insert(last_modified);
+
+    resp
+}
+
+/// Extract the crate name from a sparse index path, making sure that the path is the one that
+/// [`index::crate_path`] would create for it.
+fn parse_crate_path(path: &str) -> Option<CrateName> {
+    let name = path.rsplit('/').next()?.parse().ok()?;
+
+    (index::crate_path(&name) == Path::new(path)).then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_crate_path() {
+        assert_eq!(Some("a".parse().unwrap()), parse_crate_path("1/a"));
+        assert_eq!(Some("abc".parse().unwrap()), parse_crate_path("3/a/abc"));
+        assert_eq!(
+            Some("cargo".parse().unwrap()),
+            parse_crate_path("ca/rg/cargo")
+        );
+
+        assert_eq!(None, parse_crate_path("cargo"));
+        assert_eq!(None, parse_crate_path("xx/rg/cargo"));
+        assert_eq!(None, parse_crate_path("ca/rg/Cargo"));
+    }
+}
@@ -0,0 +1,6 @@
+//! Sparse index protocol, serving the same index files as the git index over plain HTTP. See the
+//! [Sparse Protocol](https://doc.rust-lang.org/cargo/reference/registry-index.html#sparse-protocol)
+//! documentation for details.
+
+pub mod filters;
+mod handlers;
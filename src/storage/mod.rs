@@ -1,19 +1,20 @@
-use std::{
-    io::ErrorKind,
-    path::{Path, PathBuf},
-    pin::Pin,
-};
+use std::{io::ErrorKind, path::PathBuf, pin::Pin};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, ensure, Result};
 use async_trait::async_trait;
 use semver::Version;
 use tokio::{
-    fs::{self, File},
-    io::AsyncRead,
+    fs::{self, File, OpenOptions},
+    io::{self, AsyncRead},
 };
 use tracing::instrument;
 
-use crate::models::CrateName;
+use crate::{
+    models::{AlreadyExists, CrateName},
+    settings,
+};
+
+mod s3;
 
 type PinnedRead = Pin<Box<dyn AsyncRead + Send>>;
 
@@ -21,14 +22,54 @@ type PinnedRead = Pin<Box<dyn AsyncRead + Send>>;
 /// code tarball packaged by cargo and uploaded with a new release.
 #[async_trait]
 pub trait Service: Send + Sync + 'static {
-    /// Store a new crate tarball in the storage with given name and version.
-    async fn store(&self, name: &CrateName, version: &Version, data: &[u8]) -> Result<()>;
+    /// Store a new crate tarball in the storage with given name and version, reading exactly `len`
+    /// bytes from `data`. Fails with [`AlreadyExists`] if the version was stored before. Nothing is
+    /// stored if reading the data fails.
+    async fn store(
+        &self,
+        name: &CrateName,
+        version: &Version,
+        len: u64,
+        data: PinnedRead,
+    ) -> Result<()>;
     /// Try to locate the crate data identified by name and version and open it for reading if it
     /// exists.
     async fn get(&self, name: &CrateName, version: &Version) -> Result<Option<PinnedRead>>;
+    /// Get a URL that clients can download the crate data from directly, if the backend supports
+    /// it. Otherwise, the data must be served through [`get`](Self::get).
+    async fn download_url(&self, _name: &CrateName, _version: &Version) -> Result<Option<String>> {
+        Ok(None)
+    }
+    /// Remove the crate data identified by name and version, if it exists.
+    async fn delete(&self, name: &CrateName, version: &Version) -> Result<()>;
+}
+
+#[async_trait]
+impl Service for Box<dyn Service> {
+    async fn store(
+        &self,
+        name: &CrateName,
+        version: &Version,
+        len: u64,
+        data: PinnedRead,
+    ) -> Result<()> {
+        (**self).store(name, version, len, data).await
+    }
+
+    async fn get(&self, name: &CrateName, version: &Version) -> Result<Option<PinnedRead>> {
+        (**self).get(name, version).await
+    }
+
+    async fn download_url(&self, name: &CrateName, version: &Version) -> Result<Option<String>> {
+        (**self).download_url(name, version).await
+    }
+
+    async fn delete(&self, name: &CrateName, version: &Version) -> Result<()> {
+        (**self).delete(name, version).await
+    }
 }
 
-/// Main implementation of the storage [`Service`].
+/// Main implementation of the storage [`Service`], keeping all crates in a local directory.
 struct ServiceImpl {
     location: PathBuf,
 }
@@ -36,14 +77,50 @@ struct ServiceImpl {
 #[async_trait]
 impl Service for ServiceImpl {
     #[instrument(skip_all)]
-    async fn store(&self, name: &CrateName, version: &Version, data: &[u8]) -> Result<()> {
+    async fn store(
+        &self,
+        name: &CrateName,
+        version: &Version,
+        len: u64,
+        mut data: PinnedRead,
+    ) -> Result<()> {
         let out = self.location.join(name.as_ref());
 
         fs::create_dir_all(&out).await?;
 
         let out = out.join(format!("{name}-{version}.crate"));
 
-        fs::write(out, data).await?;
+        let mut file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&out)
+            .await
+        {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                bail!(AlreadyExists {
+                    name: name.clone(),
+                    version: version.clone(),
+                })
+            }
+            Err(e) => bail!(e),
+        };
+
+        if let Err(e) = async {
+            let written = io::copy(&mut data, &mut file).await?;
+            ensure!(
+                written == len,
+                "expected {len} bytes but only got {written}"
+            );
+            file.sync_all().await?;
+            Ok(())
+        }
+        .await
+        {
+            // Don't leave a partially written file behind, which would block any further attempt.
+            fs::remove_file(&out).await.ok();
+            return Err(e);
+        }
 
         Ok(())
     }
@@ -61,15 +138,32 @@ impl Service for ServiceImpl {
             Err(e) => bail!(e),
         }
     }
-}
 
-/// Create a new storage service.
-pub fn new(location: &Path) -> impl Service {
-    ServiceImpl {
-        location: location.to_owned(),
+    #[instrument(skip_all)]
+    async fn delete(&self, name: &CrateName, version: &Version) -> Result<()> {
+        let file_name = self
+            .location
+            .join(name.as_ref())
+            .join(format!("{name}-{version}.crate"));
+
+        match fs::remove_file(file_name).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => bail!(e),
+        }
     }
 }
 
+/// Create a new storage service, using the backend that is selected in the settings.
+pub fn new(settings: &settings::Storage) -> Result<Box<dyn Service>> {
+    Ok(match settings {
+        settings::Storage::Filesystem { location } => Box::new(ServiceImpl {
+            location: location.clone(),
+        }),
+        settings::Storage::S3 { s3 } => Box::new(s3::new(s3)?),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -77,10 +171,18 @@ mod tests {
     #[tokio::test]
     async fn service_roundtrip() {
         let dir = tempfile::tempdir().unwrap();
-        let service = new(dir.path());
+        let service = new(&settings::Storage::Filesystem {
+            location: dir.path().to_owned(),
+        })
+        .unwrap();
 
         service
-            .store(&"test".parse().unwrap(), &"1.0.0".parse().unwrap(), b"test")
+            .store(
+                &"test".parse().unwrap(),
+                &"1.0.0".parse().unwrap(),
+                4,
+                Box::pin(&b"test"[..]),
+            )
             .await
             .unwrap();
 
@@ -91,11 +193,45 @@ mod tests {
 
         assert!(reader.is_some());
 
+        let err = service
+            .store(
+                &"test".parse().unwrap(),
+                &"1.0.0".parse().unwrap(),
+                5,
+                Box::pin(&b"other"[..]),
+            )
+            .await
+            .unwrap_err();
+
+        assert!(err.is::<AlreadyExists>());
+
+        service
+            .store(
+                &"test".parse().unwrap(),
+                &"2.0.0".parse().unwrap(),
+                10,
+                Box::pin(&b"short"[..]),
+            )
+            .await
+            .unwrap_err();
+
         let reader = service
             .get(&"test".parse().unwrap(), &"2.0.0".parse().unwrap())
             .await
             .unwrap();
 
         assert!(reader.is_none());
+
+        service
+            .delete(&"test".parse().unwrap(), &"1.0.0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let reader = service
+            .get(&"test".parse().unwrap(), &"1.0.0".parse().unwrap())
+            .await
+            .unwrap();
+
+        assert!(reader.is_none());
     }
 }
@@ -1,9 +1,138 @@
 use askama::Template;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use semver::Version;
+use time::{macros::format_description, OffsetDateTime};
+
+use crate::{
+    db::{
+        crates::{CrateDetails, CrateInfo},
+        tokens::TokenInfo,
+        users::User,
+        versions::{DependencyInfo, VersionInfo},
+    },
+    models::CrateName,
+};
 
 #[derive(Template)]
 #[template(path = "index.html")]
 pub struct Index;
 
+#[derive(Template)]
+#[template(path = "login.html")]
+pub struct Login {
+    pub error: Option<&'static str>,
+    /// Name of the identity provider, if logging in through one is possible.
+    pub provider: Option<String>,
+}
+
 #[derive(Template)]
 #[template(path = "me.html")]
-pub struct Me;
+pub struct Me {
+    pub user: User,
+    /// Crates that the user published at least one version of.
+    pub published: Vec<CrateInfo>,
+    pub owned: Vec<CrateInfo>,
+    pub tokens: Vec<TokenInfo>,
+    /// Plain value of a token that was just created.
+    pub new_token: Option<String>,
+    /// Token that proves that forms were sent from this page.
+    pub csrf: String,
+}
+
+impl Me {
+    fn date(&self, timestamp: &i64) -> String {
+        format_date(*timestamp)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "crates.html")]
+pub struct Crates {
+    /// The search query, empty when listing all crates.
+    pub query: String,
+    pub crates: Vec<CrateInfo>,
+    pub total: u64,
+    pub page: u32,
+    pub pages: u32,
+}
+
+impl Crates {
+    fn page_url(&self, page: &u32) -> String {
+        format!("{}&page={page}", search_url(&self.query))
+    }
+}
+
+#[derive(Template)]
+#[template(path = "crate.html")]
+pub struct Crate {
+    pub details: CrateDetails,
+    /// All versions of the crate, sorted from highest to lowest.
+    pub versions: Vec<VersionInfo>,
+    /// Index into `versions` of the version that is shown.
+    pub current: Option<usize>,
+    pub dependencies: Vec<DependencyInfo>,
+    /// README of the shown version, already rendered to sanitized HTML.
+    pub readme: Option<String>,
+}
+
+impl Crate {
+    fn version(&self) -> Option<&VersionInfo> {
+        self.current.map(|current| &self.versions[current])
+    }
+
+    /// Project links of the crate. Only `http` and `https` URLs are shown, as the values come
+    /// straight from the publish request.
+    fn links(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("Homepage", &self.details.homepage),
+            ("Documentation", &self.details.documentation),
+            ("Repository", &self.details.repository),
+        ]
+        .into_iter()
+        .filter_map(|(label, url)| {
+            url.as_deref()
+                .filter(|url| url.starts_with("https://") || url.starts_with("http://"))
+                .map(|url| (label, url))
+        })
+        .collect()
+    }
+
+    fn keyword_url(&self, keyword: &str) -> String {
+        search_url(keyword)
+    }
+
+    fn date(&self, timestamp: &i64) -> String {
+        OffsetDateTime::from_unix_timestamp(*timestamp)
+            .ok()
+            .and_then(|date| {
+                date.format(format_description!("[year]-[month]-[day]"))
+                    .ok()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "readme.html")]
+pub struct Readme {
+    pub name: CrateName,
+    pub version: Version,
+    /// README content, already rendered to sanitized HTML.
+    pub html: String,
+}
+
+/// Format seconds since the Unix epoch as plain date.
+fn format_date(timestamp: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(timestamp)
+        .ok()
+        .and_then(|date| {
+            date.format(format_description!("[year]-[month]-[day]"))
+                .ok()
+        })
+        .unwrap_or_default()
+}
+
+/// Location of the crate list, filtered by the search query.
+fn search_url(query: &str) -> String {
+    format!("/crates?q={}", utf8_percent_encode(query, NON_ALPHANUMERIC))
+}
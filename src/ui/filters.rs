@@ -1,9 +1,38 @@
+use std::{convert::Infallible, sync::Arc};
+
+use semver::Version;
 use warp::{Filter, Rejection, Reply};
 
-use super::handlers;
+use super::handlers::{
+    self, CallbackQuery, Cookies, CratesQuery, CreateTokenForm, CsrfForm, LoginForm,
+};
+use crate::{
+    db::{users::User, DbConnPool},
+    models::CrateName,
+    oidc::Provider,
+};
+
+/// Maximum size of form submissions.
+const FORM_LIMIT: u64 = 4096;
 
-pub fn ui() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    index().or(me())
+pub fn ui(
+    pool: DbConnPool,
+    provider: Option<Arc<dyn Provider>>,
+    cookies: Cookies,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    index()
+        .or(login_page(provider.clone()))
+        .or(login(provider.clone(), cookies, pool.clone()))
+        .or(oidc_login(provider.clone(), cookies))
+        .or(oidc_callback(provider, cookies, pool.clone()))
+        .or(logout(cookies, pool.clone()))
+        .or(me(pool.clone()))
+        .or(create_token(pool.clone()))
+        .or(revoke_token(pool.clone()))
+        .or(crates(pool.clone()))
+        .or(crate_info(pool.clone()))
+        .or(version_info(pool.clone()))
+        .or(readme(pool))
 }
 
 /// `GET /`
@@ -11,7 +40,174 @@ fn index() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path::end().and(warp::get()).map(handlers::index)
 }
 
+/// `GET /login`
+fn login_page(
+    provider: Option<Arc<dyn Provider>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("login")
+        .and(warp::get())
+        .and(with_provider(provider))
+        .map(handlers::login_page)
+}
+
+/// `POST /login`
+fn login(
+    provider: Option<Arc<dyn Provider>>,
+    cookies: Cookies,
+    pool: DbConnPool,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("login")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(FORM_LIMIT))
+        .and(warp::body::form::<LoginForm>())
+        .and(with_provider(provider))
+        .and(with_cookies(cookies))
+        .and(with_pool(pool))
+        .and_then(handlers::login)
+}
+
+/// `GET /login/oidc`
+fn oidc_login(
+    provider: Option<Arc<dyn Provider>>,
+    cookies: Cookies,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("login" / "oidc")
+        .and(warp::get())
+        .and(require_provider(provider))
+        .and(with_cookies(cookies))
+        .and_then(|provider, cookies| async move { handlers::oidc_login(provider, cookies) })
+}
+
+/// `GET /login/oidc/callback?code=<code>&state=<state>`
+fn oidc_callback(
+    provider: Option<Arc<dyn Provider>>,
+    cookies: Cookies,
+    pool: DbConnPool,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("login" / "oidc" / "callback")
+        .and(warp::get())
+        .and(warp::query::<CallbackQuery>())
+        .and(warp::cookie::optional::<String>(handlers::OIDC_COOKIE))
+        .and(require_provider(provider))
+        .and(with_cookies(cookies))
+        .and(with_pool(pool))
+        .and_then(handlers::oidc_callback)
+}
+
+/// `POST /logout`
+fn logout(
+    cookies: Cookies,
+    pool: DbConnPool,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("logout")
+        .and(warp::post())
+        .and(session_token())
+        .and(warp::body::content_length_limit(FORM_LIMIT))
+        .and(warp::body::form::<CsrfForm>())
+        .and(with_cookies(cookies))
+        .and(with_pool(pool))
+        .and_then(handlers::logout)
+}
+
 /// `GET /me`
-fn me() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path("me").and(warp::get()).map(handlers::me)
+fn me(pool: DbConnPool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("me")
+        .and(warp::get())
+        .and(session(pool.clone()))
+        .and(session_token())
+        .and(with_pool(pool))
+        .and_then(handlers::me)
+}
+
+/// `POST /me/tokens`
+fn create_token(pool: DbConnPool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("me" / "tokens")
+        .and(warp::post())
+        .and(session(pool.clone()))
+        .and(session_token())
+        .and(warp::body::content_length_limit(FORM_LIMIT))
+        .and(warp::body::form::<CreateTokenForm>())
+        .and(with_pool(pool))
+        .and_then(handlers::create_token)
+}
+
+/// `POST /me/tokens/<id>/revoke`
+fn revoke_token(pool: DbConnPool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("me" / "tokens" / i64 / "revoke")
+        .and(warp::post())
+        .and(session(pool.clone()))
+        .and(session_token())
+        .and(warp::body::content_length_limit(FORM_LIMIT))
+        .and(warp::body::form::<CsrfForm>())
+        .and(with_pool(pool))
+        .and_then(handlers::revoke_token)
+}
+
+/// `GET /crates?q=<query>&page=<page>`
+fn crates(pool: DbConnPool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("crates")
+        .and(warp::get())
+        .and(warp::query::<CratesQuery>())
+        .and(with_pool(pool))
+        .and_then(handlers::crates)
+}
+
+/// `GET /crates/<crate_name>`
+fn crate_info(pool: DbConnPool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("crates" / CrateName)
+        .and(warp::get())
+        .and(with_pool(pool))
+        .and_then(handlers::crate_info)
+}
+
+/// `GET /crates/<crate_name>/<version>`
+fn version_info(pool: DbConnPool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("crates" / CrateName / Version)
+        .and(warp::get())
+        .and(with_pool(pool))
+        .and_then(handlers::version_info)
+}
+
+/// `GET /crates/<crate_name>/<version>/readme`
+fn readme(pool: DbConnPool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("crates" / CrateName / Version / "readme")
+        .and(warp::get())
+        .and(with_pool(pool))
+        .and_then(handlers::readme)
+}
+
+/// The user of the current login session, if any.
+fn session(pool: DbConnPool) -> impl Filter<Extract = (Option<User>,), Error = Rejection> + Clone {
+    session_token()
+        .and(with_pool(pool))
+        .and_then(handlers::session)
+}
+
+/// The token of the current login session, if any.
+fn session_token() -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Clone {
+    warp::cookie::optional::<String>(handlers::SESSION_COOKIE)
+}
+
+/// The configured identity provider. Requests are rejected if there is none.
+fn require_provider(
+    provider: Option<Arc<dyn Provider>>,
+) -> impl Filter<Extract = (Arc<dyn Provider>,), Error = Rejection> + Clone {
+    warp::any().and_then(move || {
+        let provider = provider.clone();
+        async move { provider.ok_or_else(warp::reject::not_found) }
+    })
+}
+
+fn with_provider(
+    provider: Option<Arc<dyn Provider>>,
+) -> impl Filter<Extract = (Option<Arc<dyn Provider>>,), Error = Infallible> + Clone {
+    warp::any().map(move || provider.clone())
+}
+
+fn with_cookies(cookies: Cookies) -> impl Filter<Extract = (Cookies,), Error = Infallible> + Clone {
+    warp::any().map(move || cookies)
+}
+
+fn with_pool(pool: DbConnPool) -> impl Filter<Extract = (DbConnPool,), Error = Infallible> + Clone {
+    warp::any().map(move || pool.clone())
 }
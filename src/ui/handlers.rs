@@ -1,11 +1,557 @@
-use crate::templates;
+use std::sync::Arc;
+
+use ring::constant_time;
+use semver::Version;
+use serde::Deserialize;
+use tracing::warn;
+use warp::{
+    http::{header::SET_COOKIE, HeaderValue, StatusCode, Uri},
+    reply::Response,
+    Rejection, Reply,
+};
+
+use crate::{
+    api::error::ServerError,
+    crypto,
+    db::{self, users::User, DbConnPool},
+    markdown,
+    models::CrateName,
+    oidc::{LoginState, Provider},
+    templates,
+};
+
+/// Name of the cookie that holds the login session token.
+pub const SESSION_COOKIE: &str = "session";
+/// Name of the cookie that holds the [`LoginState`] while logging in with the identity provider.
+pub const OIDC_COOKIE: &str = "oidc_login";
+/// Time in seconds that users have to log in at the identity provider.
+const OIDC_LIFETIME: i64 = 600;
+
+/// Attributes of the cookies that the web UI sets.
+#[derive(Clone, Copy)]
+pub struct Cookies {
+    /// Only send the cookies over `https` connections. Disabled for registries that are served over
+    /// plain `http`, like during development, as browsers would drop the cookies otherwise.
+    pub secure: bool,
+}
+
+impl Cookies {
+    /// Create the value of a `Set-Cookie` header. A max age of 0 removes the cookie.
+    fn build(self, name: &str, value: &str, path: &str, max_age: i64) -> String {
+        // `SameSite=Lax` keeps other sites from sending forms with the cookie attached, in browsers
+        // that support it. Forms that change anything carry a CSRF token in addition.
+        let mut cookie =
+            format!("{name}={value}; Path={path}; Max-Age={max_age}; HttpOnly; SameSite=Lax");
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        cookie
+    }
+}
+
+/// Amount of crates shown on a single page of the crate list.
+const PER_PAGE: u32 = 20;
+
+#[derive(Debug, Deserialize)]
+pub struct CratesQuery {
+    #[serde(default)]
+    q: String,
+    #[serde(default = "default_page")]
+    page: u32,
+}
+
+const fn default_page() -> u32 {
+    1
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: String,
+}
+
+/// Parameters that the identity provider redirects back with.
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateTokenForm {
+    name: String,
+    csrf: String,
+}
+
+/// Form of actions that don't need any input besides the CSRF token.
+#[derive(Deserialize)]
+pub struct CsrfForm {
+    csrf: String,
+}
 
 #[tracing::instrument]
 pub fn index() -> templates::Index {
     templates::Index
 }
 
-#[tracing::instrument]
-pub fn me() -> templates::Me {
-    templates::Me
+/// Look up the user of the session cookie. Unknown or expired sessions count as logged out.
+pub async fn session(token: Option<String>, pool: DbConnPool) -> Result<Option<User>, Rejection> {
+    let Some(token) = token else {
+        return Ok(None);
+    };
+
+    pool.run(move |conn| db::sessions::authenticate(conn, &token))
+        .await
+        .map_err(|e| ServerError(e).into())
+}
+
+#[tracing::instrument(skip_all)]
+pub fn login_page(provider: Option<Arc<dyn Provider>>) -> templates::Login {
+    login_template(provider.as_deref(), None)
+}
+
+#[tracing::instrument(skip_all, fields(user = %form.username))]
+pub async fn login(
+    form: LoginForm,
+    provider: Option<Arc<dyn Provider>>,
+    cookies: Cookies,
+    pool: DbConnPool,
+) -> Result<Response, Rejection> {
+    let user = pool
+        .run(move |conn| db::users::verify_password(conn, &form.username, &form.password))
+        .await
+        .map_err(ServerError)?;
+
+    match user {
+        Some(user) => start_session(user, cookies, pool, None).await,
+        None => Ok(
+            login_template(provider.as_deref(), Some("Invalid username or password"))
+                .into_response(),
+        ),
+    }
+}
+
+/// Redirect to the identity provider's login page.
+#[tracing::instrument(skip_all)]
+pub fn oidc_login(provider: Arc<dyn Provider>, cookies: Cookies) -> Result<Response, Rejection> {
+    let login = LoginState::generate();
+    let location = provider
+        .authorize_url(&login)
+        .parse::<Uri>()
+        .map_err(ServerError::from)?;
+
+    // Only sent back to the callback, and `SameSite=Lax` still includes it with the redirect from
+    // the provider.
+    let cookie = cookies.build(OIDC_COOKIE, &login.encode(), "/login/oidc", OIDC_LIFETIME);
+
+    Ok(
+        warp::reply::with_header(warp::redirect::see_other(location), SET_COOKIE, cookie)
+            .into_response(),
+    )
+}
+
+/// Finish the login with the identity provider, creating a user for the account on first use.
+#[tracing::instrument(skip_all)]
+pub async fn oidc_callback(
+    query: CallbackQuery,
+    login: Option<String>,
+    provider: Arc<dyn Provider>,
+    cookies: Cookies,
+    pool: DbConnPool,
+) -> Result<Response, Rejection> {
+    let failed = || {
+        login_template(
+            Some(&*provider),
+            Some("Login with the identity provider failed"),
+        )
+        .into_response()
+    };
+
+    if let Some(error) = query.error {
+        warn!(%error, "identity provider denied the login");
+        return Ok(failed());
+    }
+
+    let login = login.as_deref().and_then(LoginState::decode);
+    let (Some(code), Some(login)) = (query.code, login) else {
+        return Ok(failed());
+    };
+    if query.state.as_ref() != Some(&login.state) {
+        warn!("login state doesn't match");
+        return Ok(failed());
+    }
+
+    let identity = match provider.authenticate(&code, &login).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            warn!(error = ?e, "failed authenticating with the identity provider");
+            return Ok(failed());
+        }
+    };
+
+    let user = pool
+        .run(move |conn| {
+            db::users::get_or_create_by_identity(
+                conn,
+                &identity.issuer,
+                &identity.subject,
+                &identity.name,
+            )
+        })
+        .await;
+
+    match user {
+        Ok(Some(user)) => {
+            let clear = cookies.build(OIDC_COOKIE, "", "/login/oidc", 0);
+            start_session(user, cookies, pool, Some(clear)).await
+        }
+        Ok(None) => {
+            warn!("user name of the identity is already taken");
+            Ok(warp::reply::with_status(
+                login_template(
+                    Some(&*provider),
+                    Some("The user name of your account is already taken by another user"),
+                ),
+                StatusCode::CONFLICT,
+            )
+            .into_response())
+        }
+        Err(e) => {
+            warn!(error = ?e, "failed linking the identity to a user");
+            Ok(failed())
+        }
+    }
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn logout(
+    token: Option<String>,
+    form: CsrfForm,
+    cookies: Cookies,
+    pool: DbConnPool,
+) -> Result<Response, Rejection> {
+    if !verify_csrf(token.as_deref(), &form.csrf) {
+        return Ok(invalid_form());
+    }
+
+    if let Some(token) = token {
+        pool.run(move |conn| db::sessions::delete(conn, &token))
+            .await
+            .map_err(ServerError)?;
+    }
+
+    let cookie = cookies.build(SESSION_COOKIE, "", "/", 0);
+
+    Ok(warp::reply::with_header(redirect("/"), SET_COOKIE, cookie).into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn me(
+    user: Option<User>,
+    token: Option<String>,
+    pool: DbConnPool,
+) -> Result<Response, Rejection> {
+    let (Some(user), Some(token)) = (user, token) else {
+        return Ok(redirect("/login").into_response());
+    };
+
+    load_me(user, &token, None, pool)
+        .await
+        .map(Reply::into_response)
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn create_token(
+    user: Option<User>,
+    token: Option<String>,
+    form: CreateTokenForm,
+    pool: DbConnPool,
+) -> Result<Response, Rejection> {
+    let (Some(user), Some(session)) = (user, token) else {
+        return Ok(redirect("/login").into_response());
+    };
+    if !verify_csrf(Some(&session), &form.csrf) {
+        return Ok(invalid_form());
+    }
+
+    let name = form.name.trim().to_owned();
+    let token = if name.is_empty() {
+        None
+    } else {
+        let user = user.clone();
+        let token = pool
+            .run(move |conn| db::tokens::create(conn, &user, &name))
+            .await
+            .map_err(ServerError)?;
+        Some(token)
+    };
+
+    load_me(user, &session, token, pool)
+        .await
+        .map(Reply::into_response)
+}
+
+#[tracing::instrument(skip(user, token, form, pool))]
+pub async fn revoke_token(
+    id: i64,
+    user: Option<User>,
+    token: Option<String>,
+    form: CsrfForm,
+    pool: DbConnPool,
+) -> Result<Response, Rejection> {
+    let Some(user) = user else {
+        return Ok(redirect("/login").into_response());
+    };
+    if !verify_csrf(token.as_deref(), &form.csrf) {
+        return Ok(invalid_form());
+    }
+
+    pool.run(move |conn| db::tokens::revoke(conn, &user, id))
+        .await
+        .map_err(ServerError)?;
+
+    Ok(redirect("/me").into_response())
+}
+
+fn login_template(
+    provider: Option<&dyn Provider>,
+    error: Option<&'static str>,
+) -> templates::Login {
+    templates::Login {
+        error,
+        provider: provider.map(|p| p.name().to_owned()),
+    }
+}
+
+/// Start a new login session for the user and redirect to the user's page. Any additional cookie
+/// is set along with the session cookie.
+async fn start_session(
+    user: User,
+    cookies: Cookies,
+    pool: DbConnPool,
+    cookie: Option<String>,
+) -> Result<Response, Rejection> {
+    let token = pool
+        .run(move |conn| db::sessions::create(conn, &user))
+        .await
+        .map_err(ServerError)?;
+
+    let session = cookies.build(SESSION_COOKIE, &token, "/", db::sessions::LIFETIME);
+
+    let mut resp = redirect("/me").into_response();
+    for cookie in [Some(session), cookie].into_iter().flatten() {
+        resp.headers_mut().append(
+            SET_COOKIE,
+            HeaderValue::from_str(&cookie).map_err(ServerError::from)?,
+        );
+    }
+
+    Ok(resp)
+}
+
+/// Load everything shown on the user's own page. A freshly created API token is shown once, as
+/// only its hash is stored.
+async fn load_me(
+    user: User,
+    session: &str,
+    new_token: Option<String>,
+    pool: DbConnPool,
+) -> Result<templates::Me, Rejection> {
+    let (published, owned, tokens) = {
+        let user = user.clone();
+        pool.run(move |conn| {
+            anyhow::Ok((
+                db::crates::published_by(conn, &user)?,
+                db::crates::owned_by(conn, &user)?,
+                db::tokens::list(conn, &user)?,
+            ))
+        })
+        .await
+        .map_err(ServerError)?
+    };
+
+    Ok(templates::Me {
+        user,
+        published,
+        owned,
+        tokens,
+        new_token,
+        csrf: csrf_token(session),
+    })
+}
+
+/// Token that forms changing anything must include, proving that they were sent from the
+/// registry's own pages. It is derived from the session token, which other sites can't read.
+fn csrf_token(session: &str) -> String {
+    hex::encode(crypto::hmac_sha256(session.as_bytes(), b"csrf"))
+}
+
+/// Check the CSRF token of a form against the one of the current session.
+fn verify_csrf(session: Option<&str>, token: &str) -> bool {
+    session.is_some_and(|session| {
+        constant_time::verify_slices_are_equal(csrf_token(session).as_bytes(), token.as_bytes())
+            .is_ok()
+    })
+}
+
+/// Response for forms that failed the CSRF check.
+fn invalid_form() -> Response {
+    warp::reply::with_status(
+        "Invalid form submission, please reload the page and try again",
+        StatusCode::FORBIDDEN,
+    )
+    .into_response()
+}
+
+/// Redirect to another page after a form submission, so reloading doesn't submit it again.
+fn redirect(location: &'static str) -> impl Reply {
+    warp::redirect::see_other(Uri::from_static(location))
+}
+
+/// List all crates, or only the ones matching the search query.
+#[tracing::instrument(skip(pool))]
+pub async fn crates(query: CratesQuery, pool: DbConnPool) -> Result<templates::Crates, Rejection> {
+    let page = query.page.max(1);
+    let (crates, total) = {
+        let query = query.q.clone();
+        let offset = (page - 1).saturating_mul(PER_PAGE);
+        pool.run(move |conn| db::crates::search(conn, &query, offset, PER_PAGE))
+            .await
+            .map_err(ServerError)?
+    };
+
+    Ok(templates::Crates {
+        query: query.q,
+        crates,
+        total,
+        page,
+        pages: u32::try_from(total.div_ceil(PER_PAGE.into()))
+            .unwrap_or(u32::MAX)
+            .max(1),
+    })
+}
+
+/// Show the details of a crate in its latest version.
+#[tracing::instrument(skip(pool))]
+pub async fn crate_info(name: CrateName, pool: DbConnPool) -> Result<templates::Crate, Rejection> {
+    load_crate(name, None, pool).await
+}
+
+/// Show the details of a crate in a specific version.
+#[tracing::instrument(skip(pool))]
+pub async fn version_info(
+    name: CrateName,
+    version: Version,
+    pool: DbConnPool,
+) -> Result<templates::Crate, Rejection> {
+    load_crate(name, Some(version), pool).await
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn readme(
+    name: CrateName,
+    version: Version,
+    pool: DbConnPool,
+) -> Result<templates::Readme, Rejection> {
+    // Rendering happens on the blocking thread pool as well, as it takes a while for big READMEs.
+    let html = {
+        let (name, version) = (name.clone(), version.clone());
+        pool.run(move |conn| {
+            let Some(info) = db::versions::find(conn, &name, &version)? else {
+                return Ok(None);
+            };
+            let readme = db::versions::readme(conn, info.id)?;
+            anyhow::Ok(readme.as_deref().map(markdown::render))
+        })
+        .await
+        .map_err(ServerError)?
+        .ok_or_else(warp::reject::not_found)?
+    };
+
+    Ok(templates::Readme {
+        name,
+        version,
+        html,
+    })
+}
+
+/// Load everything shown on the crate page. Without an explicit version, the highest version
+/// that isn't yanked is shown, or the highest version if all of them are yanked. Crates published
+/// before versions were recorded in the database are shown without any version details.
+async fn load_crate(
+    name: CrateName,
+    version: Option<Version>,
+    pool: DbConnPool,
+) -> Result<templates::Crate, Rejection> {
+    let loaded = pool
+        .run(move |conn| {
+            let Some(details) = db::crates::find(conn, &name)? else {
+                return Ok(None);
+            };
+            let versions = db::versions::list(conn, &name)?;
+
+            let current = match &version {
+                Some(version) => match versions.iter().position(|v| &v.num == version) {
+                    Some(pos) => Some(pos),
+                    None => return Ok(None),
+                },
+                None => versions
+                    .iter()
+                    .position(|v| !v.yanked)
+                    .or_else(|| (!versions.is_empty()).then_some(0)),
+            };
+
+            let (dependencies, readme) = match current {
+                Some(current) => (
+                    db::versions::dependencies(conn, versions[current].id)?,
+                    db::versions::readme(conn, versions[current].id)?
+                        .as_deref()
+                        .map(markdown::render),
+                ),
+                None => (Vec::new(), None),
+            };
+
+            anyhow::Ok(Some((details, versions, current, dependencies, readme)))
+        })
+        .await
+        .map_err(ServerError)?;
+
+    let Some((details, versions, current, dependencies, readme)) = loaded else {
+        return Err(warp::reject::not_found());
+    };
+
+    Ok(templates::Crate {
+        details,
+        versions,
+        current,
+        dependencies,
+        readme,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookie_attributes() {
+        assert_eq!(
+            "session=abc; Path=/; Max-Age=60; HttpOnly; SameSite=Lax",
+            Cookies { secure: false }.build(SESSION_COOKIE, "abc", "/", 60)
+        );
+        assert_eq!(
+            "session=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax; Secure",
+            Cookies { secure: true }.build(SESSION_COOKIE, "", "/", 0)
+        );
+    }
+
+    #[test]
+    fn csrf() {
+        let token = csrf_token("session");
+        assert!(verify_csrf(Some("session"), &token));
+        assert!(!verify_csrf(Some("other"), &token));
+        assert!(!verify_csrf(None, &token));
+        assert!(!verify_csrf(Some("session"), ""));
+    }
 }
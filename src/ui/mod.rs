@@ -1,2 +1,4 @@
 pub mod filters;
 mod handlers;
+
+pub use self::handlers::Cookies;
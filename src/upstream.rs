@@ -0,0 +1,566 @@
+//! Pull-through cache for an upstream registry like crates.io. Crates that don't exist in the local
+//! index are looked up in the upstream's sparse index, and their `.crate` files are downloaded into
+//! the storage on first use. Index files are cached in the database, so they are still served while
+//! the upstream is unreachable.
+//!
+//! Every crate that was looked up successfully is recorded as mirrored, so it can't be claimed by
+//! publishing a local crate of the same name.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use hyper::{
+    header::{CONTENT_LENGTH, ETAG, IF_NONE_MATCH, LOCATION},
+    Body, Request, Response, StatusCode,
+};
+use semver::Version;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::{Mutex, OnceCell};
+use tokio_util::io::StreamReader;
+use tracing::{instrument, warn};
+use url::Url;
+
+use crate::{
+    db::{self, upstream::CachedCrate, DbConnPool},
+    http::{self, HttpClient},
+    index::{self, dl::Template, IndexFile},
+    models::{AlreadyExists, CrateName},
+    settings, storage,
+};
+
+/// How long to wait for the upstream registry before giving up.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum amount of redirects to follow for a single request.
+const MAX_REDIRECTS: usize = 5;
+
+/// The upstream service that mirrors crates from another registry.
+#[async_trait]
+pub trait Service: Send + Sync + 'static {
+    /// Read the index file of a crate from the upstream registry, if it exists there. The cached
+    /// copy is returned if the upstream can't be reached.
+    async fn read_crate(&self, name: &CrateName) -> Result<Option<IndexFile>>;
+    /// Make sure the `.crate` file of a release is in the storage, downloading it from the upstream
+    /// registry if needed. Returns `false` if the upstream doesn't have the release, or the
+    /// release has a different checksum than expected.
+    async fn fetch_crate(
+        &self,
+        name: &CrateName,
+        version: &Version,
+        checksum: Option<&str>,
+    ) -> Result<bool>;
+}
+
+/// The parts of the upstream's `config.json` that are needed for downloads.
+#[derive(Deserialize)]
+struct Config {
+    dl: String,
+}
+
+/// The parts of an index entry that are needed for downloads. Upstream entries are passed on as
+/// they are, so unknown fields don't matter.
+#[derive(Deserialize)]
+struct Entry {
+    vers: Version,
+    cksum: String,
+}
+
+/// Locks of the releases that are currently being downloaded.
+type Downloads = HashMap<(CrateName, Version), Arc<Mutex<()>>>;
+
+/// Response of the upstream for an index file.
+enum Fetched {
+    Found {
+        content: Vec<u8>,
+        etag: Option<String>,
+    },
+    NotModified,
+    NotFound,
+}
+
+/// Main implementation of the upstream [`Service`].
+pub struct ServiceImpl<S> {
+    client: HttpClient,
+    /// Base URL of the sparse index, with trailing slash.
+    index: Url,
+    /// Download template, loaded from the upstream's `config.json` on first use if not set.
+    dl: OnceCell<(Url, Template)>,
+    storage: Arc<S>,
+    pool: DbConnPool,
+    /// Downloads that are currently in progress, so each file is only fetched once at a time.
+    fetching: parking_lot::Mutex<Downloads>,
+}
+
+#[async_trait]
+impl<S: storage::Service> Service for ServiceImpl<S> {
+    #[instrument(skip(self))]
+    async fn read_crate(&self, name: &CrateName) -> Result<Option<IndexFile>> {
+        let cached = self.cached(name).await?;
+        let etag = cached.as_ref().and_then(|c| c.etag.as_deref());
+
+        match self.fetch_index(name, etag).await {
+            Ok(Fetched::Found { content, etag }) => {
+                let name = name.to_string();
+                self.pool
+                    .run(move |conn| {
+                        db::upstream::put(conn, &name, &content, etag.as_deref())?;
+                        db::upstream::get(conn, &name)
+                    })
+                    .await
+                    .map(|cached| cached.map(index_file))
+            }
+            Ok(Fetched::NotModified) => Ok(cached.map(index_file)),
+            Ok(Fetched::NotFound) => {
+                let name = name.to_string();
+                self.pool
+                    .run(move |conn| db::upstream::delete(conn, &name))
+                    .await?;
+                Ok(None)
+            }
+            Err(e) => match cached {
+                Some(cached) => {
+                    warn!(error = ?e, "upstream unreachable, serving cached index file");
+                    Ok(Some(index_file(cached)))
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn fetch_crate(
+        &self,
+        name: &CrateName,
+        version: &Version,
+        checksum: Option<&str>,
+    ) -> Result<bool> {
+        let Some(expected) = self.checksum(name, version).await? else {
+            return Ok(false);
+        };
+        if checksum.is_some_and(|c| c != expected) {
+            return Ok(false);
+        }
+
+        let lock = self.lock(name, version);
+        let _guard = lock.lock().await;
+
+        if self.storage.get(name, version).await?.is_some() {
+            return Ok(true);
+        }
+
+        let (url, template) = self.dl().await?;
+        let mut url = url.clone();
+        url.set_path(&template.render(name, version, &expected));
+
+        let resp = self.get(url.clone(), None).await?;
+        ensure!(
+            resp.status().is_success(),
+            "upstream responded with {} for `{url}`",
+            resp.status()
+        );
+
+        let len = resp
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse().ok());
+        let (len, body) = match len {
+            Some(len) => (len, resp.into_body()),
+            // Without a known length, the file has to be loaded as a whole before storing it.
+            None => {
+                let body = hyper::body::to_bytes(resp.into_body()).await?;
+                (body.len() as u64, Body::from(body))
+            }
+        };
+
+        let hasher = Arc::new(parking_lot::Mutex::new(Sha256::new()));
+        let body = {
+            let hasher = Arc::clone(&hasher);
+            body.inspect_ok(move |chunk| hasher.lock().update(chunk))
+                .map_err(io::Error::other)
+        };
+
+        match self
+            .storage
+            .store(name, version, len, Box::pin(StreamReader::new(body)))
+            .await
+        {
+            Ok(()) => {}
+            Err(e) if e.is::<AlreadyExists>() => return Ok(true),
+            Err(e) => return Err(e),
+        }
+
+        let actual = hex::encode(hasher.lock().clone().finalize());
+        if actual != expected {
+            self.storage.delete(name, version).await?;
+            bail!("checksum of the downloaded crate `{name}@{version}` doesn't match the index");
+        }
+
+        Ok(true)
+    }
+}
+
+impl<S: storage::Service> ServiceImpl<S> {
+    /// Load the cached index file of a crate.
+    async fn cached(&self, name: &CrateName) -> Result<Option<CachedCrate>> {
+        let name = name.to_string();
+        self.pool
+            .run(move |conn| db::upstream::get(conn, &name))
+            .await
+    }
+
+    /// Find the checksum of a release, preferring the cached index file and only asking the
+    /// upstream if the release isn't known yet.
+    async fn checksum(&self, name: &CrateName, version: &Version) -> Result<Option<String>> {
+        if let Some(cksum) = self
+            .cached(name)
+            .await?
+            .and_then(|cached| find_checksum(&cached.content, version))
+        {
+            return Ok(Some(cksum));
+        }
+
+        Ok(self
+            .read_crate(name)
+            .await?
+            .and_then(|file| find_checksum(&file.content, version)))
+    }
+
+    /// Get the lock for downloading a single release, cleaning up locks that aren't used anymore.
+    fn lock(&self, name: &CrateName, version: &Version) -> Arc<Mutex<()>> {
+        let mut fetching = self.fetching.lock();
+        fetching.retain(|_, lock| Arc::strong_count(lock) > 1);
+
+        Arc::clone(fetching.entry((name.clone(), version.clone())).or_default())
+    }
+
+    /// Get the download template, loading it from the upstream's `config.json` if needed.
+    async fn dl(&self) -> Result<&(Url, Template)> {
+        self.dl
+            .get_or_try_init(|| async {
+                let resp = self.get(self.index.join("config.json")?, None).await?;
+                ensure!(
+                    resp.status().is_success(),
+                    "upstream responded with {} for the index config",
+                    resp.status()
+                );
+
+                let body = hyper::body::to_bytes(resp.into_body()).await?;
+                let config = serde_json::from_slice::<Config>(&body)
+                    .context("invalid upstream index config")?;
+
+                parse_dl(&config.dl)
+            })
+            .await
+    }
+
+    /// Request the index file of a crate, only transferring it if it changed since the cached
+    /// copy with the given entity tag.
+    async fn fetch_index(&self, name: &CrateName, etag: Option<&str>) -> Result<Fetched> {
+        let url = self
+            .index
+            .join(&index::crate_path(name).to_string_lossy())?;
+        let resp = self.get(url, etag).await?;
+
+        match resp.status() {
+            StatusCode::OK => {
+                let etag = resp
+                    .headers()
+                    .get(ETAG)
+                    .and_then(|etag| etag.to_str().ok())
+                    .map(ToOwned::to_owned);
+                let content = hyper::body::to_bytes(resp.into_body()).await?.to_vec();
+
+                Ok(Fetched::Found { content, etag })
+            }
+            StatusCode::NOT_MODIFIED if etag.is_some() => Ok(Fetched::NotModified),
+            // crates.io answers with `403 Forbidden` for some unknown crates.
+            StatusCode::NOT_FOUND | StatusCode::GONE | StatusCode::FORBIDDEN => {
+                Ok(Fetched::NotFound)
+            }
+            status => bail!("upstream responded with {status} for the index file of `{name}`"),
+        }
+    }
+
+    /// Send a `GET` request to the upstream, following redirects.
+    async fn get(&self, mut url: Url, etag: Option<&str>) -> Result<Response<Body>> {
+        let secure = url.scheme() == "https";
+
+        for _ in 0..=MAX_REDIRECTS {
+            ensure!(
+                matches!(url.scheme(), "http" | "https"),
+                "only `http` and `https` URLs are supported, got `{url}`"
+            );
+            ensure!(
+                !secure || url.scheme() == "https",
+                "refusing redirect from `https` to `{url}`"
+            );
+
+            let mut req = Request::get(url.as_str());
+            if let Some(etag) = etag {
+                req = req.header(IF_NONE_MATCH, etag);
+            }
+
+            let resp = tokio::time::timeout(TIMEOUT, self.client.request(req.body(Body::empty())?))
+                .await
+                .context("upstream didn't respond in time")?
+                .with_context(|| format!("failed requesting `{url}`"))?;
+
+            if !resp.status().is_redirection() || resp.status() == StatusCode::NOT_MODIFIED {
+                return Ok(resp);
+            }
+
+            let location = resp
+                .headers()
+                .get(LOCATION)
+                .context("redirect is missing the location")?
+                .to_str()?;
+            url = url.join(location)?;
+        }
+
+        bail!("too many redirects from upstream")
+    }
+}
+
+/// Turn a cached index file into the form that is served by the sparse index.
+fn index_file(cached: CachedCrate) -> IndexFile {
+    IndexFile {
+        content: cached.content,
+        modified: SystemTime::UNIX_EPOCH + Duration::from_secs(cached.modified_at as u64),
+    }
+}
+
+/// Find the checksum of a release in an index file.
+fn find_checksum(content: &[u8], version: &Version) -> Option<String> {
+    content
+        .split(|&b| b == b'\n')
+        .filter_map(|line| serde_json::from_slice::<Entry>(line).ok())
+        .find(|entry| &entry.vers == version)
+        .map(|entry| entry.cksum)
+}
+
+/// Parse the download template into the base URL and the template for its path.
+fn parse_dl(dl: &str) -> Result<(Url, Template)> {
+    Ok((dl.parse()?, Template::parse(dl)?))
+}
+
+/// Create the upstream service from the settings.
+pub fn new<S: storage::Service>(
+    settings: &settings::Upstream,
+    storage: Arc<S>,
+    pool: DbConnPool,
+) -> Result<ServiceImpl<S>> {
+    ensure!(
+        matches!(settings.index.scheme(), "http" | "https"),
+        "only `http` and `https` URLs are supported for the upstream index"
+    );
+
+    let mut index = settings.index.clone();
+    if !index.path().ends_with('/') {
+        index.set_path(&format!("{}/", index.path()));
+    }
+
+    Ok(ServiceImpl {
+        client: http::client(),
+        index,
+        dl: OnceCell::new_with(settings.dl.as_deref().map(parse_dl).transpose()?),
+        storage,
+        pool,
+        fetching: parking_lot::Mutex::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use hyper::body::Bytes;
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+    use warp::{http::Response, Filter};
+
+    use super::*;
+
+    const CRATE: &[u8] = b"crate content";
+
+    /// Minimal stand-in for a sparse registry with the crate `foo` and the crate `bad`, whose
+    /// checksum doesn't match its content. Returns the URL and the count of `304` responses.
+    fn start_fake_upstream() -> (Url, Arc<AtomicUsize>) {
+        let not_modified = Arc::new(AtomicUsize::new(0));
+        let cksum = hex::encode(Sha256::digest(CRATE));
+
+        let config = warp::path!("index" / "config.json")
+            .and(warp::header::<String>("host"))
+            .map(|host: String| {
+                warp::reply::json(&serde_json::json!({ "dl": format!("http://{host}/dl") }))
+            });
+
+        let counter = Arc::clone(&not_modified);
+        let index = warp::path!("index" / String / String / String)
+            .and(warp::header::optional::<String>("if-none-match"))
+            .map(
+                move |_: String, _: String, name: String, etag: Option<String>| {
+                    let cksum = match name.as_str() {
+                        "foo" => cksum.as_str(),
+                        "bad" => "0000",
+                        _ => return Response::builder().status(404).body(String::new()),
+                    };
+                    if etag.as_deref() == Some("\"v1\"") {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        return Response::builder().status(304).body(String::new());
+                    }
+
+                    let entry = serde_json::json!({
+                        "name": name,
+                        "vers": "1.0.0",
+                        "deps": [],
+                        "cksum": cksum,
+                        "features": {},
+                        "yanked": false,
+                    });
+
+                    Response::builder()
+                        .header("etag", "\"v1\"")
+                        .body(format!("{entry}\n"))
+                },
+            );
+
+        let dl = warp::path!("dl" / String / String / "download").map(
+            |name: String, version: String| {
+                Response::builder()
+                    .status(302)
+                    .header("location", format!("/static/{name}-{version}.crate"))
+                    .body(Bytes::new())
+            },
+        );
+        let file = warp::path!("static" / String).map(|_| Response::new(Bytes::from_static(CRATE)));
+
+        let (addr, server) =
+            warp::serve(config.or(index).or(dl).or(file)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        (
+            format!("http://{addr}/index").parse().unwrap(),
+            not_modified,
+        )
+    }
+
+    #[test]
+    fn upstream_url() {
+        let upstream = |index: &str| settings::Upstream {
+            index: index.parse().unwrap(),
+            dl: None,
+        };
+        let storage = Arc::new(
+            storage::new(&settings::Storage::Filesystem {
+                location: std::env::temp_dir(),
+            })
+            .unwrap(),
+        );
+
+        let service = new(
+            &upstream("https://index.crates.io"),
+            Arc::clone(&storage),
+            db::memory_pool(),
+        )
+        .unwrap();
+        assert_eq!("https://index.crates.io/", service.index.as_str());
+
+        assert!(new(
+            &upstream("ftp://index.crates.io/"),
+            storage,
+            db::memory_pool()
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn pull_through() {
+        let (index, not_modified) = start_fake_upstream();
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(
+            storage::new(&settings::Storage::Filesystem {
+                location: dir.path().to_owned(),
+            })
+            .unwrap(),
+        );
+        let pool = db::memory_pool();
+
+        let service = new(
+            &settings::Upstream { index, dl: None },
+            Arc::clone(&storage),
+            pool.clone(),
+        )
+        .unwrap();
+
+        let foo = "foo".parse().unwrap();
+        let bad = "bad".parse().unwrap();
+        let version = "1.0.0".parse().unwrap();
+
+        let file = service.read_crate(&foo).await.unwrap().unwrap();
+        assert_eq!(
+            Some(hex::encode(Sha256::digest(CRATE))),
+            find_checksum(&file.content, &version)
+        );
+
+        let again = service.read_crate(&foo).await.unwrap().unwrap();
+        assert_eq!(file.content, again.content);
+        assert_eq!(1, not_modified.load(Ordering::SeqCst));
+
+        assert!(service
+            .read_crate(&"missing".parse().unwrap())
+            .await
+            .unwrap()
+            .is_none());
+
+        assert!(service.fetch_crate(&foo, &version, None).await.unwrap());
+        let mut content = Vec::new();
+        storage
+            .get(&foo, &version)
+            .await
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut content)
+            .await
+            .unwrap();
+        assert_eq!(CRATE, content);
+
+        assert!(!service
+            .fetch_crate(&foo, &"2.0.0".parse().unwrap(), None)
+            .await
+            .unwrap());
+        assert!(!service
+            .fetch_crate(&foo, &version, Some("wrong"))
+            .await
+            .unwrap());
+
+        assert!(service.fetch_crate(&bad, &version, None).await.is_err());
+        assert!(storage.get(&bad, &version).await.unwrap().is_none());
+
+        // Cached crates are still served while the upstream is unreachable.
+        let closed = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let offline = new(
+            &settings::Upstream {
+                index: format!("http://{}/index/", closed.local_addr().unwrap())
+                    .parse()
+                    .unwrap(),
+                dl: None,
+            },
+            storage,
+            pool,
+        )
+        .unwrap();
+        drop(closed);
+
+        let cached = offline.read_crate(&foo).await.unwrap().unwrap();
+        assert_eq!(file.content, cached.content);
+        assert!(offline.fetch_crate(&foo, &version, None).await.unwrap());
+        assert!(offline.read_crate(&bad).await.is_ok());
+        assert!(offline.read_crate(&"other".parse().unwrap()).await.is_err());
+    }
+}